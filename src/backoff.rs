@@ -0,0 +1,58 @@
+use std::time::Duration;
+
+/// Exponential backoff with full jitter.
+///
+/// Reference:
+/// aws.amazon.com/blogs/architecture/exponential-backoff-and-jitter/
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    pub base: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    pub const fn new(base: Duration, max: Duration) -> Backoff {
+        Backoff { base, max }
+    }
+
+    /// The upper bound of the delay before the given retry, where the
+    /// first retry is attempt 0.
+    pub fn ceiling(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt);
+        self.base.saturating_mul(factor).min(self.max)
+    }
+
+    /// A random delay between zero and the ceiling for the given retry.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.ceiling(attempt).mul_f64(fastrand::f64())
+    }
+
+    pub async fn sleep(&self, attempt: u32) {
+        tokio::time::sleep(self.delay(attempt)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ceiling_doubles_up_to_max() {
+        let backoff =
+            Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+        assert_eq!(backoff.ceiling(0), Duration::from_millis(100));
+        assert_eq!(backoff.ceiling(1), Duration::from_millis(200));
+        assert_eq!(backoff.ceiling(3), Duration::from_millis(800));
+        assert_eq!(backoff.ceiling(4), Duration::from_secs(1));
+        assert_eq!(backoff.ceiling(100), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_delay_is_within_ceiling() {
+        let backoff =
+            Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+        for attempt in 0..10 {
+            assert!(backoff.delay(attempt) <= backoff.ceiling(attempt));
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::time::{Duration, Instant};
+
+/// How often the queue full warning is printed at most.
+const WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Counts how often the journal reader had to wait for space in the event
+/// queue, which means the uploader isn't keeping up.
+#[derive(Debug, Default)]
+pub struct Backpressure {
+    waits: u64,
+    last_warning: Option<Instant>,
+}
+
+impl Backpressure {
+    pub fn new() -> Backpressure {
+        Backpressure {
+            waits: 0,
+            last_warning: None,
+        }
+    }
+
+    pub fn waits(&self) -> u64 {
+        self.waits
+    }
+
+    /// Record that the queue was full, returning true if a warning should
+    /// be printed.
+    pub fn record_wait(&mut self, now: Instant) -> bool {
+        self.waits += 1;
+        let warn = match self.last_warning {
+            Some(last_warning) => now - last_warning >= WARNING_INTERVAL,
+            None => true,
+        };
+        if warn {
+            self.last_warning = Some(now);
+        }
+        warn
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warnings_are_rate_limited() {
+        let mut backpressure = Backpressure::new();
+        let start = Instant::now();
+        assert!(backpressure.record_wait(start));
+        assert!(!backpressure.record_wait(start + Duration::from_secs(1)));
+        assert!(!backpressure.record_wait(start + Duration::from_secs(59)));
+        assert!(backpressure.record_wait(start + Duration::from_secs(60)));
+        assert!(!backpressure.record_wait(start + Duration::from_secs(61)));
+        assert_eq!(backpressure.waits(), 5);
+    }
+}
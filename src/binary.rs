@@ -0,0 +1,49 @@
+use systemd::journal::{Journal, JournalRecord};
+use tracing::warn;
+
+/// The prefix of messages that were forwarded base64 encoded because they
+/// weren't valid UTF-8.
+pub const BASE64_PREFIX: &str = "base64:";
+
+/// Decode a field value, base64 encoding it with `BASE64_PREFIX` if it
+/// isn't valid UTF-8 so that no bytes are lost.
+pub fn decode_value(value: &[u8]) -> String {
+    match std::str::from_utf8(value) {
+        Ok(value) => value.to_string(),
+        Err(_) => format!("{}{}", BASE64_PREFIX, base64::encode(value)),
+    }
+}
+
+/// The journal decodes fields lossily, replacing invalid UTF-8 with
+/// U+FFFD. If that happened to the message of the current entry, such as
+/// a binary blob from systemd-coredump, read it again as raw bytes.
+pub fn restore_binary_message(
+    journal: &mut Journal,
+    record: &mut JournalRecord,
+) {
+    match record.get("MESSAGE") {
+        Some(message) if message.contains(char::REPLACEMENT_CHARACTER) => {}
+        _ => return,
+    }
+    match journal.get_data("MESSAGE") {
+        Ok(Some(field)) => {
+            if let Some(value) = field.value() {
+                record.insert("MESSAGE".to_string(), decode_value(value));
+            }
+        }
+        Ok(None) => {}
+        Err(err) => warn!("failed to read the raw message: {}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_value() {
+        assert_eq!(decode_value(b"disk full"), "disk full");
+        assert_eq!(decode_value("caf\u{e9}".as_bytes()), "caf\u{e9}");
+        assert_eq!(decode_value(b"\x7fELF\xff\xfe"), "base64:f0VMRv/+");
+    }
+}
@@ -1,117 +1,593 @@
-use crate::configuration::Configuration;
+use crate::backoff::Backoff;
+use crate::configuration::{Backend, Configuration, ConfigurationError};
+use crate::cursor::{CursorStore, JournalPosition};
+use crate::failover::{self, Failover};
+use crate::file::FileUploader;
+use crate::kinesis::KinesisUploader;
+use crate::metrics::{DropReason, Metrics};
+use crate::spool::{SpoolStore, SpooledBatch};
+use crate::stdout::StdoutUploader;
 use async_trait::async_trait;
-use aws_config::meta::region::RegionProviderChain;
+use aws_sdk_cloudwatchlogs::error::{
+    DescribeLogStreamsError, PutLogEventsError, PutLogEventsErrorKind,
+};
 use aws_sdk_cloudwatchlogs::model::{InputLogEvent, LogStream};
-use aws_sdk_cloudwatchlogs::{Client, Region};
+use aws_sdk_cloudwatchlogs::output::DescribeLogStreamsOutput;
+use aws_sdk_cloudwatchlogs::types::SdkError;
+use aws_sdk_cloudwatchlogs::Client;
+use aws_smithy_types::retry::ProvideErrorKind;
 use chrono::Utc;
-use std::time::Duration;
+use futures::stream::{self, StreamExt};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error, info, warn};
 
 #[async_trait]
-trait Uploader {
+pub trait Uploader: Send {
     fn group_events(
         &self,
         events: Vec<InputLogEvent>,
     ) -> Vec<Vec<InputLogEvent>>;
-    async fn upload(&mut self, events: Vec<InputLogEvent>);
+    /// Upload the events. If any group of events isn't accepted, the
+    /// events that weren't delivered are returned with the error.
+    async fn upload(
+        &mut self,
+        events: Vec<InputLogEvent>,
+    ) -> Result<(), Undelivered>;
+    /// Upload the events to the named log stream instead of the default
+    /// one. Uploaders without log streams upload them like any others.
+    async fn upload_to_stream(
+        &mut self,
+        _stream: &str,
+        events: Vec<InputLogEvent>,
+    ) -> Result<(), Undelivered> {
+        self.upload(events).await
+    }
+    /// Upload several batches, each to its own log stream or, for `None`,
+    /// the default one, returning the result of each batch in order.
+    /// Uploaders that can upload to several streams at once override this;
+    /// by default the batches are uploaded one after the other.
+    async fn upload_batches(
+        &mut self,
+        batches: Vec<(Option<String>, Vec<InputLogEvent>)>,
+    ) -> Vec<Result<(), Undelivered>> {
+        let mut results = Vec::new();
+        for (stream, events) in batches {
+            let result = match stream {
+                Some(stream) => self.upload_to_stream(&stream, events).await,
+                None => self.upload(events).await,
+            };
+            results.push(result);
+        }
+        results
+    }
+}
+
+/// Why an upload failed, so the retries, the metrics and the tests can
+/// tell failures apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UploadError {
+    /// The request was throttled.
+    Throttled,
+    /// The sequence token was rejected.
+    InvalidToken,
+    /// The log group, log stream or Kinesis stream doesn't exist.
+    ResourceNotFound,
+    /// The request timed out, couldn't be sent or failed on the server.
+    Network,
+    /// Anything else, such as missing permissions or invalid events.
+    Other,
+}
+
+impl UploadError {
+    pub const ALL: [UploadError; 5] = [
+        UploadError::Throttled,
+        UploadError::InvalidToken,
+        UploadError::ResourceNotFound,
+        UploadError::Network,
+        UploadError::Other,
+    ];
+
+    /// Classify a failed request to any of the AWS services.
+    pub fn from_sdk_error<E: ProvideErrorKind>(
+        err: &SdkError<E>,
+    ) -> UploadError {
+        match err {
+            SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) => {
+                UploadError::Network
+            }
+            SdkError::ResponseError { raw, .. }
+                if raw.http().status().is_server_error() =>
+            {
+                UploadError::Network
+            }
+            SdkError::ServiceError { err, raw } => {
+                let status = raw.http().status();
+                match err.code().map(UploadError::from_code) {
+                    Some(error) if error != UploadError::Other => error,
+                    _ if status.as_u16() == 429 => UploadError::Throttled,
+                    _ if status.is_server_error() => UploadError::Network,
+                    _ => UploadError::Other,
+                }
+            }
+            SdkError::ResponseError { .. }
+            | SdkError::ConstructionFailure(_) => UploadError::Other,
+        }
+    }
+
+    /// Classify an AWS error code, such as the one of a Kinesis record
+    /// that wasn't accepted.
+    pub fn from_code(code: &str) -> UploadError {
+        match code {
+            _ if THROTTLING_ERROR_CODES.contains(&code) => {
+                UploadError::Throttled
+            }
+            "InvalidSequenceTokenException" => UploadError::InvalidToken,
+            "ResourceNotFoundException" => UploadError::ResourceNotFound,
+            "InternalFailure" | "InternalFailureException" => {
+                UploadError::Network
+            }
+            _ => UploadError::Other,
+        }
+    }
+
+    /// Whether trying again later may succeed.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, UploadError::Throttled | UploadError::Network)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            UploadError::Throttled => "throttled",
+            UploadError::InvalidToken => "invalid_token",
+            UploadError::ResourceNotFound => "resource_not_found",
+            UploadError::Network => "network",
+            UploadError::Other => "other",
+        }
+    }
+}
+
+/// The events of an upload that weren't delivered, and the error the last
+/// of them failed with.
+#[derive(Debug)]
+pub struct Undelivered {
+    pub error: UploadError,
+    pub events: Vec<InputLogEvent>,
+}
+
+impl Undelivered {
+    pub fn new(error: UploadError, events: Vec<InputLogEvent>) -> Undelivered {
+        Undelivered { error, events }
+    }
+}
+
+/// Error codes that mean the request was throttled and can be retried.
+const THROTTLING_ERROR_CODES: &[&str] = &[
+    "Throttling",
+    "ThrottlingException",
+    "ThrottledException",
+    "RequestLimitExceeded",
+    "TooManyRequestsException",
+    "ServiceUnavailableException",
+    "ProvisionedThroughputExceededException",
+    "KMSThrottlingException",
+];
+
+/// Check whether a failed request is worth retrying: throttling, server
+/// errors, timeouts and network failures are; anything else is not.
+pub fn is_retryable<E: ProvideErrorKind>(err: &SdkError<E>) -> bool {
+    UploadError::from_sdk_error(err).is_retryable()
+}
+
+fn is_data_already_accepted(err: &SdkError<PutLogEventsError>) -> bool {
+    match err {
+        SdkError::ServiceError { err, .. } => {
+            err.is_data_already_accepted_exception()
+        }
+        _ => false,
+    }
+}
+
+/// Find the sequence token CloudWatch expected in an
+/// `InvalidSequenceTokenException` or `DataAlreadyAcceptedException`, so
+/// the next put can be sent without describing the log stream. The outer
+/// `None` means no token could be found; `Some(None)` means the stream
+/// expects no token at all.
+fn get_expected_sequence_token(
+    err: &SdkError<PutLogEventsError>,
+) -> Option<Option<String>> {
+    let err = match err {
+        SdkError::ServiceError { err, .. } => err,
+        _ => return None,
+    };
+    let (expected, message) = match &err.kind {
+        PutLogEventsErrorKind::InvalidSequenceTokenException(inner) => {
+            (inner.expected_sequence_token(), inner.message())
+        }
+        PutLogEventsErrorKind::DataAlreadyAcceptedException(inner) => {
+            (inner.expected_sequence_token(), inner.message())
+        }
+        _ => return None,
+    };
+    match expected {
+        Some(token) => Some(Some(token.to_string())),
+        None => message.and_then(parse_expected_sequence_token),
+    }
+}
+
+/// Extract the expected token from an error message such as "The given
+/// sequenceToken is invalid. The next expected sequenceToken is: 4959..."
+/// or "The given batch of log events has already been accepted. The next
+/// batch can be sent with sequenceToken: 4959..."
+fn parse_expected_sequence_token(message: &str) -> Option<Option<String>> {
+    let rest = &message[message.rfind("sequenceToken")?..];
+    let start = rest.find(':')? + 1;
+    let token: String = rest[start..]
+        .trim_start()
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric())
+        .collect();
+    match token.as_str() {
+        "" => None,
+        "null" => Some(None),
+        _ => Some(Some(token)),
+    }
+}
+
+/// Make a cheap request to check that CloudWatch Logs can be reached with
+/// the configured region and credentials. A log group that doesn't exist
+/// yet is fine, since it may be created on startup.
+pub async fn check_connection(
+    conf: &Configuration,
+) -> Result<(), ConfigurationError> {
+    let client = Client::new(&conf.aws_config);
+    check_client(&client, &conf.log_group_name)
+        .await
+        .map_err(|err| ConfigurationError::Unreachable(Box::new(err)))
+}
+
+async fn check_client(
+    client: &Client,
+    log_group_name: &str,
+) -> Result<(), SdkError<DescribeLogStreamsError>> {
+    let result = client
+        .describe_log_streams()
+        .log_group_name(log_group_name)
+        .limit(1)
+        .send()
+        .await;
+    match result {
+        Ok(_) => Ok(()),
+        Err(SdkError::ServiceError { err, .. })
+            if err.is_resource_not_found_exception() =>
+        {
+            Ok(())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// The longest delay between attempts to reach CloudWatch Logs on
+/// startup.
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Wait until CloudWatch Logs can be reached, retrying with backoff until
+/// the connect timeout passes. Right after an instance boots, the network
+/// or the instance credentials are often not ready yet. Once the timeout
+/// has passed the uploader starts anyway, and its uploads are retried and
+/// spooled like any others.
+async fn wait_for_connection(client: &Client, conf: &Configuration) {
+    let deadline = Instant::now() + conf.connect_timeout;
+    let backoff = Backoff::new(conf.connect_backoff_base, MAX_CONNECT_BACKOFF);
+    let mut attempt = 0;
+    loop {
+        let err = match check_client(client, &conf.log_group_name).await {
+            Ok(()) => {
+                if attempt > 0 {
+                    info!(
+                        "reached CloudWatch Logs after {} attempts",
+                        attempt + 1
+                    );
+                }
+                return;
+            }
+            Err(err) => err,
+        };
+        let delay = backoff.delay(attempt);
+        if Instant::now() + delay >= deadline {
+            warn!(
+                "could not reach CloudWatch Logs within {:?}, starting \
+                 anyway: {}",
+                conf.connect_timeout, err
+            );
+            return;
+        }
+        warn!(
+            attempt = attempt + 1,
+            "failed to reach CloudWatch Logs, retrying in {:?}: {}", delay, err
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
 }
 
 struct CloudWatch {
     client: Client,
-    sequence_token: Option<String>,
+    /// The client for the region not in use, if a fallback region is
+    /// configured. Switching regions swaps it with `client`.
+    standby: Option<Client>,
+    failover: Failover,
+    /// The sequence token to send next to each log stream that has been
+    /// set up, by stream name.
+    sequence_tokens: HashMap<String, Option<String>>,
     conf: Configuration,
+    backoff: Backoff,
+    metrics: Arc<Metrics>,
 }
 
 impl CloudWatch {
-    async fn new(conf: Configuration) -> CloudWatch {
-        let region_provider = RegionProviderChain::default_provider()
-            .or_else(Region::new("us-west-2"));
-
-        let shared_config =
-            aws_config::from_env().region(region_provider).load().await;
-
-        let client = Client::new(&shared_config);
+    async fn new(conf: Configuration, metrics: Arc<Metrics>) -> CloudWatch {
+        let client = Client::new(&conf.aws_config);
+        let standby = conf.fallback_aws_config.as_ref().map(Client::new);
+        wait_for_connection(&client, &conf).await;
 
         let mut cw = CloudWatch {
-            sequence_token: None,
+            sequence_tokens: HashMap::new(),
             client,
+            standby,
+            failover: Failover::new(),
             conf,
+            metrics,
+            backoff: Backoff::new(
+                Duration::from_millis(100),
+                Duration::from_secs(20),
+            ),
         };
-        cw.update_sequence_token().await;
+        if cw.conf.create_log_group {
+            cw.create_log_group().await;
+        }
+        if let Some(days) = cw.conf.log_retention_days {
+            cw.put_retention_policy(days).await;
+        }
+        // Without sequence tokens there is nothing to look up, and the
+        // stream is created on the first upload
+        if cw.conf.use_sequence_tokens {
+            let stream = cw.conf.log_stream_name.clone();
+            cw.update_sequence_token(&stream).await;
+        }
         cw
     }
 
-    async fn get_log_stream(&self) -> Option<LogStream> {
+    async fn put_retention_policy(&self, days: i32) {
+        if let Err(err) = self
+            .client
+            .put_retention_policy()
+            .log_group_name(self.conf.log_group_name.as_str())
+            .retention_in_days(days)
+            .send()
+            .await
+        {
+            warn!("failed to set log group retention policy: {}", err);
+        }
+    }
+
+    /// Create the log group, which is fine to attempt on every start
+    /// since an existing group is not an error.
+    async fn create_log_group(&self) {
         let result = self
             .client
-            .describe_log_streams()
-            .log_group_name(self.conf.log_group_name.clone())
-            .log_stream_name_prefix(self.conf.log_stream_name.clone())
-            .limit(1)
+            .create_log_group()
+            .log_group_name(self.conf.log_group_name.as_str())
             .send()
             .await;
         match result {
-            Ok(result) => {
-                if let Some(log_streams) = result.log_streams {
-                    if let Some(log_stream) = log_streams.first() {
-                        if log_stream.log_stream_name
-                            == Some(self.conf.log_stream_name.clone())
-                        {
-                            return Some(log_stream.clone());
-                        }
-                    }
-                }
-                None
+            Ok(_) => {
+                debug!("created log group {}", self.conf.log_group_name);
+            }
+            Err(SdkError::ServiceError { err, .. })
+                if err.is_resource_already_exists_exception() => {}
+            Err(err) => {
+                warn!("failed to create log group: {}", err);
             }
-            Err(_) => None,
         }
     }
 
-    async fn create_log_stream(&self) {
-        if let Err(err) = self
+    /// Look up a log stream by its exact name. The API can only filter by
+    /// prefix, so other streams whose names start with this one are
+    /// skipped, paging through them if needed. `Ok(None)` means the stream
+    /// doesn't exist.
+    async fn get_log_stream(
+        &self,
+        stream: &str,
+    ) -> Result<Option<LogStream>, SdkError<DescribeLogStreamsError>> {
+        let mut next_token = None;
+        loop {
+            let output = self.describe_log_streams(stream, next_token).await?;
+            let log_streams = output.log_streams.unwrap_or_default();
+            if let Some(log_stream) = find_log_stream(log_streams, stream) {
+                return Ok(Some(log_stream));
+            }
+            next_token = output.next_token;
+            if next_token.is_none() {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Fetch one page of the streams with a name prefix, retrying
+    /// throttled and failed requests with backoff like uploads.
+    async fn describe_log_streams(
+        &self,
+        prefix: &str,
+        next_token: Option<String>,
+    ) -> Result<DescribeLogStreamsOutput, SdkError<DescribeLogStreamsError>>
+    {
+        let max_attempts = self.conf.max_upload_attempts;
+        let mut attempt = 0;
+        loop {
+            let result = self
+                .client
+                .describe_log_streams()
+                .log_group_name(self.conf.log_group_name.as_str())
+                .log_stream_name_prefix(prefix)
+                .set_next_token(next_token.clone())
+                .send()
+                .await;
+            let err = match result {
+                Ok(output) => return Ok(output),
+                Err(err) => err,
+            };
+            attempt += 1;
+            if attempt >= max_attempts || !is_retryable(&err) {
+                return Err(err);
+            }
+            warn!(
+                attempt,
+                max_attempts, "describe_log_streams failed, retrying: {}", err
+            );
+            self.backoff.sleep(attempt - 1).await;
+        }
+    }
+
+    async fn create_log_stream(&self, stream: &str) {
+        let result = self
             .client
             .create_log_stream()
-            .log_group_name(self.conf.log_group_name.clone())
-            .log_stream_name(self.conf.log_stream_name.clone())
+            .log_group_name(self.conf.log_group_name.as_str())
+            .log_stream_name(stream)
             .send()
-            .await
-        {
-            eprintln!("failed to create log stream: {}", err);
+            .await;
+        match result {
+            Ok(_) => debug!("created log stream {}", stream),
+            Err(SdkError::ServiceError { err, .. })
+                if err.is_resource_already_exists_exception() => {}
+            Err(err) => warn!("failed to create log stream: {}", err),
+        }
+    }
+
+    /// Swap the active and standby clients. Sequence tokens belong to the
+    /// streams of one region, so they are dropped and the streams are set
+    /// up again on first use.
+    async fn switch_region(&mut self) {
+        if let Some(standby) = self.standby.as_mut() {
+            std::mem::swap(&mut self.client, standby);
+        }
+        self.sequence_tokens.clear();
+        if self.conf.create_log_group {
+            self.create_log_group().await;
+        }
+        if let Some(days) = self.conf.log_retention_days {
+            self.put_retention_policy(days).await;
         }
     }
 
-    async fn update_sequence_token(&mut self) {
-        let mut log_stream = self.get_log_stream().await;
-        if log_stream.is_none() {
-            self.create_log_stream().await;
-            log_stream = self.get_log_stream().await;
+    /// While on the fallback region, periodically check whether the
+    /// primary region is reachable again and switch back if it is.
+    async fn probe_primary(&mut self) {
+        if !self.failover.should_probe(Instant::now()) {
+            return;
+        }
+        let primary = match &self.standby {
+            Some(primary) => primary,
+            None => return,
+        };
+        match check_client(primary, &self.conf.log_group_name).await {
+            Ok(_) => {
+                info!("the primary region is reachable again, switching back");
+                self.failover.recover();
+                self.switch_region().await;
+            }
+            Err(err) => debug!("the primary region is still failing: {}", err),
+        }
+    }
+
+    async fn update_sequence_token(&mut self, stream: &str) {
+        let token = self.fetch_sequence_token(stream).await;
+        self.sequence_tokens.insert(stream.to_string(), token);
+    }
+
+    /// Take the sequence token of a stream out of the map while it is
+    /// being uploaded to, creating the stream, and looking up its token if
+    /// sequence tokens are used, if it hasn't been used yet.
+    async fn take_sequence_token(&mut self, stream: &str) -> Option<String> {
+        match self.sequence_tokens.remove(stream) {
+            Some(token) => token,
+            None => self.refresh_sequence_token(stream).await,
         }
+    }
 
-        if let Some(log_stream) = log_stream {
-            self.sequence_token = log_stream.upload_sequence_token;
+    /// Look up the sequence token of a stream in the legacy mode; without
+    /// it the stream only has to exist.
+    async fn refresh_sequence_token(&self, stream: &str) -> Option<String> {
+        if self.conf.use_sequence_tokens {
+            self.fetch_sequence_token(stream).await
         } else {
-            eprintln!("log stream {} does not exist", self.conf.path());
+            self.create_log_stream(stream).await;
+            None
+        }
+    }
+
+    /// Look up the sequence token of a stream, creating the stream if it
+    /// doesn't exist. A stream that couldn't be looked up isn't created,
+    /// since it most likely exists.
+    async fn fetch_sequence_token(&self, stream: &str) -> Option<String> {
+        let mut log_stream = self.get_log_stream(stream).await;
+        if let Ok(None) = log_stream {
+            self.create_log_stream(stream).await;
+            log_stream = self.get_log_stream(stream).await;
+        }
+
+        match log_stream {
+            Ok(Some(log_stream)) => log_stream.upload_sequence_token,
+            Ok(None) => {
+                warn!(
+                    "log stream {}/{} does not exist",
+                    self.conf.log_group_name, stream
+                );
+                None
+            }
+            Err(err) => {
+                warn!(
+                    "failed to look up log stream {}/{}: {}",
+                    self.conf.log_group_name, stream, err
+                );
+                None
+            }
         }
     }
 }
 
-fn do_group_events(events: Vec<InputLogEvent>) -> Vec<Vec<InputLogEvent>> {
-    // Group events by 16 hour windows (cloudwatch requires events be in 24 groups)
+/// The stream with exactly this name among those with it as a prefix.
+fn find_log_stream(
+    log_streams: Vec<LogStream>,
+    stream: &str,
+) -> Option<LogStream> {
+    log_streams.into_iter().find(|log_stream| {
+        log_stream.log_stream_name.as_deref() == Some(stream)
+    })
+}
 
-    // Why in this form? Because it's a bit easier to understand that it's a time.
-    let sixteen =
-        i64::try_from(Duration::from_secs(57600).as_millis()).unwrap();
+pub fn do_group_events(events: Vec<InputLogEvent>) -> Vec<Vec<InputLogEvent>> {
+    // Group events so each group spans at most BATCH_WINDOW and stays
+    // within the PutLogEvents count and size limits
+    let window = i64::try_from(BATCH_WINDOW.as_millis()).unwrap();
 
     let mut groups: Vec<Vec<InputLogEvent>> = Vec::new();
-    // First, we order the events by their timestamps
-    let mut sorted = events.to_vec();
-    sorted.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    let mut last_group_bytes = 0;
+    // First, we order the events by their timestamps. The sort is stable,
+    // so the many events that share a millisecond keep the order they
+    // were read from the journal in, which is the order they were written
+    let mut sorted = events;
+    sorted.sort_by_key(|event| event.timestamp);
     for event in sorted.into_iter() {
-        if let None = groups.last() {
-            let mut new_group = Vec::new();
-            new_group.push(event);
-            groups.push(new_group);
+        let event_num_bytes = get_event_num_bytes(&event);
+        if groups.last().is_none() {
+            groups.push(vec![event]);
+            last_group_bytes = event_num_bytes;
             continue;
         }
 
@@ -120,22 +596,24 @@ fn do_group_events(events: Vec<InputLogEvent>) -> Vec<Vec<InputLogEvent>> {
         let first = existing_group.first().unwrap();
         let too_new = match event.timestamp {
             Some(ts) => match first.timestamp {
-                Some(fts) => ts - sixteen > fts,
+                Some(fts) => ts - window > fts,
                 None => true,
             },
             None => true,
         };
+        let too_full = existing_group.len() >= MAX_BATCH_EVENTS
+            || last_group_bytes + event_num_bytes > MAX_BATCH_BYTES;
 
-        if too_new {
-            // too new; make a new group
+        if too_new || too_full {
+            // too new or no room left; make a new group
             // but first, put the old one back
             groups.push(existing_group);
-            let mut new_group = Vec::new();
-            new_group.push(event);
-            groups.push(new_group);
+            groups.push(vec![event]);
+            last_group_bytes = event_num_bytes;
         } else {
             existing_group.push(event);
             groups.push(existing_group);
+            last_group_bytes += event_num_bytes;
         }
     }
     groups
@@ -150,172 +628,1054 @@ impl Uploader for CloudWatch {
         do_group_events(events)
     }
 
-    async fn upload(&mut self, events: Vec<InputLogEvent>) {
-        self.conf
-            .debug(format!("--F> uploading {} events", events.len()));
-        for group in self.group_events(events).iter() {
+    async fn upload(
+        &mut self,
+        events: Vec<InputLogEvent>,
+    ) -> Result<(), Undelivered> {
+        let stream = self.conf.log_stream_name.clone();
+        self.upload_to_stream(&stream, events).await
+    }
+
+    async fn upload_to_stream(
+        &mut self,
+        stream: &str,
+        events: Vec<InputLogEvent>,
+    ) -> Result<(), Undelivered> {
+        self.probe_primary().await;
+        let mut token = self.take_sequence_token(stream).await;
+        let result = self.put_stream(stream, events, &mut token).await;
+        self.sequence_tokens.insert(stream.to_string(), token);
+        self.record_failover(result.is_ok()).await;
+        result
+    }
+
+    /// Upload to up to `MAX_CONCURRENT_UPLOADS` streams at once. The puts
+    /// to one stream have to be serialized, since each needs the sequence
+    /// token returned by the previous one, so each stream's token is taken
+    /// out of the map and owned by the one upload using it until it's
+    /// done. Batches are only ever uploaded concurrently across streams:
+    /// those that resolve to the same stream, such as the default one
+    /// named both explicitly and by `None`, are uploaded one after the
+    /// other with the same token.
+    async fn upload_batches(
+        &mut self,
+        batches: Vec<(Option<String>, Vec<InputLogEvent>)>,
+    ) -> Vec<Result<(), Undelivered>> {
+        self.probe_primary().await;
+        let num_batches = batches.len();
+        let mut streams: Vec<(String, Vec<(usize, Vec<InputLogEvent>)>)> =
+            Vec::new();
+        for (index, (stream, events)) in batches.into_iter().enumerate() {
+            let stream =
+                stream.unwrap_or_else(|| self.conf.log_stream_name.clone());
+            match streams.iter_mut().find(|(name, _)| *name == stream) {
+                Some((_, stream_batches)) => {
+                    stream_batches.push((index, events))
+                }
+                None => streams.push((stream, vec![(index, events)])),
+            }
+        }
+        let mut uploads = Vec::new();
+        for (stream, stream_batches) in streams {
+            let token = self.take_sequence_token(&stream).await;
+            uploads.push((stream, stream_batches, token));
+        }
+        let cw = &*self;
+        let finished: Vec<_> = stream::iter(uploads)
+            .map(|(stream, stream_batches, mut token)| async move {
+                let mut results = Vec::new();
+                for (index, events) in stream_batches {
+                    let result =
+                        cw.put_stream(&stream, events, &mut token).await;
+                    results.push((index, result));
+                }
+                (stream, token, results)
+            })
+            .buffered(self.conf.max_concurrent_uploads)
+            .collect()
+            .await;
+        let mut results: Vec<Option<Result<(), Undelivered>>> =
+            (0..num_batches).map(|_| None).collect();
+        for (stream, token, stream_results) in finished {
+            self.sequence_tokens.insert(stream, token);
+            for (index, result) in stream_results {
+                results[index] = Some(result);
+            }
+        }
+        let results: Vec<Result<(), Undelivered>> =
+            results.into_iter().flatten().collect();
+        self.record_failover(results.iter().all(Result::is_ok))
+            .await;
+        results
+    }
+}
+
+impl CloudWatch {
+    /// Upload events to a stream, group by group, with the stream's
+    /// sequence token, which is updated as the groups are accepted.
+    async fn put_stream(
+        &self,
+        stream: &str,
+        events: Vec<InputLogEvent>,
+        token: &mut Option<String>,
+    ) -> Result<(), Undelivered> {
+        debug!("--F> uploading {} events to {}", events.len(), stream);
+        let mut undelivered = Vec::new();
+        let mut last_error = None;
+        for mut group in self.group_events(events) {
+            if let Err(error) = self.put_group(stream, &group, token).await {
+                undelivered.append(&mut group);
+                last_error = Some(error);
+            }
+        }
+        match last_error {
+            Some(error) => Err(Undelivered::new(error, undelivered)),
+            None => Ok(()),
+        }
+    }
+
+    /// Switch to the fallback region once uploads have kept failing for
+    /// long enough.
+    async fn record_failover(&mut self, delivered: bool) {
+        if self.standby.is_some()
+            && self.failover.record_upload(delivered, Instant::now())
+        {
+            warn!(
+                "uploads have failed for {:?}, switching to the fallback \
+                 region",
+                failover::FAILOVER_AFTER
+            );
+            self.switch_region().await;
+        }
+    }
+
+    /// Send one group of events, retrying throttled and failed requests
+    /// with backoff up to the configured number of attempts. An invalid
+    /// sequence token is refreshed and retried straight away without
+    /// counting as an attempt, though the number of refreshes is bounded
+    /// by the same limit so a persistent conflict can't loop forever.
+    async fn put_group(
+        &self,
+        stream: &str,
+        group: &[InputLogEvent],
+        token: &mut Option<String>,
+    ) -> Result<(), UploadError> {
+        let max_attempts = self.conf.max_upload_attempts;
+        let mut attempt = 0;
+        let mut token_refreshes = 0;
+        loop {
             let mut call = self
                 .client
                 .put_log_events()
-                .log_group_name(self.conf.log_group_name.clone())
-                .log_stream_name(self.conf.log_stream_name.clone());
-            if let Some(sequence_token) = &self.sequence_token {
+                .log_group_name(self.conf.log_group_name.as_str())
+                .log_stream_name(stream);
+            if let Some(sequence_token) = token.as_deref() {
                 call = call.sequence_token(sequence_token);
             }
             call = call.set_log_events(Some(group.to_vec()));
+            let started = Instant::now();
             let result = call.send().await;
-            match result {
+            self.metrics.record_put_latency(started.elapsed());
+            let err = match result {
                 Ok(result) => {
-                    self.sequence_token = result.next_sequence_token;
+                    if self.conf.use_sequence_tokens {
+                        *token = result.next_sequence_token;
+                    }
+                    return Ok(());
                 }
-                Err(err) => {
-                    eprintln!("--F> send_to_cloudwatch failed: {}", err);
-                    self.update_sequence_token().await
+                Err(err) => err,
+            };
+            let error = UploadError::from_sdk_error(&err);
+
+            if is_data_already_accepted(&err) {
+                // A previous attempt (or run) already delivered this
+                // batch, so there is nothing left to send
+                debug!("--F> batch was already accepted: {}", err);
+                if self.conf.use_sequence_tokens {
+                    *token = match get_expected_sequence_token(&err) {
+                        Some(expected) => expected,
+                        None => self.fetch_sequence_token(stream).await,
+                    };
                 }
+                return Ok(());
             }
+
+            if error == UploadError::InvalidToken
+                && !self.conf.use_sequence_tokens
+            {
+                error!(
+                    events = group.len(),
+                    "--F> the endpoint still requires sequence tokens, set \
+                     USE_SEQUENCE_TOKENS=true: {}",
+                    err
+                );
+                self.metrics
+                    .record_upload_error(&err, Utc::now().timestamp());
+                return Err(error);
+            }
+
+            if error == UploadError::InvalidToken
+                && token_refreshes < max_attempts
+            {
+                token_refreshes += 1;
+                *token = match get_expected_sequence_token(&err) {
+                    Some(expected) => {
+                        debug!(
+                            "--F> invalid sequence token, retrying with the \
+                             expected token {:?}",
+                            expected
+                        );
+                        expected
+                    }
+                    None => {
+                        debug!(
+                            "--F> invalid sequence token, refreshing: {}",
+                            err
+                        );
+                        self.fetch_sequence_token(stream).await
+                    }
+                };
+                continue;
+            }
+
+            attempt += 1;
+            if attempt < max_attempts && error.is_retryable() {
+                warn!(
+                    attempt,
+                    max_attempts,
+                    error = error.name(),
+                    "--F> send_to_cloudwatch failed, retrying: {}",
+                    err
+                );
+                self.backoff.sleep(attempt - 1).await;
+                continue;
+            }
+
+            error!(
+                attempt,
+                events = group.len(),
+                error = error.name(),
+                "--F> send_to_cloudwatch failed, giving up: {}",
+                err
+            );
+            self.metrics
+                .record_upload_error(&err, Utc::now().timestamp());
+            *token = self.refresh_sequence_token(stream).await;
+            return Err(error);
         }
     }
 }
 
 /// Calculate the number of bytes this message requires as counted
-/// by the PutLogEvents API.
+/// by the PutLogEvents API. This is the length of the message as it will
+/// be sent, so JSON encoded messages are counted after encoding.
 ///
 /// Reference:
 /// docs.aws.amazon.com/AmazonCloudWatchLogs/latest/APIReference/API_PutLogEvents.html
-fn get_event_num_bytes(event: &InputLogEvent) -> usize {
+pub fn get_event_num_bytes(event: &InputLogEvent) -> usize {
     match &event.message {
-        Some(m) => m.len() + 26,
-        None => 26,
+        Some(m) => m.len() + EVENT_OVERHEAD_BYTES,
+        None => EVENT_OVERHEAD_BYTES,
+    }
+}
+
+/// The number of bytes PutLogEvents adds to the length of each message
+/// when counting the size of a batch.
+///
+/// Reference:
+/// docs.aws.amazon.com/AmazonCloudWatchLogs/latest/APIReference/API_PutLogEvents.html
+pub const EVENT_OVERHEAD_BYTES: usize = 26;
+
+/// The largest event PutLogEvents accepts, including the overhead.
+pub const MAX_EVENT_BYTES: usize = 256 * 1024;
+
+/// The longest time between the first and last event of a batch.
+/// PutLogEvents rejects batches that span more than 24 hours; staying well
+/// under that leaves room for clock skew between hosts. An event exactly
+/// this long after the first one still fits in the batch.
+pub const BATCH_WINDOW: Duration = Duration::from_secs(16 * 60 * 60);
+
+/// The most events a single PutLogEvents call accepts.
+pub const MAX_BATCH_EVENTS: usize = 10000;
+
+/// The largest batch a single PutLogEvents call accepts, counting each
+/// event with its overhead of `EVENT_OVERHEAD_BYTES`.
+///
+/// Reference:
+/// docs.aws.amazon.com/AmazonCloudWatchLogs/latest/APIReference/API_PutLogEvents.html
+pub const MAX_BATCH_BYTES: usize = 1024 * 1024;
+
+/// Split an event that is too large for PutLogEvents into several events
+/// with the same timestamp. Each part ends with a `(part N/M)` suffix and
+/// is cut on a UTF-8 character boundary. Events that fit are returned
+/// unchanged.
+fn split_oversized_event(event: InputLogEvent) -> Vec<InputLogEvent> {
+    if get_event_num_bytes(&event) <= MAX_EVENT_BYTES {
+        return vec![event];
+    }
+    let message = event.message.clone().unwrap_or_default();
+    let max_message_bytes = MAX_EVENT_BYTES - EVENT_OVERHEAD_BYTES;
+
+    // The suffix length depends on the number of parts, so find the
+    // smallest digit count that leaves enough room for all of them
+    let mut digits = 1;
+    let chunks = loop {
+        let suffix_bytes = " (part /)".len() + 2 * digits;
+        let chunks = split_on_char_boundaries(
+            &message,
+            max_message_bytes - suffix_bytes,
+        );
+        if chunks.len() < 10usize.pow(digits as u32) {
+            break chunks;
+        }
+        digits += 1;
+    };
+
+    let num_parts = chunks.len();
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            InputLogEvent::builder()
+                .message(format!("{} (part {}/{})", chunk, i + 1, num_parts))
+                .set_timestamp(event.timestamp)
+                .build()
+        })
+        .collect()
+}
+
+/// Split the text into pieces of at most `max_bytes` bytes without
+/// breaking up any multi-byte UTF-8 characters.
+fn split_on_char_boundaries(text: &str, max_bytes: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut rest = text;
+    while rest.len() > max_bytes {
+        let mut end = max_bytes;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        chunks.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    if !rest.is_empty() || chunks.is_empty() {
+        chunks.push(rest);
+    }
+    chunks
+}
+
+/// PutLogEvents rejects events older than this.
+const MAX_EVENT_AGE: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+/// PutLogEvents rejects events further in the future than this.
+const MAX_EVENT_FUTURE: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// What to do with an event's timestamp so that PutLogEvents accepts it.
+#[derive(Debug, PartialEq, Eq)]
+enum TimestampCheck {
+    Accept,
+    /// The event is too old to be uploaded.
+    Drop,
+    /// The event is too far in the future; use the current time.
+    Clamp,
+}
+
+fn check_timestamp(timestamp: i64, now: i64) -> TimestampCheck {
+    let max_age = MAX_EVENT_AGE.as_millis() as i64;
+    let max_future = MAX_EVENT_FUTURE.as_millis() as i64;
+    if timestamp < now - max_age {
+        TimestampCheck::Drop
+    } else if timestamp > now + max_future {
+        TimestampCheck::Clamp
+    } else {
+        TimestampCheck::Accept
+    }
+}
+
+/// An event read from the journal, along with the journal cursor of the
+/// record it came from and the log stream it goes to, if that isn't the
+/// default one.
+#[derive(Clone, Debug)]
+pub struct JournalEvent {
+    pub event: InputLogEvent,
+    pub cursor: Option<String>,
+    pub stream: Option<String>,
+    /// The record's timestamp in microseconds, as the journal keeps it,
+    /// which orders events within the millisecond of the uploaded one.
+    pub timestamp_micros: Option<i64>,
+}
+
+impl JournalEvent {
+    pub fn new(event: InputLogEvent, cursor: Option<String>) -> JournalEvent {
+        JournalEvent {
+            event,
+            cursor,
+            stream: None,
+            timestamp_micros: None,
+        }
+    }
+
+    pub fn with_stream(mut self, stream: Option<String>) -> JournalEvent {
+        self.stream = stream;
+        self
+    }
+
+    pub fn with_timestamp_micros(mut self, timestamp: i64) -> JournalEvent {
+        self.timestamp_micros = Some(timestamp);
+        self
+    }
+
+    /// The microsecond timestamp, or the event's own if it has none.
+    fn get_timestamp_micros(&self) -> Option<i64> {
+        self.timestamp_micros
+            .or_else(|| self.event.timestamp.map(|timestamp| timestamp * 1000))
     }
 }
 
 struct UploadThreadState<U: Uploader> {
     conf: Configuration,
     uploader: U,
-    events: Vec<InputLogEvent>,
+    metrics: Arc<Metrics>,
+    cursor_store: CursorStore,
+    spool: Option<SpoolStore>,
+    /// Pending events by log stream, where `None` is the default stream.
+    events: BTreeMap<Option<String>, Vec<InputLogEvent>>,
+    num_pending_events: usize,
+    cursor: Option<String>,
+    /// The position of the last event known to be delivered, whether in
+    /// this run or a previous one. Events at or before it are replays and
+    /// are skipped.
+    confirmed: Option<JournalPosition>,
+    /// The microsecond timestamps of the first and last pending events.
     first_timestamp: Option<i64>,
     last_timestamp: Option<i64>,
     num_pending_bytes: usize,
+    /// When the oldest pending event was pushed.
+    pending_since: Option<Instant>,
+    /// When the discarded event counts were last logged.
+    last_drop_summary: Instant,
+    /// Events dropped since the buffer went over its limit, or 0 while
+    /// it's under.
+    num_overflow_drops: u64,
 }
 
 impl<U: Uploader> UploadThreadState<U> {
-    fn new(uploader: U, conf: Configuration) -> UploadThreadState<U> {
+    fn new(
+        uploader: U,
+        conf: Configuration,
+        metrics: Arc<Metrics>,
+    ) -> UploadThreadState<U> {
+        let cursor_store = CursorStore::new(conf.cursor_path.clone());
+        let confirmed = cursor_store
+            .load()
+            .and_then(|cursor| JournalPosition::parse(&cursor));
         UploadThreadState {
+            metrics,
+            cursor_store,
+            spool: conf
+                .spool_dir
+                .clone()
+                .map(|dir| SpoolStore::new(dir, conf.spool_max_bytes)),
             conf,
             uploader,
-            events: Vec::new(),
+            events: BTreeMap::new(),
+            num_pending_events: 0,
+            cursor: None,
+            confirmed,
             first_timestamp: None,
             last_timestamp: None,
             num_pending_bytes: 0,
+            pending_since: None,
+            last_drop_summary: Instant::now(),
+            num_overflow_drops: 0,
+        }
+    }
+
+    /// Push an event taken off the queue, unless the queue and the pending
+    /// events together already hold `MAX_BUFFERED_BYTES`. Then the event,
+    /// the oldest one still queued, is dropped instead so a stalled upload
+    /// can't use up the host's memory.
+    async fn receive(&mut self, journal_event: JournalEvent) {
+        let num_bytes = get_event_num_bytes(&journal_event.event) as u64;
+        let queued_bytes = self
+            .metrics
+            .queued_bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bytes| {
+                Some(bytes.saturating_sub(num_bytes))
+            })
+            .unwrap_or_default()
+            .saturating_sub(num_bytes);
+        let buffered_bytes =
+            queued_bytes + self.num_pending_bytes as u64 + num_bytes;
+        if buffered_bytes > self.conf.max_buffered_bytes {
+            if self.num_overflow_drops == 0 {
+                warn!(
+                    "more than {} bytes of events are buffered, dropping the \
+                     oldest queued events",
+                    self.conf.max_buffered_bytes
+                );
+            }
+            self.num_overflow_drops += 1;
+            self.metrics.record_drop(DropReason::BufferFull, 1);
+            return;
+        }
+        if self.num_overflow_drops > 0 {
+            warn!(
+                "dropped {} events while the buffer was full",
+                self.num_overflow_drops
+            );
+            self.num_overflow_drops = 0;
+        }
+        self.push(journal_event).await;
+    }
+
+    async fn push(&mut self, journal_event: JournalEvent) {
+        let mut timestamp_micros = journal_event.get_timestamp_micros();
+        let JournalEvent {
+            mut event,
+            cursor,
+            stream,
+            ..
+        } = journal_event;
+
+        if self.is_replay(cursor.as_deref()) {
+            debug!("skipping already delivered event {:?}", cursor);
+            return;
+        }
+
+        // A single event outside the accepted time window would make
+        // the whole batch fail
+        if let Some(timestamp) = event.timestamp {
+            let now = Utc::now().timestamp_millis();
+            match check_timestamp(timestamp, now) {
+                TimestampCheck::Accept => {}
+                TimestampCheck::Drop => {
+                    self.metrics.record_drop(DropReason::TooOld, 1);
+                    debug!(
+                        "dropping event with timestamp {}, older than {:?}",
+                        timestamp, MAX_EVENT_AGE
+                    );
+                    return;
+                }
+                TimestampCheck::Clamp => {
+                    debug!(
+                        "clamping future event timestamp {} to {}",
+                        timestamp, now
+                    );
+                    event.timestamp = Some(now);
+                    timestamp_micros = Some(now * 1000);
+                }
+            }
+        }
+
+        let parts = split_oversized_event(event);
+        let num_parts = parts.len();
+        for (i, part) in parts.into_iter().enumerate() {
+            // Only the last part completes the journal record
+            let part_cursor = if i + 1 == num_parts {
+                cursor.clone()
+            } else {
+                None
+            };
+            self.push_event(
+                stream.clone(),
+                part,
+                part_cursor,
+                timestamp_micros,
+            )
+            .await;
+        }
+    }
+
+    /// Check whether the event with this cursor has been delivered
+    /// already, which happens when the journal is read again from before
+    /// the saved cursor, for example with `JOURNAL_SEEK=head`.
+    fn is_replay(&self, cursor: Option<&str>) -> bool {
+        let position = cursor.and_then(JournalPosition::parse);
+        match (position, &self.confirmed) {
+            (Some(position), Some(confirmed)) => {
+                position.is_at_or_before(confirmed)
+            }
+            _ => false,
         }
     }
 
-    async fn push(&mut self, event: InputLogEvent) {
+    async fn push_event(
+        &mut self,
+        stream: Option<String>,
+        event: InputLogEvent,
+        cursor: Option<String>,
+        timestamp_micros: Option<i64>,
+    ) {
         // Flush if the latest event's timestamp is older than the
-        // previous event
+        // previous event. Comparing microseconds keeps events of the same
+        // millisecond in order too, since the batch is only sorted by the
+        // millisecond timestamp it is uploaded with. Events read from
+        // several journals alternate between them and go back in time
+        // all the time, so they are left to that sort, which keeps each
+        // journal's events in the order they were read
         if let Some(last_timestamp) = self.last_timestamp {
-            if event.timestamp < Some(last_timestamp) {
+            if timestamp_micros < Some(last_timestamp)
+                && self.conf.journal_sources.len() <= 1
+            {
                 self.flush().await;
             }
         }
 
         // Flush if the maximum size (in bytes) of events has been reached
-        let max_bytes = 1048576;
         let event_num_bytes = get_event_num_bytes(&event);
-        if self.num_pending_bytes + event_num_bytes > max_bytes {
-            self.flush().await;
-        }
-
-        // Flush if the maximum number of events has been reached
-        let max_events = if self.conf.is_debug_mode_enabled {
-            1
-        } else {
-            100
-        };
-        if self.events.len() + 1 >= max_events {
+        if self.num_pending_bytes + event_num_bytes > MAX_BATCH_BYTES {
             self.flush().await;
         }
 
         // Add the event to the pending events
         if self.first_timestamp.is_none() {
-            self.first_timestamp = event.timestamp;
+            self.first_timestamp = timestamp_micros;
         }
-        self.last_timestamp = event.timestamp;
+        self.last_timestamp = timestamp_micros;
         self.num_pending_bytes += event_num_bytes;
-        self.events.push(event);
+        self.pending_since.get_or_insert_with(Instant::now);
+        self.events.entry(stream).or_default().push(event);
+        self.num_pending_events += 1;
+        if cursor.is_some() {
+            self.cursor = cursor;
+        }
+
+        // Flush once the maximum number of events has been reached
+        if self.num_pending_events >= self.conf.max_events_per_batch {
+            self.flush().await;
+        }
     }
 
-    /// Upload all pending events to CloudWatch Logs
+    /// Upload all pending events to CloudWatch Logs, then persist the
+    /// cursor of the newest event if the upload succeeded. Spooled batches
+    /// are uploaded first, and events that can't be delivered are spooled
+    /// if a spool is configured.
     async fn flush(&mut self) {
-        self.conf.debug(format!("flush: {}", self.summary()));
+        debug!("flush: {}", self.summary());
+        if self.last_drop_summary.elapsed() >= DROP_SUMMARY_INTERVAL {
+            debug!("discarded events: {}", self.metrics.drop_summary());
+            self.last_drop_summary = Instant::now();
+        }
 
         if self.events.is_empty() {
             return;
         }
 
-        let mut events = Vec::new();
-        std::mem::swap(&mut events, &mut self.events);
-        self.uploader.upload(events).await;
+        let batches = std::mem::take(&mut self.events);
+        self.num_pending_events = 0;
+        // While spooled batches are failing there's no point trying the
+        // live events, and spooling them keeps the upload order intact
+        let spool_delivered = self.upload_spooled().await;
+        let streams: Vec<Option<String>> = batches.keys().cloned().collect();
+        let results = if spool_delivered {
+            self.upload_batches(batches.into_iter().collect()).await
+        } else {
+            // Held back behind the spool, as if they had failed like it
+            batches
+                .into_values()
+                .map(|events| Err(Undelivered::new(UploadError::Other, events)))
+                .collect()
+        };
+        let mut delivered = true;
+        for (stream, result) in streams.into_iter().zip(results) {
+            // Spooled events will still be uploaded, so it's as safe to
+            // move the cursor past them as past delivered ones
+            if let Err(undelivered) = result {
+                delivered &= self.spool_events(stream, undelivered.events);
+            }
+        }
+        let cursor = self.cursor.take();
+        // A dry run hasn't delivered anything, so don't let it move the
+        // cursor a real run would resume from
+        if delivered && !self.conf.dry_run {
+            if let Some(cursor) = cursor {
+                if let Err(err) = self.cursor_store.save(&cursor) {
+                    warn!("failed to save journal cursor: {}", err);
+                }
+                if let Some(position) = JournalPosition::parse(&cursor) {
+                    self.confirmed = Some(position);
+                }
+            }
+        }
         self.first_timestamp = None;
         self.last_timestamp = None;
         self.num_pending_bytes = 0;
+        self.pending_since = None;
     }
 
-    fn summary(&self) -> String {
+    /// When the pending events should be flushed if no batch limit is
+    /// reached first, given when the last event was received: once no new
+    /// event has arrived for the flush interval, but not before the oldest
+    /// one has lingered for `BATCH_LINGER_MS`, and never after it has
+    /// waited `MAX_BATCH_LATENCY_MS`. `None` if nothing is pending.
+    fn flush_deadline(&self, last_received: Instant) -> Option<Instant> {
+        let pending_since = self.pending_since?;
+        let deadline = (last_received + self.conf.flush_interval)
+            .max(pending_since + self.conf.batch_linger);
+        Some(match self.conf.max_batch_latency {
+            Some(max_latency) => deadline.min(pending_since + max_latency),
+            None => deadline,
+        })
+    }
+
+    /// Whether the oldest pending event has waited `MAX_BATCH_LATENCY_MS`,
+    /// which a steady stream of events would otherwise keep postponing.
+    fn is_latency_exceeded(&self, now: Instant) -> bool {
+        match (self.pending_since, self.conf.max_batch_latency) {
+            (Some(pending_since), Some(max_latency)) => {
+                now >= pending_since + max_latency
+            }
+            _ => false,
+        }
+    }
+
+    /// Retry the spooled batches, oldest first, returning false if one of
+    /// them still couldn't be delivered. Each batch's file is only removed
+    /// once the batch is delivered; the batches after a failed one are left
+    /// in the spool without being tried.
+    async fn upload_spooled(&mut self) -> bool {
+        loop {
+            let (path, SpooledBatch { stream, events }) = match &self.spool {
+                Some(spool) => match spool.oldest() {
+                    Some(oldest) => oldest,
+                    None => return true,
+                },
+                None => return true,
+            };
+            // Events may have aged out of the accepted window while spooled
+            let now = Utc::now().timestamp_millis();
+            let batch: Vec<InputLogEvent> = events
+                .into_iter()
+                .filter(|event| match event.timestamp {
+                    Some(timestamp) => {
+                        check_timestamp(timestamp, now) != TimestampCheck::Drop
+                    }
+                    None => true,
+                })
+                .collect();
+            let num_events = batch.len();
+            let result = if batch.is_empty() {
+                Ok(())
+            } else {
+                debug!("uploading {} spooled events", num_events);
+                self.upload_batch(&stream, batch).await
+            };
+            let spool = match &self.spool {
+                Some(spool) => spool,
+                None => return true,
+            };
+            match result {
+                Ok(()) => spool.remove(&path),
+                Err(undelivered) => {
+                    // Keep only what is left to deliver, so the delivered
+                    // part of the batch isn't sent again
+                    if undelivered.events.len() < num_events {
+                        if let Err(err) = spool.replace(
+                            &path,
+                            stream.as_deref(),
+                            &undelivered.events,
+                        ) {
+                            warn!(
+                                "failed to rewrite {}: {}",
+                                path.display(),
+                                err
+                            );
+                        }
+                    }
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Upload events to a log stream and count the result, returning the
+    /// events that weren't delivered with the error.
+    async fn upload_batch(
+        &mut self,
+        stream: &Option<String>,
+        events: Vec<InputLogEvent>,
+    ) -> Result<(), Undelivered> {
+        let mut results =
+            self.upload_batches(vec![(stream.clone(), events)]).await;
+        results.pop().unwrap_or(Ok(()))
+    }
+
+    /// Upload batches to their log streams, as many at once as the
+    /// uploader allows, and count the results.
+    async fn upload_batches(
+        &mut self,
+        batches: Vec<(Option<String>, Vec<InputLogEvent>)>,
+    ) -> Vec<Result<(), Undelivered>> {
+        let stats: Vec<(usize, Option<i64>)> = batches
+            .iter()
+            .map(|(_, events)| {
+                let newest_timestamp =
+                    events.iter().filter_map(|event| event.timestamp).max();
+                (events.len(), newest_timestamp)
+            })
+            .collect();
+        let results = self.uploader.upload_batches(batches).await;
+        for ((num_events, newest_timestamp), result) in
+            stats.into_iter().zip(results.iter())
+        {
+            match result {
+                Ok(()) => {
+                    self.record_upload(num_events);
+                    if let Some(timestamp) = newest_timestamp {
+                        self.record_lag(timestamp);
+                    }
+                }
+                Err(undelivered) => {
+                    self.record_upload(num_events - undelivered.events.len());
+                    self.metrics.record_upload_failure(
+                        undelivered.error,
+                        Utc::now().timestamp(),
+                    );
+                }
+            }
+        }
+        results
+    }
+
+    /// Count events that were accepted by the uploader.
+    fn record_upload(&self, num_events: usize) {
+        if num_events == 0 {
+            return;
+        }
+        self.metrics
+            .events_forwarded
+            .fetch_add(num_events as u64, Ordering::Relaxed);
+        self.metrics
+            .batches_uploaded
+            .fetch_add(1, Ordering::Relaxed);
+        self.metrics
+            .last_upload_timestamp
+            .store(Utc::now().timestamp(), Ordering::Relaxed);
+    }
+
+    /// Record how far behind the clock the newest uploaded event was.
+    fn record_lag(&self, timestamp: i64) {
+        let lag = Utc::now().timestamp_millis() - timestamp;
+        self.metrics.upload_lag_millis.store(lag, Ordering::Relaxed);
+        let requests = self.metrics.put_requests.load(Ordering::Relaxed);
+        let latency = self.metrics.put_latency_micros.load(Ordering::Relaxed);
+        debug!(
+            "upload lag {}ms, mean PutLogEvents latency {}ms",
+            lag,
+            latency / requests.max(1) / 1000
+        );
+    }
+
+    /// Write undelivered events to the spool, returning true if they were
+    /// saved.
+    fn spool_events(
+        &self,
+        stream: Option<String>,
+        events: Vec<InputLogEvent>,
+    ) -> bool {
+        let num_events = events.len();
+        let spooled = match &self.spool {
+            Some(spool) => match spool.enqueue(stream.as_deref(), events) {
+                Ok(()) => {
+                    warn!("spooled {} undelivered events", num_events);
+                    true
+                }
+                Err(err) => {
+                    warn!(
+                        "failed to spool {} undelivered events: {}",
+                        num_events, err
+                    );
+                    false
+                }
+            },
+            None => {
+                warn!("dropping {} undelivered events", num_events);
+                false
+            }
+        };
+        if !spooled {
+            self.metrics
+                .record_drop(DropReason::Undelivered, num_events as u64);
+        }
+        spooled
+    }
+
+    fn summary(&self) -> String {
         format!("events.len()={}, first_timestamp={:?}, last_timestamp={:?}, num_pending_bytes={}",
-                self.events.len(),
+                self.num_pending_events,
                 self.first_timestamp,
                 self.last_timestamp, self.num_pending_bytes)
     }
 }
 
-pub async fn upload_thread(
-    conf: Configuration,
-    mut rx: mpsc::Receiver<InputLogEvent>,
+/// How often the discarded event counts are logged, at debug level.
+const DROP_SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The most events taken off the queue at once.
+const MAX_RECEIVE_BURST: usize = 256;
+
+/// Take the events that are already waiting in the queue after `first`,
+/// up to `MAX_RECEIVE_BURST`, without waiting for more.
+fn receive_burst(
+    first: JournalEvent,
+    rx: &mut mpsc::Receiver<JournalEvent>,
+) -> Vec<JournalEvent> {
+    let mut burst = vec![first];
+    while burst.len() < MAX_RECEIVE_BURST {
+        match rx.try_recv() {
+            Ok(event) => burst.push(event),
+            Err(_) => break,
+        }
+    }
+    burst
+}
+
+/// Push events from the queue until it is closed. Pending events are
+/// flushed whenever no new event has arrived for the flush interval, so
+/// a quiet host doesn't hold on to them indefinitely, subject to the
+/// batch linger and latency limits of `UploadThreadState::flush_deadline`.
+async fn receive_events<U: Uploader>(
+    state: &mut UploadThreadState<U>,
+    rx: &mut mpsc::Receiver<JournalEvent>,
 ) {
-    conf.debug("upload thread started".to_string());
-    let uploader = CloudWatch::new(conf.clone()).await;
-    let mut state = UploadThreadState::new(uploader, conf.clone());
-    while let Some(record) = rx.recv().await {
-        state.push(record).await;
+    let mut last_received = Instant::now();
+    loop {
+        let received = match state.flush_deadline(last_received) {
+            Some(deadline) => {
+                let deadline = tokio::time::Instant::from_std(deadline);
+                tokio::time::timeout_at(deadline, rx.recv()).await
+            }
+            None => Ok(rx.recv().await),
+        };
+        match received {
+            Ok(Some(first)) => {
+                last_received = Instant::now();
+                for event in receive_burst(first, rx) {
+                    state.receive(event).await;
+                }
+                if state.is_latency_exceeded(Instant::now()) {
+                    state.flush().await;
+                }
+            }
+            Ok(None) => break,
+            Err(_) => state.flush().await,
+        }
     }
-    conf.debug(
-        "The receiver has been dropped and the event queue is drained"
-            .to_string(),
-    );
+}
 
-    // If we have "old" records, flush now
-    if let Some(first_timestamp) = state.first_timestamp {
-        if Utc::now().timestamp_millis() - first_timestamp > 1000 {
-            state.flush().await;
+/// Upload events from the queue until it is closed. `ready` is signalled
+/// once the uploader has connected.
+pub async fn upload_thread(
+    conf: Configuration,
+    rx: mpsc::Receiver<JournalEvent>,
+    ready: oneshot::Sender<()>,
+    metrics: Arc<Metrics>,
+) {
+    debug!("upload thread started");
+    match conf.backend {
+        Backend::CloudWatch => {
+            let uploader = CloudWatch::new(conf.clone(), metrics.clone()).await;
+            run_backend(uploader, conf, rx, ready, metrics).await;
+        }
+        Backend::Stdout => {
+            let uploader = StdoutUploader::new();
+            run_backend(uploader, conf, rx, ready, metrics).await;
+        }
+        Backend::File => {
+            let uploader = FileUploader::new(
+                conf.log_file_path.clone(),
+                conf.log_file_max_bytes,
+            );
+            run_backend(uploader, conf, rx, ready, metrics).await;
+        }
+        Backend::Kinesis => {
+            let uploader = KinesisUploader::new(
+                &conf.aws_config,
+                conf.kinesis_stream_name.clone(),
+                &conf.log_stream_name,
+                conf.max_upload_attempts,
+                metrics.clone(),
+            );
+            run_backend(uploader, conf, rx, ready, metrics).await;
         }
     }
 }
 
+async fn run_backend<U: Uploader>(
+    uploader: U,
+    conf: Configuration,
+    rx: mpsc::Receiver<JournalEvent>,
+    ready: oneshot::Sender<()>,
+    metrics: Arc<Metrics>,
+) {
+    let _ = ready.send(());
+    let mut state = UploadThreadState::new(uploader, conf, metrics);
+    run_upload_thread(&mut state, rx).await;
+}
+
+async fn run_upload_thread<U: Uploader>(
+    state: &mut UploadThreadState<U>,
+    mut rx: mpsc::Receiver<JournalEvent>,
+) {
+    receive_events(state, &mut rx).await;
+    debug!("The receiver has been dropped and the event queue is drained");
+
+    // Nothing else is coming, so upload whatever is still pending
+    state.flush().await;
+}
+
 #[cfg(test)]
 mod tests {
-    use aws_types::SdkConfig;
-
     use super::*;
+    use crate::configuration::JournalSource;
 
     fn create_conf() -> Configuration {
-        Configuration {
-            log_group_name: "myGroup".to_string(),
-            log_stream_name: "myStream".to_string(),
-            is_debug_mode_enabled: false,
-            aws_config: SdkConfig::builder()
-                .region(Region::from_static("us-test-2"))
+        Configuration::for_tests()
+    }
+
+    fn create_conf_with_cursor_path(name: &str) -> Configuration {
+        let mut conf = create_conf();
+        conf.cursor_path = std::env::temp_dir()
+            .join(format!("jtc-upload-{}-{}", std::process::id(), name))
+            .join("cursor");
+        conf
+    }
+
+    fn now() -> i64 {
+        Utc::now().timestamp_millis()
+    }
+
+    fn journal_event(
+        message: &str,
+        timestamp: i64,
+        cursor: Option<&str>,
+    ) -> JournalEvent {
+        JournalEvent::new(
+            InputLogEvent::builder()
+                .message(message.to_string())
+                .timestamp(timestamp)
                 .build(),
-        }
+            cursor.map(|c| c.to_string()),
+        )
     }
 
     struct MockUploader {
         events: Vec<InputLogEvent>,
+        batch_sizes: Vec<usize>,
+        /// The stream of each accepted batch, `None` for the default one.
+        streams: Vec<Option<String>>,
+        /// What uploads fail with, if they aren't accepted.
+        error: Option<UploadError>,
     }
 
     impl MockUploader {
         fn new() -> MockUploader {
-            MockUploader { events: Vec::new() }
+            MockUploader {
+                events: Vec::new(),
+                batch_sizes: Vec::new(),
+                streams: Vec::new(),
+                error: None,
+            }
         }
     }
 
@@ -327,22 +1687,42 @@ mod tests {
         ) -> Vec<Vec<InputLogEvent>> {
             super::do_group_events(events)
         }
-        async fn upload(&mut self, mut events: Vec<InputLogEvent>) {
+        async fn upload(
+            &mut self,
+            mut events: Vec<InputLogEvent>,
+        ) -> Result<(), Undelivered> {
+            if let Some(error) = self.error {
+                return Err(Undelivered::new(error, events));
+            }
+            self.batch_sizes.push(events.len());
+            self.streams.push(None);
             self.events.append(&mut events);
+            Ok(())
+        }
+        async fn upload_to_stream(
+            &mut self,
+            stream: &str,
+            events: Vec<InputLogEvent>,
+        ) -> Result<(), Undelivered> {
+            self.upload(events).await?;
+            *self.streams.last_mut().unwrap() = Some(stream.to_string());
+            Ok(())
         }
     }
 
     #[tokio::test]
     async fn test_manual_flush() {
         let uploader = MockUploader::new();
-        let mut state = UploadThreadState::new(uploader, create_conf());
+        let mut state =
+            UploadThreadState::new(uploader, create_conf(), Arc::default());
         state
-            .push(
+            .push(JournalEvent::new(
                 InputLogEvent::builder()
                     .message("myMessage".to_string())
                     .timestamp(Utc::now().timestamp_millis())
                     .build(),
-            )
+                None,
+            ))
             .await;
         assert_eq!(state.uploader.events.len(), 0);
         state.flush().await;
@@ -352,23 +1732,27 @@ mod tests {
     #[tokio::test]
     async fn test_out_of_order_events() {
         let uploader = MockUploader::new();
-        let mut state = UploadThreadState::new(uploader, create_conf());
+        let mut state =
+            UploadThreadState::new(uploader, create_conf(), Arc::default());
+        let now = Utc::now().timestamp_millis();
         state
-            .push(
+            .push(JournalEvent::new(
                 InputLogEvent::builder()
                     .message("myMessage1".to_string())
-                    .timestamp(2)
+                    .timestamp(now + 2)
                     .build(),
-            )
+                None,
+            ))
             .await;
         assert_eq!(state.uploader.events.len(), 0);
         state
-            .push(
+            .push(JournalEvent::new(
                 InputLogEvent::builder()
                     .message("myMessage2".to_string())
-                    .timestamp(1)
+                    .timestamp(now + 1)
                     .build(),
-            )
+                None,
+            ))
             .await;
         assert_eq!(state.uploader.events.len(), 1);
     }
@@ -376,27 +1760,91 @@ mod tests {
     #[tokio::test]
     async fn test_simultaneous_events() {
         let uploader = MockUploader::new();
-        let mut state = UploadThreadState::new(uploader, create_conf());
+        let mut state =
+            UploadThreadState::new(uploader, create_conf(), Arc::default());
+        let now = Utc::now().timestamp_millis();
         state
-            .push(
+            .push(JournalEvent::new(
                 InputLogEvent::builder()
                     .message("myMessage1".to_string())
-                    .timestamp(1)
+                    .timestamp(now + 1)
                     .build(),
-            )
+                None,
+            ))
             .await;
         assert_eq!(state.uploader.events.len(), 0);
         state
-            .push(
+            .push(JournalEvent::new(
                 InputLogEvent::builder()
                     .message("myMessage2".to_string())
-                    .timestamp(1)
+                    .timestamp(now + 1)
                     .build(),
-            )
+                None,
+            ))
             .await;
         assert_eq!(state.uploader.events.len(), 0);
     }
 
+    #[tokio::test]
+    async fn test_interleaved_sources_share_batches() {
+        let mut conf = create_conf();
+        conf.journal_sources = vec![JournalSource::System, JournalSource::User];
+        let mut state =
+            UploadThreadState::new(MockUploader::new(), conf, Arc::default());
+        let start = now();
+        // Each journal is in order, but one lags the other, so every other
+        // event goes back in time
+        for i in 0..10 {
+            state
+                .push(journal_event("system", start + 10 + i, None))
+                .await;
+            state.push(journal_event("user", start + i, None)).await;
+        }
+        assert!(state.uploader.batch_sizes.is_empty());
+        state.flush().await;
+        assert_eq!(state.uploader.batch_sizes, vec![20]);
+        // The upload puts the batch back in order
+        let groups = do_group_events(state.uploader.events.clone());
+        assert_eq!(groups.len(), 1);
+        let timestamps: Vec<i64> = groups[0]
+            .iter()
+            .map(|event| event.timestamp.unwrap())
+            .collect();
+        assert!(timestamps.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[tokio::test]
+    async fn test_out_of_order_within_a_millisecond() {
+        let uploader = MockUploader::new();
+        let mut state =
+            UploadThreadState::new(uploader, create_conf(), Arc::default());
+        let now = Utc::now().timestamp_nanos() / 1000;
+        for (message, offset) in [("ev1", 100), ("ev2", 300), ("ev3", 200)] {
+            let timestamp = now + offset;
+            let event = InputLogEvent::builder()
+                .message(message)
+                .timestamp(timestamp / 1000)
+                .build();
+            state
+                .push(
+                    JournalEvent::new(event, None)
+                        .with_timestamp_micros(timestamp),
+                )
+                .await;
+        }
+        // Only the event that went back in time started a new batch, even
+        // if all three fall in the same millisecond
+        assert_eq!(state.uploader.batch_sizes, vec![2]);
+        state.flush().await;
+        let messages: Vec<_> = state
+            .uploader
+            .events
+            .iter()
+            .map(|event| event.message.as_deref().unwrap_or_default())
+            .collect();
+        assert_eq!(messages, ["ev1", "ev2", "ev3"]);
+    }
+
     #[test]
     fn test_events_more_than_24h_apart() {
         let uploader = MockUploader::new();
@@ -404,25 +1852,20 @@ mod tests {
             - i64::try_from(Duration::from_secs(86400 * 2).as_millis())
                 .unwrap();
         let later = Utc::now().timestamp_millis();
-        let mut events = Vec::with_capacity(3);
-        events.push(
+        let events = vec![
             InputLogEvent::builder()
                 .message("ev1".to_string())
                 .timestamp(sooner)
                 .build(),
-        );
-        events.push(
             InputLogEvent::builder()
                 .message("ev2".to_string())
                 .timestamp(sooner + 42)
                 .build(),
-        );
-        events.push(
             InputLogEvent::builder()
                 .message("ev3".to_string())
                 .timestamp(later)
                 .build(),
-        );
+        ];
         assert_eq!(uploader.group_events(events).len(), 2);
     }
 
@@ -432,26 +1875,846 @@ mod tests {
         let interval =
             i64::try_from(Duration::from_secs(17 * 60 * 60).as_millis())
                 .unwrap();
-        let mut events = Vec::with_capacity(3);
         let now = Utc::now().timestamp_millis();
-        events.push(
+        let events = vec![
             InputLogEvent::builder()
                 .message("ev1".to_string())
                 .timestamp(now - (2 * interval))
                 .build(),
-        );
-        events.push(
             InputLogEvent::builder()
                 .message("ev2".to_string())
                 .timestamp(now - interval)
                 .build(),
-        );
-        events.push(
             InputLogEvent::builder()
                 .message("ev3".to_string())
                 .timestamp(now)
                 .build(),
-        );
+        ];
         assert_eq!(uploader.group_events(events).len(), 3);
     }
+
+    #[test]
+    fn test_parse_expected_sequence_token() {
+        let message = "The given sequenceToken is invalid. The next expected \
+                       sequenceToken is: \
+                       49039859288591433390965178606229454565562970314442077602";
+        assert_eq!(
+            parse_expected_sequence_token(message),
+            Some(Some(
+                "49039859288591433390965178606229454565562970314442077602"
+                    .to_string()
+            ))
+        );
+        assert_eq!(
+            parse_expected_sequence_token(
+                "The given sequenceToken is invalid. The next expected \
+                 sequenceToken is: null"
+            ),
+            Some(None)
+        );
+        assert_eq!(
+            parse_expected_sequence_token(
+                "The given batch of log events has already been accepted. \
+                 The next batch can be sent with sequenceToken: \
+                 49039859288591433390965178606229454565562970314442077603"
+            ),
+            Some(Some(
+                "49039859288591433390965178606229454565562970314442077603"
+                    .to_string()
+            ))
+        );
+        assert_eq!(
+            parse_expected_sequence_token("The given sequenceToken is invalid"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_timeouts_are_retryable() {
+        let err: SdkError<PutLogEventsError> =
+            SdkError::TimeoutError("request timed out".into());
+        assert!(is_retryable(&err));
+        assert_eq!(UploadError::from_sdk_error(&err), UploadError::Network);
+        let err: SdkError<PutLogEventsError> =
+            SdkError::ConstructionFailure("no region".into());
+        assert_eq!(UploadError::from_sdk_error(&err), UploadError::Other);
+        assert!(!is_retryable(&err));
+    }
+
+    #[test]
+    fn test_upload_error_from_code() {
+        for (code, error) in [
+            ("ThrottlingException", UploadError::Throttled),
+            ("ServiceUnavailableException", UploadError::Throttled),
+            ("InvalidSequenceTokenException", UploadError::InvalidToken),
+            ("ResourceNotFoundException", UploadError::ResourceNotFound),
+            ("InternalFailure", UploadError::Network),
+            ("AccessDeniedException", UploadError::Other),
+        ] {
+            assert_eq!(UploadError::from_code(code), error, "{}", code);
+        }
+        assert!(UploadError::Throttled.is_retryable());
+        assert!(!UploadError::ResourceNotFound.is_retryable());
+    }
+
+    #[test]
+    fn test_groups_limited_to_batch_window() {
+        let uploader = MockUploader::new();
+        let window = BATCH_WINDOW.as_millis() as i64;
+        let events = [0, window, window + 1]
+            .iter()
+            .map(|&timestamp| {
+                InputLogEvent::builder()
+                    .message(format!("ev{}", timestamp))
+                    .timestamp(timestamp)
+                    .build()
+            })
+            .collect();
+        let groups = uploader.group_events(events);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].len(), 2);
+        assert_eq!(groups[1][0].timestamp, Some(window + 1));
+    }
+
+    #[test]
+    fn test_groups_keep_order_within_a_millisecond() {
+        let uploader = MockUploader::new();
+        let events =
+            [(2, "ev1"), (1, "ev2"), (2, "ev3"), (1, "ev4"), (2, "ev5")]
+                .iter()
+                .map(|&(timestamp, message)| {
+                    InputLogEvent::builder()
+                        .message(message)
+                        .timestamp(timestamp)
+                        .build()
+                })
+                .collect();
+        let groups = uploader.group_events(events);
+        let messages: Vec<_> = groups[0]
+            .iter()
+            .map(|event| event.message.as_deref().unwrap_or_default())
+            .collect();
+        assert_eq!(messages, ["ev2", "ev4", "ev1", "ev3", "ev5"]);
+    }
+
+    #[test]
+    fn test_groups_limited_to_max_batch_events() {
+        let uploader = MockUploader::new();
+        let events = (0..MAX_BATCH_EVENTS + 1)
+            .map(|i| {
+                InputLogEvent::builder()
+                    .message(format!("ev{}", i))
+                    .timestamp(1)
+                    .build()
+            })
+            .collect();
+        let groups = uploader.group_events(events);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].len(), MAX_BATCH_EVENTS);
+        assert_eq!(groups[1].len(), 1);
+    }
+
+    #[test]
+    fn test_groups_limited_to_max_batch_bytes() {
+        let uploader = MockUploader::new();
+        // Five events of 250 KB don't fit in one 1 MB batch
+        let events = (0..5)
+            .map(|_| {
+                InputLogEvent::builder()
+                    .message("x".repeat(250 * 1024))
+                    .timestamp(1)
+                    .build()
+            })
+            .collect();
+        let groups = uploader.group_events(events);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].len(), 4);
+        for group in groups.iter() {
+            let num_bytes: usize = group.iter().map(get_event_num_bytes).sum();
+            assert!(num_bytes <= MAX_BATCH_BYTES);
+        }
+    }
+
+    #[test]
+    fn test_check_timestamp() {
+        let now = 1_700_000_000_000;
+        let max_age = MAX_EVENT_AGE.as_millis() as i64;
+        let max_future = MAX_EVENT_FUTURE.as_millis() as i64;
+        assert_eq!(check_timestamp(now, now), TimestampCheck::Accept);
+        assert_eq!(check_timestamp(now - max_age, now), TimestampCheck::Accept);
+        assert_eq!(
+            check_timestamp(now - max_age - 1, now),
+            TimestampCheck::Drop
+        );
+        assert_eq!(
+            check_timestamp(now + max_future, now),
+            TimestampCheck::Accept
+        );
+        assert_eq!(
+            check_timestamp(now + max_future + 1, now),
+            TimestampCheck::Clamp
+        );
+    }
+
+    #[tokio::test]
+    async fn test_events_outside_time_window() {
+        let now = Utc::now().timestamp_millis();
+        let day = 24 * 60 * 60 * 1000;
+        let mut state = UploadThreadState::new(
+            MockUploader::new(),
+            create_conf(),
+            Arc::default(),
+        );
+        state
+            .push(journal_event("too old", now - 15 * day, None))
+            .await;
+        state.push(journal_event("recent", now, None)).await;
+        state.push(journal_event("future", now + day, None)).await;
+        state.flush().await;
+        let events = &state.uploader.events;
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].message.as_deref(), Some("recent"));
+        assert!(events[1].timestamp.unwrap() < now + day);
+    }
+
+    #[test]
+    fn test_get_event_num_bytes() {
+        let event = InputLogEvent::builder()
+            .message("x".repeat(100))
+            .timestamp(1)
+            .build();
+        assert_eq!(get_event_num_bytes(&event), 126);
+        let event = InputLogEvent::builder().message("é").timestamp(1).build();
+        assert_eq!(get_event_num_bytes(&event), 2 + EVENT_OVERHEAD_BYTES);
+        let event = InputLogEvent::builder().timestamp(1).build();
+        assert_eq!(get_event_num_bytes(&event), EVENT_OVERHEAD_BYTES);
+        assert_eq!(MAX_BATCH_BYTES, 1_048_576);
+    }
+
+    #[test]
+    fn test_small_event_is_not_split() {
+        let event = InputLogEvent::builder()
+            .message("small".to_string())
+            .timestamp(1)
+            .build();
+        let parts = split_oversized_event(event);
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].message.as_deref(), Some("small"));
+    }
+
+    #[test]
+    fn test_split_oversized_event() {
+        let message = "x".repeat(600 * 1024);
+        let event = InputLogEvent::builder()
+            .message(message.clone())
+            .timestamp(42)
+            .build();
+        let parts = split_oversized_event(event);
+        assert_eq!(parts.len(), 3);
+        let mut joined = String::new();
+        for (i, part) in parts.iter().enumerate() {
+            assert!(get_event_num_bytes(part) <= MAX_EVENT_BYTES);
+            assert_eq!(part.timestamp, Some(42));
+            let suffix = format!(" (part {}/3)", i + 1);
+            let text = part.message.as_deref().unwrap();
+            assert!(text.ends_with(&suffix));
+            joined.push_str(&text[..text.len() - suffix.len()]);
+        }
+        assert_eq!(joined, message);
+        assert_eq!(get_event_num_bytes(&parts[0]), MAX_EVENT_BYTES);
+    }
+
+    #[test]
+    fn test_split_respects_char_boundaries() {
+        // Three byte characters never line up with the part size
+        let message = "\u{20ac}".repeat(200 * 1024);
+        let event = InputLogEvent::builder()
+            .message(message.clone())
+            .timestamp(1)
+            .build();
+        let parts = split_oversized_event(event);
+        assert_eq!(parts.len(), 3);
+        let joined: String = parts
+            .iter()
+            .map(|part| {
+                let text = part.message.as_deref().unwrap();
+                text[..text.rfind(" (part ").unwrap()].to_string()
+            })
+            .collect();
+        assert_eq!(joined, message);
+        for part in parts.iter() {
+            assert!(get_event_num_bytes(part) <= MAX_EVENT_BYTES);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oversized_event_is_pushed_in_parts() {
+        let mut state = UploadThreadState::new(
+            MockUploader::new(),
+            create_conf(),
+            Arc::default(),
+        );
+        state
+            .push(journal_event(&"x".repeat(300 * 1024), now(), Some("c1")))
+            .await;
+        state.flush().await;
+        assert_eq!(state.uploader.events.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_idle_flush() {
+        let mut conf = create_conf();
+        conf.flush_interval = Duration::from_millis(10);
+        let mut state =
+            UploadThreadState::new(MockUploader::new(), conf, Arc::default());
+        let (tx, mut rx) = mpsc::channel(8);
+        let sender = tokio::spawn(async move {
+            tx.send(journal_event("ev1", now(), None)).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        });
+        receive_events(&mut state, &mut rx).await;
+        sender.await.unwrap();
+        assert_eq!(state.uploader.events.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_batch_linger() {
+        let mut conf = create_conf();
+        conf.flush_interval = Duration::from_millis(10);
+        conf.batch_linger = Duration::from_millis(300);
+        let mut state =
+            UploadThreadState::new(MockUploader::new(), conf, Arc::default());
+        let (tx, mut rx) = mpsc::channel(8);
+        let sender = tokio::spawn(async move {
+            tx.send(journal_event("ev1", now(), None)).await.unwrap();
+            // Idle for longer than the flush interval, but within the linger
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            tx.send(journal_event("ev2", now(), None)).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        });
+        receive_events(&mut state, &mut rx).await;
+        sender.await.unwrap();
+        assert_eq!(state.uploader.batch_sizes, vec![2]);
+    }
+
+    #[tokio::test]
+    async fn test_max_batch_latency() {
+        let mut conf = create_conf();
+        conf.flush_interval = Duration::from_secs(60);
+        conf.max_batch_latency = Some(Duration::from_millis(100));
+        let mut state =
+            UploadThreadState::new(MockUploader::new(), conf, Arc::default());
+        let (tx, mut rx) = mpsc::channel(8);
+        let sender = tokio::spawn(async move {
+            // Events keep arriving, so the idle flush never happens
+            for i in 0..5 {
+                let message = format!("ev{}", i);
+                tx.send(journal_event(&message, now(), None)).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(40)).await;
+            }
+        });
+        receive_events(&mut state, &mut rx).await;
+        sender.await.unwrap();
+        assert!(!state.uploader.batch_sizes.is_empty());
+        assert!(state.uploader.events.len() < 5);
+    }
+
+    #[test]
+    fn test_flush_deadline() {
+        let mut conf = create_conf();
+        conf.flush_interval = Duration::from_secs(5);
+        let mut state =
+            UploadThreadState::new(MockUploader::new(), conf, Arc::default());
+        let start = Instant::now();
+        assert_eq!(state.flush_deadline(start), None);
+        state.pending_since = Some(start);
+        let later = start + Duration::from_secs(2);
+        assert_eq!(
+            state.flush_deadline(later),
+            Some(later + Duration::from_secs(5))
+        );
+        state.conf.batch_linger = Duration::from_secs(30);
+        assert_eq!(
+            state.flush_deadline(later),
+            Some(start + Duration::from_secs(30))
+        );
+        // The latency limit wins over the linger
+        state.conf.max_batch_latency = Some(Duration::from_secs(10));
+        assert_eq!(
+            state.flush_deadline(later),
+            Some(start + Duration::from_secs(10))
+        );
+        assert!(!state.is_latency_exceeded(later));
+        assert!(state.is_latency_exceeded(start + Duration::from_secs(10)));
+    }
+
+    #[tokio::test]
+    async fn test_receive_burst() {
+        let (tx, mut rx) = mpsc::channel(MAX_RECEIVE_BURST + 10);
+        for i in 0..MAX_RECEIVE_BURST + 10 {
+            let message = format!("ev{}", i);
+            tx.send(journal_event(&message, now(), None)).await.unwrap();
+        }
+        let first = rx.recv().await.unwrap();
+        let burst = receive_burst(first, &mut rx);
+        assert_eq!(burst.len(), MAX_RECEIVE_BURST);
+        assert_eq!(burst[0].event.message.as_deref(), Some("ev0"));
+        let first = rx.recv().await.unwrap();
+        assert_eq!(receive_burst(first, &mut rx).len(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_receive_drops_over_buffer_limit() {
+        let mut conf = create_conf();
+        // Room for two events of 3 bytes and the overhead
+        conf.max_buffered_bytes = 2 * (3 + EVENT_OVERHEAD_BYTES as u64) + 2;
+        let metrics = Arc::new(Metrics::default());
+        let mut state =
+            UploadThreadState::new(MockUploader::new(), conf, metrics.clone());
+        // Three events queued, so the first one received is over the limit
+        metrics
+            .queued_bytes
+            .store(3 * (3 + EVENT_OVERHEAD_BYTES as u64), Ordering::Relaxed);
+        for message in ["ev1", "ev2", "ev3"] {
+            state.receive(journal_event(message, now(), None)).await;
+        }
+        state.flush().await;
+        let messages: Vec<_> = state
+            .uploader
+            .events
+            .iter()
+            .filter_map(|event| event.message.as_deref())
+            .collect();
+        assert_eq!(messages, ["ev2", "ev3"]);
+        assert_eq!(metrics.events_dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.queued_bytes.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_events_routed_to_unit_streams() {
+        let mut state = UploadThreadState::new(
+            MockUploader::new(),
+            create_conf(),
+            Arc::default(),
+        );
+        let start = now();
+        let units = [Some("a.service"), Some("b.service"), None];
+        for (i, unit) in units.iter().cycle().take(6).enumerate() {
+            let event = journal_event("ev", start + i as i64, None)
+                .with_stream(unit.map(|unit| format!("host/{}", unit)));
+            state.push(event).await;
+        }
+        state.flush().await;
+        assert_eq!(state.uploader.batch_sizes, vec![2, 2, 2]);
+        assert_eq!(
+            state.uploader.streams,
+            vec![
+                None,
+                Some("host/a.service".to_string()),
+                Some("host/b.service".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cursor_saved_after_upload() {
+        let conf = create_conf_with_cursor_path("saved");
+        let store = CursorStore::new(conf.cursor_path.clone());
+        let mut state =
+            UploadThreadState::new(MockUploader::new(), conf, Arc::default());
+        state.push(journal_event("ev1", now(), Some("c1"))).await;
+        state.push(journal_event("ev2", now(), Some("c2"))).await;
+        assert_eq!(store.load(), None);
+        state.flush().await;
+        assert_eq!(store.load(), Some("c2".to_string()));
+        std::fs::remove_dir_all(state.conf.cursor_path.parent().unwrap())
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replayed_events_are_skipped() {
+        let conf = create_conf_with_cursor_path("replay");
+        let cursor =
+            |seqnum: u64| format!("s=abc;i={:x};b=1;m=1;t=1;x=1", seqnum);
+        CursorStore::new(conf.cursor_path.clone())
+            .save(&cursor(2))
+            .unwrap();
+        let mut state =
+            UploadThreadState::new(MockUploader::new(), conf, Arc::default());
+        // A previous run delivered up to the second event, and the journal
+        // is read again from the start
+        for seqnum in 1..=3 {
+            let message = format!("ev{}", seqnum);
+            let event = journal_event(&message, now(), Some(&cursor(seqnum)));
+            state.push(event).await;
+        }
+        state.flush().await;
+        // Reading the same events again in this run skips all of them
+        for seqnum in 2..=4 {
+            let message = format!("ev{}", seqnum);
+            let event = journal_event(&message, now(), Some(&cursor(seqnum)));
+            state.push(event).await;
+        }
+        state.flush().await;
+        let messages: Vec<&str> = state
+            .uploader
+            .events
+            .iter()
+            .map(|e| e.message.as_deref().unwrap())
+            .collect();
+        assert_eq!(messages, vec!["ev3", "ev4"]);
+        std::fs::remove_dir_all(state.conf.cursor_path.parent().unwrap())
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cursor_not_saved_after_failed_upload() {
+        let conf = create_conf_with_cursor_path("failed");
+        let store = CursorStore::new(conf.cursor_path.clone());
+        let mut uploader = MockUploader::new();
+        uploader.error = Some(UploadError::Network);
+        let mut state = UploadThreadState::new(uploader, conf, Arc::default());
+        state.push(journal_event("ev1", now(), Some("c1"))).await;
+        state.flush().await;
+        assert_eq!(store.load(), None);
+    }
+
+    #[tokio::test]
+    async fn test_failed_events_are_spooled_and_retried_first() {
+        let mut conf = create_conf_with_cursor_path("spool");
+        let spool_dir = conf.cursor_path.with_file_name("spool");
+        conf.spool_dir = Some(spool_dir.clone());
+        let store = CursorStore::new(conf.cursor_path.clone());
+        let mut uploader = MockUploader::new();
+        uploader.error = Some(UploadError::Network);
+        let mut state = UploadThreadState::new(uploader, conf, Arc::default());
+        let timestamp = now();
+        state
+            .push(journal_event("ev1", timestamp, Some("c1")))
+            .await;
+        state.flush().await;
+        // The spooled events are safe on disk, so the cursor moves on
+        assert_eq!(store.load(), Some("c1".to_string()));
+        assert!(state.uploader.events.is_empty());
+
+        state.uploader.error = None;
+        state
+            .push(journal_event("ev2", timestamp + 1, Some("c2")))
+            .await;
+        state.flush().await;
+        let messages: Vec<&str> = state
+            .uploader
+            .events
+            .iter()
+            .map(|e| e.message.as_deref().unwrap())
+            .collect();
+        assert_eq!(messages, vec!["ev1", "ev2"]);
+        assert!(state.spool.as_ref().unwrap().is_empty());
+        std::fs::remove_dir_all(spool_dir.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_spooled_batch_stays_on_disk() {
+        let mut conf = create_conf_with_cursor_path("spool_kept");
+        let spool_dir = conf.cursor_path.with_file_name("spool");
+        conf.spool_dir = Some(spool_dir.clone());
+        let mut uploader = MockUploader::new();
+        uploader.error = Some(UploadError::Network);
+        let mut state = UploadThreadState::new(uploader, conf, Arc::default());
+        let timestamp = now();
+        state.push(journal_event("ev1", timestamp, None)).await;
+        state.flush().await;
+        let spooled = state.spool.as_ref().unwrap().files().unwrap();
+        assert_eq!(spooled.len(), 1);
+
+        // The retry fails too, which leaves the file where it was and
+        // spools the new events after it
+        state.push(journal_event("ev2", timestamp + 1, None)).await;
+        state.flush().await;
+        let files = state.spool.as_ref().unwrap().files().unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0], spooled[0]);
+
+        state.uploader.error = None;
+        state.push(journal_event("ev3", timestamp + 2, None)).await;
+        state.flush().await;
+        let messages: Vec<&str> = state
+            .uploader
+            .events
+            .iter()
+            .map(|e| e.message.as_deref().unwrap())
+            .collect();
+        assert_eq!(messages, vec!["ev1", "ev2", "ev3"]);
+        assert!(state.spool.as_ref().unwrap().is_empty());
+        std::fs::remove_dir_all(spool_dir.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_batches_hold_up_to_max_events() {
+        let mut state = UploadThreadState::new(
+            MockUploader::new(),
+            create_conf(),
+            Arc::default(),
+        );
+        let start = now();
+        for i in 0..250 {
+            state.push(journal_event("ev", start + i, None)).await;
+        }
+        assert_eq!(state.uploader.batch_sizes, vec![100, 100]);
+        assert_eq!(state.num_pending_events, 50);
+        state.flush().await;
+        assert_eq!(state.uploader.batch_sizes, vec![100, 100, 50]);
+    }
+
+    #[tokio::test]
+    async fn test_batch_of_one_flushes_every_event() {
+        let mut conf = create_conf();
+        conf.max_events_per_batch = 1;
+        let mut state =
+            UploadThreadState::new(MockUploader::new(), conf, Arc::default());
+        let start = now();
+        for i in 0..3 {
+            state.push(journal_event("ev", start + i, None)).await;
+        }
+        assert_eq!(state.uploader.batch_sizes, vec![1, 1, 1]);
+        assert!(state.events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pending_events_flushed_on_shutdown() {
+        let mut state = UploadThreadState::new(
+            MockUploader::new(),
+            create_conf(),
+            Arc::default(),
+        );
+        let (tx, rx) = mpsc::channel(10);
+        let timestamp = now();
+        tx.send(journal_event("ev1", timestamp, None))
+            .await
+            .unwrap();
+        tx.send(journal_event("ev2", timestamp, None))
+            .await
+            .unwrap();
+        drop(tx);
+        run_upload_thread(&mut state, rx).await;
+        assert_eq!(state.uploader.events.len(), 2);
+        assert!(state.events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_upload_metrics() {
+        let metrics = Arc::new(Metrics::default());
+        let mut state = UploadThreadState::new(
+            MockUploader::new(),
+            create_conf(),
+            metrics.clone(),
+        );
+        let day = 24 * 60 * 60 * 1000;
+        state
+            .push(journal_event("too old", now() - 15 * day, None))
+            .await;
+        state.push(journal_event("ev1", now(), None)).await;
+        state.flush().await;
+        state.uploader.error = Some(UploadError::Throttled);
+        state.push(journal_event("ev2", now(), None)).await;
+        state.flush().await;
+
+        assert_eq!(metrics.events_forwarded.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.batches_uploaded.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.upload_failures.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.upload_errors(UploadError::Throttled), 1);
+        // The old event and the undelivered one, since there's no spool
+        assert_eq!(metrics.events_dropped.load(Ordering::Relaxed), 2);
+        assert!(metrics.last_upload_timestamp.load(Ordering::Relaxed) > 0);
+        let lag = metrics.upload_lag_millis.load(Ordering::Relaxed);
+        assert!((0..60_000).contains(&lag));
+    }
+
+    /// A configuration that sends requests to the given endpoint with
+    /// static test credentials.
+    fn create_endpoint_conf(endpoint: &str) -> Configuration {
+        use aws_sdk_cloudwatchlogs::{Credentials, Endpoint, Region};
+        use aws_types::credentials::SharedCredentialsProvider;
+        use aws_types::SdkConfig;
+
+        let mut conf = create_conf();
+        conf.aws_config = SdkConfig::builder()
+            .region(Region::from_static("us-east-1"))
+            .endpoint_resolver(Endpoint::immutable(endpoint.parse().unwrap()))
+            .credentials_provider(SharedCredentialsProvider::new(
+                Credentials::new("test", "test", None, None, "test"),
+            ))
+            .build();
+        conf
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_connection_gives_up() {
+        // Nothing listens on the discard port, so every attempt fails
+        let mut conf = create_endpoint_conf("http://127.0.0.1:9");
+        conf.connect_timeout = Duration::from_millis(200);
+        conf.connect_backoff_base = Duration::from_millis(10);
+        let client = Client::new(&conf.aws_config);
+        let started = Instant::now();
+        wait_for_connection(&client, &conf).await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_find_log_stream() {
+        let log_streams: Vec<LogStream> = ["web-1", "web-1/nginx", "web-10"]
+            .iter()
+            .map(|name| {
+                LogStream::builder()
+                    .log_stream_name(*name)
+                    .upload_sequence_token(format!("{}-token", name))
+                    .build()
+            })
+            .collect();
+        let found = find_log_stream(log_streams.clone(), "web-10").unwrap();
+        assert_eq!(
+            found.upload_sequence_token.as_deref(),
+            Some("web-10-token")
+        );
+        let found = find_log_stream(log_streams[1..].to_vec(), "web-1");
+        assert!(found.is_none());
+        assert!(find_log_stream(log_streams, "web").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_log_stream_failure_is_an_error() {
+        // A failed lookup must not look like a missing stream, which would
+        // be created
+        let mut conf = create_endpoint_conf("http://127.0.0.1:9");
+        conf.connect_timeout = Duration::from_millis(10);
+        conf.create_log_group = false;
+        conf.max_upload_attempts = 2;
+        let cw = CloudWatch::new(conf, Arc::new(Metrics::default())).await;
+        assert!(cw.get_log_stream("myStream").await.is_err());
+    }
+
+    /// Runs the CloudWatch uploader against LocalStack when
+    /// `LOCALSTACK_ENDPOINT` is set, for example to
+    /// `http://localhost:4566`, and is skipped otherwise.
+    #[tokio::test]
+    async fn test_upload_to_localstack() {
+        let endpoint = match std::env::var("LOCALSTACK_ENDPOINT") {
+            Ok(endpoint) => endpoint,
+            Err(_) => return,
+        };
+        let mut conf = create_endpoint_conf(&endpoint);
+        conf.log_stream_name = format!("test-{}", now());
+        conf.use_sequence_tokens = true;
+        let mut cw = CloudWatch::new(conf, Arc::new(Metrics::default())).await;
+
+        // The second upload only succeeds if the sequence token returned by
+        // the first one was kept
+        for message in ["ev1", "ev2"] {
+            let event = InputLogEvent::builder()
+                .message(message)
+                .timestamp(now())
+                .build();
+            assert!(cw.upload(vec![event]).await.is_ok());
+        }
+        let events = cw
+            .client
+            .get_log_events()
+            .log_group_name(cw.conf.log_group_name.as_str())
+            .log_stream_name(cw.conf.log_stream_name.as_str())
+            .send()
+            .await
+            .unwrap()
+            .events
+            .unwrap_or_default();
+        let messages: Vec<_> =
+            events.iter().filter_map(|e| e.message.as_deref()).collect();
+        assert_eq!(messages, ["ev1", "ev2"]);
+    }
+
+    /// Like `test_upload_to_localstack`, uploading to two streams at once.
+    #[tokio::test]
+    async fn test_concurrent_upload_to_localstack() {
+        let endpoint = match std::env::var("LOCALSTACK_ENDPOINT") {
+            Ok(endpoint) => endpoint,
+            Err(_) => return,
+        };
+        let mut conf = create_endpoint_conf(&endpoint);
+        conf.log_stream_name = format!("test-{}", now());
+        conf.max_concurrent_uploads = 2;
+        let mut cw = CloudWatch::new(conf, Arc::new(Metrics::default())).await;
+        let other_stream = format!("{}/other", cw.conf.log_stream_name);
+
+        // Each stream keeps its own sequence token between the uploads
+        for message in ["ev1", "ev2"] {
+            let batches = [None, Some(other_stream.clone())]
+                .into_iter()
+                .map(|stream| {
+                    let event = InputLogEvent::builder()
+                        .message(message)
+                        .timestamp(now())
+                        .build();
+                    (stream, vec![event])
+                })
+                .collect();
+            let results = cw.upload_batches(batches).await;
+            assert!(results.iter().all(Result::is_ok));
+        }
+        for stream in [cw.conf.log_stream_name.clone(), other_stream] {
+            let events = cw
+                .client
+                .get_log_events()
+                .log_group_name(cw.conf.log_group_name.as_str())
+                .log_stream_name(stream)
+                .send()
+                .await
+                .unwrap()
+                .events
+                .unwrap_or_default();
+            let messages: Vec<_> =
+                events.iter().filter_map(|e| e.message.as_deref()).collect();
+            assert_eq!(messages, ["ev1", "ev2"]);
+        }
+    }
+
+    /// Batches for the default stream, named explicitly and by `None`,
+    /// share its sequence token instead of racing for it.
+    #[tokio::test]
+    async fn test_same_stream_batches_to_localstack() {
+        let endpoint = match std::env::var("LOCALSTACK_ENDPOINT") {
+            Ok(endpoint) => endpoint,
+            Err(_) => return,
+        };
+        let mut conf = create_endpoint_conf(&endpoint);
+        conf.log_stream_name = format!("test-{}", now());
+        conf.max_concurrent_uploads = 2;
+        let mut cw = CloudWatch::new(conf, Arc::new(Metrics::default())).await;
+        let stream = cw.conf.log_stream_name.clone();
+
+        for message in ["ev1", "ev2"] {
+            let batches = [None, Some(stream.clone())]
+                .into_iter()
+                .map(|stream| {
+                    let event = InputLogEvent::builder()
+                        .message(message)
+                        .timestamp(now())
+                        .build();
+                    (stream, vec![event])
+                })
+                .collect();
+            let results = cw.upload_batches(batches).await;
+            assert_eq!(results.len(), 2);
+            assert!(results.iter().all(Result::is_ok));
+        }
+        let events = cw
+            .client
+            .get_log_events()
+            .log_group_name(cw.conf.log_group_name.as_str())
+            .log_stream_name(stream)
+            .send()
+            .await
+            .unwrap()
+            .events
+            .unwrap_or_default();
+        assert_eq!(events.len(), 4);
+    }
 }
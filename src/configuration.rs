@@ -1,53 +1,1621 @@
+use std::collections::BTreeMap;
 use std::env::var;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use crate::ec2;
+use crate::ecs;
+use crate::format::{self, JsonFormat, MessageFormat};
+use aws_config::default_provider::credentials::DefaultCredentialsChain;
 use aws_config::meta::region::RegionProviderChain;
+use aws_config::sts::AssumeRoleProvider;
+use aws_sdk_cloudwatchlogs::error::DescribeLogStreamsError;
+use aws_sdk_cloudwatchlogs::types::SdkError;
+use aws_sdk_cloudwatchlogs::Endpoint;
+use aws_smithy_types::retry::RetryConfig;
+use aws_smithy_types::timeout;
+use aws_smithy_types::tristate::TriState;
+use aws_types::credentials::{
+    CredentialsError, ProvideCredentials, SharedCredentialsProvider,
+};
 use aws_types::region::Region;
 use aws_types::SdkConfig;
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use systemd::login::{self, UnitType};
+use tracing::{debug, info, warn};
+
+/// Where in the journal to start reading from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StartPosition {
+    /// Only read entries written after startup.
+    Tail,
+    /// Read the whole journal from the oldest entry.
+    Head,
+    /// Resume after the last uploaded entry, falling back to the tail if
+    /// there is no saved cursor.
+    Cursor,
+    /// Read the entries from this time on, in microseconds since the Unix
+    /// epoch like the journal's timestamps.
+    Since(u64),
+}
+
+impl StartPosition {
+    fn parse(value: &str) -> Option<StartPosition> {
+        match value.trim().to_lowercase().as_str() {
+            "tail" => Some(StartPosition::Tail),
+            "head" => Some(StartPosition::Head),
+            "cursor" => Some(StartPosition::Cursor),
+            _ => None,
+        }
+    }
+}
+
+/// How each uploaded message is encoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageEncoding {
+    /// Plain text built from the message format.
+    Text,
+    /// A JSON object with a selection of the record's fields.
+    Json,
+}
+
+impl MessageEncoding {
+    fn parse(value: &str) -> Option<MessageEncoding> {
+        match value.trim().to_lowercase().as_str() {
+            "text" => Some(MessageEncoding::Text),
+            "json" => Some(MessageEncoding::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Where events are sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// Upload to CloudWatch Logs.
+    CloudWatch,
+    /// Print to stdout.
+    Stdout,
+    /// Append to a local file.
+    File,
+    /// Put records to a Kinesis data stream.
+    Kinesis,
+}
+
+impl Backend {
+    fn parse(value: &str) -> Option<Backend> {
+        match value.trim().to_lowercase().as_str() {
+            "cloudwatch" => Some(Backend::CloudWatch),
+            "stdout" => Some(Backend::Stdout),
+            "file" => Some(Backend::File),
+            "kinesis" => Some(Backend::Kinesis),
+            _ => None,
+        }
+    }
+}
+
+/// One of the journals in `JOURNAL_SOURCES`, each read by its own reader.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JournalSource {
+    /// The journal of system services and the kernel.
+    System,
+    /// The journal of the user the service runs as.
+    User,
+    /// The journal files in a directory, such as those of a container.
+    Directory(PathBuf),
+}
+
+impl JournalSource {
+    fn parse(value: &str) -> Option<JournalSource> {
+        let value = value.trim();
+        if value.starts_with('/') {
+            return Some(JournalSource::Directory(PathBuf::from(value)));
+        }
+        match value.to_lowercase().as_str() {
+            "system" => Some(JournalSource::System),
+            "user" => Some(JournalSource::User),
+            _ => None,
+        }
+    }
+}
+
+/// Which records are forwarded, by where they came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    All,
+    /// Only kernel messages.
+    Kernel,
+    /// Only messages from userspace programs.
+    User,
+}
+
+impl Source {
+    fn parse(value: &str) -> Option<Source> {
+        match value.trim().to_lowercase().as_str() {
+            "all" => Some(Source::All),
+            "kernel" => Some(Source::Kernel),
+            "user" => Some(Source::User),
+            _ => None,
+        }
+    }
+}
+
+/// What to do with syslog style "message repeated N times" summaries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RepeatMode {
+    /// Forward the summary as it is.
+    Keep,
+    /// Forward the original message once per repeat, up to a limit.
+    Expand,
+    /// Forward the original message once, with the repeat count.
+    Annotate,
+}
+
+impl RepeatMode {
+    fn parse(value: &str) -> Option<RepeatMode> {
+        match value.trim().to_lowercase().as_str() {
+            "keep" => Some(RepeatMode::Keep),
+            "expand" => Some(RepeatMode::Expand),
+            "annotate" => Some(RepeatMode::Annotate),
+            _ => None,
+        }
+    }
+}
+
+/// What to do with records that have no `MESSAGE` field.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MissingMessage {
+    /// Drop the record.
+    Drop,
+    /// Use the value of another field as the message.
+    Field(String),
+    /// Use all of the record's fields, encoded as JSON.
+    Record,
+}
+
+impl MissingMessage {
+    fn parse(value: &str) -> Option<MissingMessage> {
+        let value = value.trim();
+        if let Some(field) = value.strip_prefix("field:") {
+            return match field.trim() {
+                "" => None,
+                field => Some(MissingMessage::Field(field.to_string())),
+            };
+        }
+        match value.to_lowercase().as_str() {
+            "drop" => Some(MissingMessage::Drop),
+            "record" => Some(MissingMessage::Record),
+            _ => None,
+        }
+    }
+}
+
+/// The retention periods, in days, that CloudWatch Logs accepts.
+///
+/// Reference:
+/// docs.aws.amazon.com/AmazonCloudWatchLogs/latest/APIReference/API_PutRetentionPolicy.html
+const RETENTION_DAYS: &[i32] = &[
+    1, 3, 5, 7, 14, 30, 60, 90, 120, 150, 180, 365, 400, 545, 731, 1096, 1827,
+    2192, 2557, 2922, 3288, 3653,
+];
 
 #[derive(Clone, Debug)]
 pub struct Configuration {
     pub log_group_name: String,
     pub log_stream_name: String,
-    pub is_debug_mode_enabled: bool,
+    /// How many events are collected before they are uploaded.
+    pub max_events_per_batch: usize,
     pub aws_config: SdkConfig,
+    /// The configuration for `LOG_REGION_FALLBACK`, if it is set.
+    pub fallback_aws_config: Option<SdkConfig>,
+    pub cursor_path: PathBuf,
+    pub journal_directory: Option<PathBuf>,
+    pub journal_namespace: Option<String>,
+    /// The journals to read at the same time instead of the one set by
+    /// `JOURNAL_DIRECTORY` or `JOURNAL_NAMESPACE`, if any are listed.
+    pub journal_sources: Vec<JournalSource>,
+    pub oneshot: bool,
+    /// How long the reader waits for a new entry before it stops, if it
+    /// doesn't follow the journal forever.
+    pub idle_timeout: Option<Duration>,
+    pub start_position: StartPosition,
+    pub units: Vec<String>,
+    pub source: Source,
+    pub message_format: MessageFormat,
+    pub message_encoding: MessageEncoding,
+    pub json_format: JsonFormat,
+    pub max_upload_attempts: u32,
+    pub flush_interval: Duration,
+    /// How long pending events are held, at least, for more to join them
+    /// before an idle flush. Zero flushes as soon as the host goes quiet.
+    pub batch_linger: Duration,
+    /// The longest pending events are held before they are flushed, even
+    /// while new events keep arriving.
+    pub max_batch_latency: Option<Duration>,
+    pub create_log_group: bool,
+    pub log_retention_days: Option<i32>,
+    pub dry_run: bool,
+    pub backend: Backend,
+    pub stream_per_unit: bool,
+    pub unit_stream_template: String,
+    pub stream_per_boot: bool,
+    pub log_file_path: PathBuf,
+    pub log_file_max_bytes: u64,
+    pub kinesis_stream_name: String,
+    pub spool_dir: Option<PathBuf>,
+    pub spool_max_bytes: u64,
+    pub shutdown_timeout: Duration,
+    pub connect_timeout: Duration,
+    pub connect_backoff_base: Duration,
+    pub channel_capacity: usize,
+    pub max_concurrent_uploads: usize,
+    pub use_sequence_tokens: bool,
+    pub max_buffered_bytes: u64,
+    pub include_pattern: Option<Regex>,
+    pub exclude_pattern: Option<Regex>,
+    pub redact_patterns: Vec<Regex>,
+    /// The length `MESSAGE` is truncated to, in bytes, if it's limited.
+    pub max_message_bytes: Option<usize>,
+    /// Whether text messages start with the event's time.
+    pub timestamp_prefix: bool,
+    pub repeat_mode: RepeatMode,
+    pub missing_message: MissingMessage,
+    pub forward_own_logs: bool,
+    /// The systemd service this runs as, if any.
+    pub own_unit: Option<String>,
+    pub metrics_addr: Option<SocketAddr>,
+    pub health_addr: Option<SocketAddr>,
+    pub validate_config: bool,
+}
+
+/// A configuration that can't work, found before starting.
+#[derive(Debug)]
+pub enum ConfigurationError {
+    /// A variable with a value that can't be used, and why.
+    InvalidValue {
+        name: String,
+        value: String,
+        reason: String,
+    },
+    MissingKinesisStreamName,
+    /// The credentials provider is misconfigured, as opposed to not having
+    /// credentials available yet.
+    InvalidCredentials(CredentialsError),
+    InvalidLogGroupName(String),
+    InvalidLogStreamName(String),
+    Unreachable(Box<SdkError<DescribeLogStreamsError>>),
+}
+
+impl ConfigurationError {
+    fn invalid_value(
+        name: &str,
+        value: &str,
+        reason: impl fmt::Display,
+    ) -> ConfigurationError {
+        ConfigurationError::InvalidValue {
+            name: name.to_string(),
+            value: value.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigurationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigurationError::InvalidValue {
+                name,
+                value,
+                reason,
+            } => write!(f, "invalid {} value {:?}: {}", name, value, reason),
+            ConfigurationError::MissingKinesisStreamName => write!(
+                f,
+                "KINESIS_STREAM_NAME must be set for the kinesis backend"
+            ),
+            ConfigurationError::InvalidCredentials(err) => {
+                write!(f, "invalid AWS credentials configuration: {}", err)
+            }
+            ConfigurationError::InvalidLogGroupName(name) => write!(
+                f,
+                "invalid log group name {:?}: it must be 1 to 512 of the \
+                 characters a-z, A-Z, 0-9, '_', '-', '/', '.' and '#'",
+                name
+            ),
+            ConfigurationError::InvalidLogStreamName(name) => write!(
+                f,
+                "invalid log stream name {:?}: it must be 1 to 512 \
+                 characters other than ':' and '*'",
+                name
+            ),
+            ConfigurationError::Unreachable(err) => {
+                write!(f, "failed to reach CloudWatch Logs: {}", err)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigurationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigurationError::InvalidCredentials(err) => Some(err),
+            ConfigurationError::Unreachable(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Check a name against the log group naming rules.
+///
+/// Reference:
+/// docs.aws.amazon.com/AmazonCloudWatchLogs/latest/APIReference/API_CreateLogGroup.html
+fn is_valid_log_group_name(name: &str) -> bool {
+    (1..=512).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-/.#".contains(c))
+}
+
+/// Check a name against the log stream naming rules.
+///
+/// Reference:
+/// docs.aws.amazon.com/AmazonCloudWatchLogs/latest/APIReference/API_CreateLogStream.html
+fn is_valid_log_stream_name(name: &str) -> bool {
+    (1..=512).contains(&name.len()) && !name.contains([':', '*'])
 }
 
 impl Configuration {
-    pub async fn new() -> Configuration {
-        let region_provider = RegionProviderChain::default_provider()
-            .or_else(Region::new("us-west-2"));
+    /// Read the configuration from the environment. Values that can't
+    /// work are an error, while ones with a sensible default are only
+    /// warned about.
+    pub async fn new() -> Result<Configuration, ConfigurationError> {
+        let region = get_region().await;
+        info!("using region {}", region);
+        let aws_config = load_aws_config(region).await?;
+        let fallback_aws_config = match var("LOG_REGION_FALLBACK") {
+            Ok(region) => {
+                info!("using fallback region {}", region);
+                Some(load_aws_config(Region::new(region)).await?)
+            }
+            Err(_) => None,
+        };
 
-        let aws_config =
-            aws_config::from_env().region(region_provider).load().await;
+        let dry_run = get_bool("DRY_RUN", false);
+        let backend = get_backend(dry_run)?;
+        if matches!(backend, Backend::CloudWatch | Backend::Kinesis) {
+            check_credentials(&aws_config).await?;
+        }
 
-        let log_stream_name = get_log_stream_name().await;
-        Configuration {
-            log_group_name: var("LOG_GROUP_NAME")
-                .unwrap_or("journald-to-cloudwatch".to_string()),
+        let log_stream_name = get_log_stream_name(&aws_config).await;
+        let start_position = get_start_position()?;
+        Ok(Configuration {
+            log_group_name: get_log_group_name(
+                &var("LOG_GROUP_NAME")
+                    .unwrap_or("journald-to-cloudwatch".to_string()),
+                &var("LOG_GROUP_PREFIX").unwrap_or_default(),
+                &var("LOG_GROUP_SUFFIX").unwrap_or_default(),
+            ),
             log_stream_name,
-            is_debug_mode_enabled: var("DEBUG").is_ok(),
+            max_events_per_batch: get_max_events_per_batch(),
             aws_config,
+            fallback_aws_config,
+            cursor_path: var("CURSOR_PATH").map(PathBuf::from).unwrap_or_else(
+                |_| PathBuf::from("/var/lib/journald-to-cloudwatch/cursor"),
+            ),
+            journal_directory: var("JOURNAL_DIRECTORY").ok().map(PathBuf::from),
+            journal_namespace: var("JOURNAL_NAMESPACE").ok(),
+            journal_sources: get_journal_sources(start_position)?,
+            oneshot: get_bool("ONESHOT", false),
+            idle_timeout: get_idle_timeout(),
+            start_position,
+            units: get_list("UNITS"),
+            source: get_source()?,
+            message_format: get_message_format(),
+            message_encoding: get_message_encoding(),
+            json_format: get_json_format(),
+            max_upload_attempts: get_number("MAX_UPLOAD_ATTEMPTS", 5),
+            flush_interval: Duration::from_secs(get_number(
+                "FLUSH_INTERVAL_SECS",
+                5,
+            )),
+            batch_linger: Duration::from_millis(get_number(
+                "BATCH_LINGER_MS",
+                0,
+            )),
+            max_batch_latency: get_max_batch_latency(),
+            create_log_group: get_bool("CREATE_LOG_GROUP", true),
+            log_retention_days: get_log_retention_days()?,
+            dry_run,
+            backend,
+            stream_per_unit: get_bool("STREAM_PER_UNIT", false),
+            unit_stream_template: var("UNIT_STREAM_TEMPLATE")
+                .unwrap_or_else(|_| "{stream}/{unit}".to_string()),
+            stream_per_boot: get_bool("STREAM_PER_BOOT", false),
+            log_file_path: var("LOG_FILE_PATH")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| {
+                    PathBuf::from(
+                        "/var/lib/journald-to-cloudwatch/events.jsonl",
+                    )
+                }),
+            log_file_max_bytes: get_number(
+                "LOG_FILE_MAX_BYTES",
+                100 * 1024 * 1024,
+            ),
+            kinesis_stream_name: get_kinesis_stream_name(backend)?,
+            spool_dir: var("SPOOL_DIR").ok().map(PathBuf::from),
+            spool_max_bytes: get_number("SPOOL_MAX_BYTES", 100 * 1024 * 1024),
+            shutdown_timeout: Duration::from_secs(get_number(
+                "SHUTDOWN_TIMEOUT_SECS",
+                10,
+            )),
+            connect_timeout: Duration::from_secs(get_number(
+                "CONNECT_TIMEOUT_SECS",
+                300,
+            )),
+            connect_backoff_base: Duration::from_millis(get_number(
+                "CONNECT_BACKOFF_MS",
+                1000,
+            )),
+            channel_capacity: get_channel_capacity(),
+            max_concurrent_uploads: get_max_concurrent_uploads(),
+            use_sequence_tokens: get_bool("USE_SEQUENCE_TOKENS", false),
+            max_buffered_bytes: get_number(
+                "MAX_BUFFERED_BYTES",
+                256 * 1024 * 1024,
+            ),
+            include_pattern: get_pattern("INCLUDE_PATTERN")?,
+            exclude_pattern: get_pattern("EXCLUDE_PATTERN")?,
+            redact_patterns: get_patterns("REDACT_PATTERNS")?,
+            max_message_bytes: get_max_message_bytes(),
+            timestamp_prefix: get_bool("INCLUDE_TIMESTAMP_PREFIX", false),
+            repeat_mode: get_repeat_mode(),
+            missing_message: get_missing_message(),
+            forward_own_logs: get_bool("FORWARD_OWN_LOGS", false),
+            own_unit: get_own_unit(),
+            metrics_addr: get_address("METRICS_ADDR")?,
+            health_addr: get_address("HEALTH_ADDR")?,
+            validate_config: get_bool("VALIDATE_CONFIG", true),
+        })
+    }
+
+    /// A configuration for unit tests that doesn't read the environment
+    /// or talk to AWS.
+    #[cfg(test)]
+    pub fn for_tests() -> Configuration {
+        Configuration {
+            log_group_name: "myGroup".to_string(),
+            log_stream_name: "myStream".to_string(),
+            max_events_per_batch: DEFAULT_MAX_EVENTS_PER_BATCH,
+            aws_config: SdkConfig::builder()
+                .region(Region::from_static("us-test-2"))
+                .build(),
+            fallback_aws_config: None,
+            cursor_path: PathBuf::from("/nonexistent/cursor"),
+            journal_directory: None,
+            journal_namespace: None,
+            journal_sources: Vec::new(),
+            oneshot: false,
+            idle_timeout: None,
+            start_position: StartPosition::Tail,
+            units: Vec::new(),
+            source: Source::All,
+            message_format: MessageFormat::default(),
+            message_encoding: MessageEncoding::Text,
+            json_format: JsonFormat::default(),
+            max_upload_attempts: 5,
+            flush_interval: Duration::from_secs(5),
+            batch_linger: Duration::ZERO,
+            max_batch_latency: None,
+            create_log_group: true,
+            log_retention_days: None,
+            dry_run: false,
+            backend: Backend::CloudWatch,
+            stream_per_unit: false,
+            unit_stream_template: "{stream}/{unit}".to_string(),
+            stream_per_boot: false,
+            log_file_path: PathBuf::from("/nonexistent/events.jsonl"),
+            log_file_max_bytes: 100 * 1024 * 1024,
+            kinesis_stream_name: "myKinesisStream".to_string(),
+            spool_dir: None,
+            spool_max_bytes: 100 * 1024 * 1024,
+            shutdown_timeout: Duration::from_secs(10),
+            connect_timeout: Duration::from_secs(300),
+            connect_backoff_base: Duration::from_secs(1),
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            max_concurrent_uploads: 1,
+            use_sequence_tokens: false,
+            max_buffered_bytes: 256 * 1024 * 1024,
+            include_pattern: None,
+            exclude_pattern: None,
+            redact_patterns: Vec::new(),
+            max_message_bytes: None,
+            timestamp_prefix: false,
+            repeat_mode: RepeatMode::Keep,
+            missing_message: MissingMessage::Drop,
+            forward_own_logs: false,
+            own_unit: None,
+            metrics_addr: None,
+            health_addr: None,
+            validate_config: true,
+        }
+    }
+
+    /// Log the settings that decide what is read and where it goes, as one
+    /// line with a field per setting. Credentials are left out, and of the
+    /// redaction patterns only the number is shown.
+    pub fn log_summary(&self) {
+        let region = self.aws_config.region().map(|region| region.to_string());
+        let fallback_region = self
+            .fallback_aws_config
+            .as_ref()
+            .and_then(|conf| conf.region())
+            .map(|region| region.to_string());
+        let aws_max_attempts = self
+            .aws_config
+            .retry_config()
+            .map(|retry_config| retry_config.max_attempts());
+        let include_pattern = self.include_pattern.as_ref().map(Regex::as_str);
+        let exclude_pattern = self.exclude_pattern.as_ref().map(Regex::as_str);
+        info!(
+            backend = ?self.backend,
+            region = ?region,
+            fallback_region = ?fallback_region,
+            log_group = %self.log_group_name,
+            log_stream = %self.log_stream_name,
+            stream_per_unit = self.stream_per_unit,
+            stream_per_boot = self.stream_per_boot,
+            max_concurrent_uploads = self.max_concurrent_uploads,
+            max_upload_attempts = self.max_upload_attempts,
+            aws_max_attempts = ?aws_max_attempts,
+            flush_interval = ?self.flush_interval,
+            batch_linger = ?self.batch_linger,
+            max_batch_latency = ?self.max_batch_latency,
+            use_sequence_tokens = self.use_sequence_tokens,
+            kinesis_stream = %self.kinesis_stream_name,
+            log_file = %self.log_file_path.display(),
+            start_position = ?self.start_position,
+            journal_directory = ?self.journal_directory,
+            journal_namespace = ?self.journal_namespace,
+            journal_sources = ?self.journal_sources,
+            units = ?self.units,
+            source = ?self.source,
+            include_pattern = ?include_pattern,
+            exclude_pattern = ?exclude_pattern,
+            redact_patterns = self.redact_patterns.len(),
+            max_message_bytes = ?self.max_message_bytes,
+            timestamp_prefix = self.timestamp_prefix,
+            message_encoding = ?self.message_encoding,
+            missing_message = ?self.missing_message,
+            forward_own_logs = self.forward_own_logs,
+            own_unit = ?self.own_unit,
+            spool_dir = ?self.spool_dir,
+            dry_run = self.dry_run,
+            oneshot = self.oneshot,
+            idle_timeout = ?self.idle_timeout,
+            "effective configuration"
+        );
+    }
+
+    /// Check that the log group and stream names are ones CloudWatch
+    /// accepts. Per-unit and per-boot stream names are checked with a
+    /// placeholder unit and boot, since those are always valid.
+    pub fn validate(&self) -> Result<(), ConfigurationError> {
+        if !is_valid_log_group_name(&self.log_group_name) {
+            return Err(ConfigurationError::InvalidLogGroupName(
+                self.log_group_name.clone(),
+            ));
+        }
+        let mut stream_names = vec![self.log_stream_name.clone()];
+        if self.stream_per_unit || self.stream_per_boot {
+            let mut record = BTreeMap::new();
+            record.insert("_SYSTEMD_UNIT".to_string(), "unit".to_string());
+            record.insert("_BOOT_ID".to_string(), "boot".to_string());
+            stream_names.extend(self.record_stream_name(&record));
+        }
+        for name in stream_names {
+            if !is_valid_log_stream_name(&name) {
+                return Err(ConfigurationError::InvalidLogStreamName(name));
+            }
+        }
+        Ok(())
+    }
+
+    /// The log stream for a record when each unit or boot has its own
+    /// stream, or `None` for the default stream. Records without a unit,
+    /// such as kernel messages, go to the stream of their boot, and those
+    /// without a boot ID to the default stream. A record from a new boot
+    /// gets a new name, so reading across a reboot starts a new stream.
+    pub fn record_stream_name(
+        &self,
+        record: &BTreeMap<String, String>,
+    ) -> Option<String> {
+        let boot_stream = record
+            .get("_BOOT_ID")
+            .filter(|_| self.stream_per_boot)
+            .map(|boot_id| {
+                format!(
+                    "{}/{}",
+                    self.log_stream_name,
+                    get_short_boot_id(boot_id)
+                )
+            });
+        let unit = record.get("_SYSTEMD_UNIT").filter(|_| self.stream_per_unit);
+        match unit {
+            Some(unit) => Some(
+                self.unit_stream_template
+                    .replace(
+                        "{stream}",
+                        boot_stream.as_deref().unwrap_or(&self.log_stream_name),
+                    )
+                    .replace("{unit}", unit),
+            ),
+            None => boot_stream,
+        }
+    }
+}
+
+/// How many characters of the boot ID name the stream of a boot. The
+/// journal's boot IDs are random, so this is plenty to tell boots apart.
+const SHORT_BOOT_ID_CHARS: usize = 8;
+
+fn get_short_boot_id(boot_id: &str) -> &str {
+    boot_id.get(..SHORT_BOOT_ID_CHARS).unwrap_or(boot_id)
+}
+
+/// Read a comma-separated list from the environment, ignoring empty
+/// entries.
+fn get_list(name: &str) -> Vec<String> {
+    match var(name) {
+        Ok(value) => parse_list(&value),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(|item| item.to_string())
+        .collect()
+}
+
+/// Read a number from the environment, falling back to the default if
+/// it is unset or invalid.
+fn get_number<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match var(name) {
+        Ok(value) => value.trim().parse().unwrap_or_else(|_| {
+            warn!("invalid {} value {:?}, using the default", name, value);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// The number of events that can wait in the queue between the journal
+/// reader and the uploader when `CHANNEL_CAPACITY` is not set.
+const DEFAULT_CHANNEL_CAPACITY: usize = 1024;
+
+const DEFAULT_MAX_EVENTS_PER_BATCH: usize = 100;
+
+/// `DEBUG` used to upload every event on its own, so it still does when
+/// `MAX_EVENTS_PER_BATCH` isn't set.
+fn get_max_events_per_batch() -> usize {
+    if var("MAX_EVENTS_PER_BATCH").is_err() && var("DEBUG").is_ok() {
+        return 1;
+    }
+    match get_number("MAX_EVENTS_PER_BATCH", DEFAULT_MAX_EVENTS_PER_BATCH) {
+        0 => {
+            warn!("MAX_EVENTS_PER_BATCH must be at least 1, using the default");
+            DEFAULT_MAX_EVENTS_PER_BATCH
+        }
+        max_events => max_events,
+    }
+}
+
+/// Wrap the base log group name in the `LOG_GROUP_PREFIX` and
+/// `LOG_GROUP_SUFFIX` of an environment, as they are, so the separator is
+/// up to them. The result is checked like any other name by `validate`.
+fn get_log_group_name(name: &str, prefix: &str, suffix: &str) -> String {
+    format!("{}{}{}", prefix, name, suffix)
+}
+
+/// `IDLE_TIMEOUT_SECS`, where 0 or unset means following the journal
+/// forever.
+fn get_idle_timeout() -> Option<Duration> {
+    match get_number("IDLE_TIMEOUT_SECS", 0) {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
+
+/// `MAX_MESSAGE_BYTES`, where 0 or unset means no limit.
+fn get_max_message_bytes() -> Option<usize> {
+    match get_number("MAX_MESSAGE_BYTES", 0) {
+        0 => None,
+        max_bytes => Some(max_bytes),
+    }
+}
+
+/// `MAX_BATCH_LATENCY_MS`, where 0 or unset means no limit.
+fn get_max_batch_latency() -> Option<Duration> {
+    match get_number("MAX_BATCH_LATENCY_MS", 0) {
+        0 => None,
+        millis => Some(Duration::from_millis(millis)),
+    }
+}
+
+fn get_channel_capacity() -> usize {
+    match get_number("CHANNEL_CAPACITY", DEFAULT_CHANNEL_CAPACITY) {
+        0 => {
+            warn!("CHANNEL_CAPACITY must be at least 1, using the default");
+            DEFAULT_CHANNEL_CAPACITY
         }
+        capacity => capacity,
     }
+}
 
-    pub fn path(&self) -> String {
-        format!("{}/{}", self.log_group_name, self.log_stream_name)
+fn get_max_concurrent_uploads() -> usize {
+    match get_number("MAX_CONCURRENT_UPLOADS", 1) {
+        0 => {
+            warn!("MAX_CONCURRENT_UPLOADS must be at least 1, using 1");
+            1
+        }
+        max_uploads => max_uploads,
     }
+}
 
-    pub fn debug(&self, message: String) {
-        if self.is_debug_mode_enabled {
-            eprintln!("{}", message);
+/// Read a boolean flag from the environment, falling back to the default
+/// if it is unset or invalid.
+fn get_bool(name: &str, default: bool) -> bool {
+    match var(name) {
+        Ok(value) => parse_bool(&value).unwrap_or_else(|| {
+            warn!("invalid {} value {:?}, using the default", name, value);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// Read the log group retention period. An invalid value is fatal, since
+/// CloudWatch would reject it anyway.
+fn get_log_retention_days() -> Result<Option<i32>, ConfigurationError> {
+    let value = match var("LOG_RETENTION_DAYS") {
+        Ok(value) => value,
+        Err(_) => return Ok(None),
+    };
+    match parse_retention_days(&value) {
+        Some(days) => Ok(Some(days)),
+        None => Err(ConfigurationError::invalid_value(
+            "LOG_RETENTION_DAYS",
+            &value,
+            format!("expected one of {:?}", RETENTION_DAYS),
+        )),
+    }
+}
+
+/// Read a regular expression from the environment. An invalid pattern is
+/// fatal, since silently filtering nothing (or everything) would be worse.
+fn get_pattern(name: &str) -> Result<Option<Regex>, ConfigurationError> {
+    let value = match var(name) {
+        Ok(value) => value,
+        Err(_) => return Ok(None),
+    };
+    Regex::new(&value)
+        .map(Some)
+        .map_err(|err| ConfigurationError::invalid_value(name, &value, err))
+}
+
+/// Read a list of regular expressions from the environment, one per
+/// line, so that a pattern may contain spaces. Like a single pattern, an
+/// invalid one is fatal.
+fn get_patterns(name: &str) -> Result<Vec<Regex>, ConfigurationError> {
+    let value = var(name).unwrap_or_default();
+    parse_patterns(&value).map_err(|(pattern, err)| {
+        ConfigurationError::invalid_value(name, pattern, err)
+    })
+}
+
+/// Parse one regular expression per line, ignoring blank lines and the
+/// whitespace around each pattern. Returns the first invalid pattern
+/// with its error.
+fn parse_patterns(value: &str) -> Result<Vec<Regex>, (&str, regex::Error)> {
+    value
+        .lines()
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+        .map(|pattern| Regex::new(pattern).map_err(|err| (pattern, err)))
+        .collect()
+}
+
+/// Read a listen address such as `127.0.0.1:9100` from the environment.
+/// An invalid address is fatal.
+fn get_address(name: &str) -> Result<Option<SocketAddr>, ConfigurationError> {
+    let value = match var(name) {
+        Ok(value) => value,
+        Err(_) => return Ok(None),
+    };
+    value
+        .trim()
+        .parse()
+        .map(Some)
+        .map_err(|err| ConfigurationError::invalid_value(name, &value, err))
+}
+
+/// Read the endpoint override used instead of the regional AWS endpoints,
+/// for example to test against a local emulator.
+fn get_endpoint() -> Result<Option<Endpoint>, ConfigurationError> {
+    let (name, value) = match ["CLOUDWATCH_ENDPOINT", "AWS_ENDPOINT_URL"]
+        .iter()
+        .find_map(|name| var(name).ok().map(|value| (name, value)))
+    {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    match value.trim().parse() {
+        Ok(uri) => {
+            info!("using endpoint {}", value.trim());
+            Ok(Some(Endpoint::immutable(uri)))
         }
+        Err(err) => Err(ConfigurationError::invalid_value(name, &value, err)),
     }
 }
 
-async fn get_log_stream_name() -> String {
-    match ec2::get_instance_id().await {
+fn parse_retention_days(value: &str) -> Option<i32> {
+    let days = value.trim().parse().ok()?;
+    if RETENTION_DAYS.contains(&days) {
+        Some(days)
+    } else {
+        None
+    }
+}
+
+/// `SINCE` takes precedence over `JOURNAL_SEEK`, and an invalid time is
+/// fatal, since reading from the wrong place could upload far too much.
+fn get_start_position() -> Result<StartPosition, ConfigurationError> {
+    if let Ok(value) = var("SINCE") {
+        let now = Utc::now().timestamp_nanos() / 1000;
+        return parse_since(&value, now)
+            .map(StartPosition::Since)
+            .ok_or_else(|| {
+                ConfigurationError::invalid_value(
+                    "SINCE",
+                    &value,
+                    "expected an RFC 3339 time or an offset such as -1h",
+                )
+            });
+    }
+    Ok(match var("JOURNAL_SEEK") {
+        Ok(value) => StartPosition::parse(&value).unwrap_or_else(|| {
+            warn!(
+                "invalid JOURNAL_SEEK value {:?}, expected tail, head or \
+                 cursor; using tail",
+                value
+            );
+            StartPosition::Tail
+        }),
+        Err(_) => StartPosition::Tail,
+    })
+}
+
+/// Parse a `SINCE` time, either an RFC 3339 time such as
+/// `2024-05-01T12:00:00Z` or an offset back from `now` such as `-90s`,
+/// `-30m`, `-1h` or `-7d`, into microseconds since the epoch.
+fn parse_since(value: &str, now: i64) -> Option<u64> {
+    let value = value.trim();
+    let micros = match DateTime::parse_from_rfc3339(value) {
+        Ok(time) => time.timestamp_nanos() / 1000,
+        Err(_) => {
+            let offset = value.strip_prefix('-').unwrap_or(value);
+            let unit_start = offset.len().checked_sub(1)?;
+            let amount: i64 = offset.get(..unit_start)?.parse().ok()?;
+            let unit_secs = match &offset[unit_start..] {
+                "s" => 1,
+                "m" => 60,
+                "h" => 60 * 60,
+                "d" => 24 * 60 * 60,
+                _ => return None,
+            };
+            now - amount.checked_mul(unit_secs * 1_000_000)?
+        }
+    };
+    u64::try_from(micros).ok()
+}
+
+fn get_message_format() -> MessageFormat {
+    let template = match var("MESSAGE_FORMAT") {
+        Ok(template) => {
+            if var("MESSAGE_PREFIX_SEPARATOR").is_ok() {
+                warn!(
+                    "MESSAGE_PREFIX_SEPARATOR has no effect when \
+                     MESSAGE_FORMAT is set"
+                );
+            }
+            template
+        }
+        Err(_) => format::default_message_format(&get_prefix_separator()),
+    };
+    let mut format = MessageFormat::parse(&template)
+        .with_fallback_hostname(format::get_local_hostname())
+        .with_fallback_comm(
+            var("COMM_FALLBACK")
+                .unwrap_or_else(|_| format::DEFAULT_COMM_FALLBACK.to_string()),
+        )
+        .with_fallback_level(get_default_level());
+    if get_bool("INCLUDE_CONTAINER_FIELDS", false) {
+        format = format.with_container_prefix();
+    }
+    if get_bool("INCLUDE_HOSTNAME", false) {
+        format.with_hostname_prefix()
+    } else {
+        format
+    }
+}
+
+/// `MESSAGE_PREFIX_SEPARATOR`, where `\t` stands for a tab since that's
+/// awkward to write in a unit file. Set but empty means no separator.
+fn get_prefix_separator() -> String {
+    match var("MESSAGE_PREFIX_SEPARATOR") {
+        Ok(separator) => separator.replace("\\t", "\t"),
+        Err(_) => format::DEFAULT_PREFIX_SEPARATOR.to_string(),
+    }
+}
+
+fn get_json_format() -> JsonFormat {
+    let mut format = JsonFormat::new().with_fallback_level(get_default_level());
+    let fields = get_list("FORWARD_FIELDS");
+    if !fields.is_empty() {
+        format = format.with_fields(&fields);
+    }
+    if get_bool("INCLUDE_CONTAINER_FIELDS", false) {
+        format = format.with_container_fields();
+    }
+    if get_bool("INCLUDE_BOOT_ID", false) {
+        format = format.with_boot_id();
+    }
+    if get_bool("EXTRACT_LOGFMT", false) {
+        format.with_logfmt()
+    } else {
+        format
+    }
+}
+
+fn get_default_level() -> String {
+    var("DEFAULT_LEVEL").unwrap_or_else(|_| format::DEFAULT_LEVEL.to_string())
+}
+
+fn get_message_encoding() -> MessageEncoding {
+    match var("MESSAGE_ENCODING") {
+        Ok(value) => MessageEncoding::parse(&value).unwrap_or_else(|| {
+            warn!(
+                "invalid MESSAGE_ENCODING value {:?}, expected text or json; \
+                 using text",
+                value
+            );
+            MessageEncoding::Text
+        }),
+        Err(_) => MessageEncoding::Text,
+    }
+}
+
+/// A dry run always prints to stdout, whatever the backend.
+fn get_backend(dry_run: bool) -> Result<Backend, ConfigurationError> {
+    if dry_run {
+        return Ok(Backend::Stdout);
+    }
+    match var("BACKEND") {
+        Ok(value) => Backend::parse(&value).ok_or_else(|| {
+            ConfigurationError::invalid_value(
+                "BACKEND",
+                &value,
+                "expected cloudwatch, stdout, file or kinesis",
+            )
+        }),
+        Err(_) => Ok(Backend::CloudWatch),
+    }
+}
+
+/// The Kinesis backend has nowhere to send events without a stream name,
+/// so it's required when that backend is used.
+fn get_kinesis_stream_name(
+    backend: Backend,
+) -> Result<String, ConfigurationError> {
+    match var("KINESIS_STREAM_NAME") {
+        Ok(name) => Ok(name),
+        Err(_) if backend == Backend::Kinesis => {
+            Err(ConfigurationError::MissingKinesisStreamName)
+        }
+        Err(_) => Ok(String::new()),
+    }
+}
+
+/// `JOURNAL_SOURCES`, which replaces `JOURNAL_DIRECTORY` and
+/// `JOURNAL_NAMESPACE` and so can't be combined with them. There is only
+/// one saved cursor, which can't hold the position in several journals,
+/// so resuming from it isn't supported either.
+fn get_journal_sources(
+    start_position: StartPosition,
+) -> Result<Vec<JournalSource>, ConfigurationError> {
+    let value = match var("JOURNAL_SOURCES") {
+        Ok(value) => value,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let sources = parse_journal_sources(&value).ok_or_else(|| {
+        ConfigurationError::invalid_value(
+            "JOURNAL_SOURCES",
+            &value,
+            "expected a list of system, user and journal directories",
+        )
+    })?;
+    let reason = if var("JOURNAL_DIRECTORY").is_ok()
+        || var("JOURNAL_NAMESPACE").is_ok()
+    {
+        "can't be combined with JOURNAL_DIRECTORY or JOURNAL_NAMESPACE"
+    } else if start_position == StartPosition::Cursor {
+        "can't be combined with JOURNAL_SEEK=cursor"
+    } else {
+        return Ok(sources);
+    };
+    Err(ConfigurationError::invalid_value(
+        "JOURNAL_SOURCES",
+        &value,
+        reason,
+    ))
+}
+
+/// Parse a list of journal sources, where each may only be listed once.
+fn parse_journal_sources(value: &str) -> Option<Vec<JournalSource>> {
+    let mut sources = Vec::new();
+    for item in parse_list(value) {
+        let source = JournalSource::parse(&item)?;
+        if sources.contains(&source) {
+            return None;
+        }
+        sources.push(source);
+    }
+    Some(sources)
+}
+
+fn get_source() -> Result<Source, ConfigurationError> {
+    match var("SOURCE") {
+        Ok(value) => Source::parse(&value).ok_or_else(|| {
+            ConfigurationError::invalid_value(
+                "SOURCE",
+                &value,
+                "expected all, kernel or user",
+            )
+        }),
+        Err(_) => Ok(Source::All),
+    }
+}
+
+fn get_repeat_mode() -> RepeatMode {
+    match var("REPEATED_MESSAGES") {
+        Ok(value) => RepeatMode::parse(&value).unwrap_or_else(|| {
+            warn!(
+                "invalid REPEATED_MESSAGES value {:?}, expected keep, expand \
+                 or annotate; using keep",
+                value
+            );
+            RepeatMode::Keep
+        }),
+        Err(_) => RepeatMode::Keep,
+    }
+}
+
+/// Look up the unit of this process from its control group. Outside of a
+/// service, such as in a login session, the process shares a scope unit
+/// with others, so only service units count.
+fn get_own_unit() -> Option<String> {
+    match login::get_unit(UnitType::SystemUnit, None) {
+        Ok(unit) if unit.ends_with(".service") => Some(unit),
+        Ok(_) => None,
+        Err(err) => {
+            debug!("failed to look up the unit of this process: {}", err);
+            None
+        }
+    }
+}
+
+fn get_missing_message() -> MissingMessage {
+    match var("MISSING_MESSAGE") {
+        Ok(value) => MissingMessage::parse(&value).unwrap_or_else(|| {
+            warn!(
+                "invalid MISSING_MESSAGE value {:?}, expected drop, record \
+                 or field:NAME; using drop",
+                value
+            );
+            MissingMessage::Drop
+        }),
+        Err(_) => MissingMessage::Drop,
+    }
+}
+
+/// The region used when none is configured and instance metadata isn't
+/// available.
+const DEFAULT_REGION: &str = "us-west-2";
+
+/// How long before they expire cached credentials are refreshed.
+const CREDENTIALS_REFRESH_BUFFER: Duration = Duration::from_secs(5 * 60);
+
+/// Resolve the region for all AWS clients: `LOG_REGION` first, then the
+/// SDK's default chain (`AWS_REGION`, `AWS_DEFAULT_REGION`, the profile,
+/// then instance metadata), then `DEFAULT_REGION`.
+async fn get_region() -> Region {
+    let region_provider =
+        RegionProviderChain::first_try(var("LOG_REGION").ok().map(Region::new))
+            .or_default_provider()
+            .or_else(Region::new(DEFAULT_REGION));
+    region_provider
+        .region()
+        .await
+        .unwrap_or_else(|| Region::new(DEFAULT_REGION))
+}
+
+/// Load the AWS configuration for a region, with the credentials, endpoint
+/// and timeouts that are configured.
+async fn load_aws_config(
+    region: Region,
+) -> Result<SdkConfig, ConfigurationError> {
+    // A hung connection times out like any other failed request, so the
+    // upload retries and backoff apply to it as well
+    let request_timeout = get_number("REQUEST_TIMEOUT_SECS", 30);
+    let call_timeout = match get_number("CALL_TIMEOUT_SECS", 0) {
+        0 => TriState::Unset,
+        secs => TriState::Set(Duration::from_secs(secs)),
+    };
+    let timeout_config = timeout::Config::new().with_api_timeouts(
+        timeout::Api::new()
+            .with_call_attempt_timeout(TriState::Set(Duration::from_secs(
+                request_timeout,
+            )))
+            .with_call_timeout(call_timeout),
+    );
+    let loader = aws_config::from_env()
+        .region(region.clone())
+        .retry_config(get_retry_config()?)
+        .timeout_config(timeout_config);
+    let mut loader = match get_assume_role_provider(&region).await {
+        Some(provider) => loader.credentials_provider(provider),
+        None => loader.credentials_provider(get_default_chain(&region).await),
+    };
+    if let Some(endpoint) = get_endpoint()? {
+        loader = loader.endpoint_resolver(endpoint);
+    }
+    Ok(loader.load().await)
+}
+
+/// The SDK's own retries of each request, which happen within a single
+/// one of our upload attempts. `AWS_MAX_ATTEMPTS` is read here rather than
+/// by the SDK, which panics on invalid values; it defaults to the SDK's
+/// 3 attempts.
+fn get_retry_config() -> Result<RetryConfig, ConfigurationError> {
+    let config = RetryConfig::new();
+    match var("AWS_MAX_ATTEMPTS") {
+        Ok(value) => parse_max_attempts(&value)
+            .map(|max_attempts| config.with_max_attempts(max_attempts))
+            .ok_or_else(|| {
+                ConfigurationError::invalid_value(
+                    "AWS_MAX_ATTEMPTS",
+                    &value,
+                    "expected a number of attempts of at least 1",
+                )
+            }),
+        Err(_) => Ok(config),
+    }
+}
+
+fn parse_max_attempts(value: &str) -> Option<u32> {
+    value
+        .trim()
+        .parse()
+        .ok()
+        .filter(|max_attempts| *max_attempts > 0)
+}
+
+/// Assume `ASSUME_ROLE_ARN`, if set, with credentials from the default
+/// chain. The temporary credentials are cached and refreshed before they
+/// expire.
+async fn get_assume_role_provider(
+    region: &Region,
+) -> Option<AssumeRoleProvider> {
+    let role_arn = var("ASSUME_ROLE_ARN").ok()?;
+    info!("assuming role {}", role_arn);
+    let mut builder = AssumeRoleProvider::builder(role_arn)
+        .region(region.clone())
+        .session_name("journald-to-cloudwatch");
+    if let Ok(external_id) = var("EXTERNAL_ID") {
+        builder = builder.external_id(external_id);
+    }
+    let base = get_default_chain(region).await;
+    Some(builder.build(SharedCredentialsProvider::new(base)))
+}
+
+/// The default credential chain: environment variables, the `AWS_PROFILE`
+/// profile (including SSO and role profiles), web identity tokens, ECS
+/// task roles and the instance profile, in that order. Credentials are
+/// cached and refreshed early, so a slow refresh of hourly role or SSO
+/// credentials can't leave requests signed with expired ones.
+async fn get_default_chain(region: &Region) -> DefaultCredentialsChain {
+    DefaultCredentialsChain::builder()
+        .region(region.clone())
+        .buffer_time(CREDENTIALS_REFRESH_BUFFER)
+        .build()
+        .await
+}
+
+/// Resolve the credentials once on startup, so the log shows where they
+/// come from and a missing source is noticed before the first upload. A
+/// misconfigured provider won't fix itself, so that is an error.
+async fn check_credentials(
+    aws_config: &SdkConfig,
+) -> Result<(), ConfigurationError> {
+    let provider = match aws_config.credentials_provider() {
+        Some(provider) => provider,
+        None => return Ok(()),
+    };
+    match provider.provide_credentials().await {
+        // The secret key and session token are redacted
+        Ok(credentials) => info!("loaded AWS credentials: {:?}", credentials),
+        Err(err @ CredentialsError::InvalidConfiguration { .. }) => {
+            return Err(ConfigurationError::InvalidCredentials(err));
+        }
+        // Instance credentials are often not available yet right after
+        // boot, so other errors are left to the upload retries
+        Err(err) => warn!("failed to load AWS credentials: {}", err),
+    }
+    Ok(())
+}
+
+/// What the stream is named when neither the task nor the instance can be
+/// looked up: `DEFAULT_STREAM_NAME`, where `{hostname}` is this machine's
+/// hostname, or `not-ec2`.
+fn get_default_stream_name() -> String {
+    let name = var("DEFAULT_STREAM_NAME");
+    fill_default_stream_name(name.as_deref().unwrap_or("not-ec2"), || {
+        format::get_local_hostname()
+    })
+}
+
+fn fill_default_stream_name(
+    template: &str,
+    hostname: impl FnOnce() -> String,
+) -> String {
+    if template.contains("{hostname}") {
+        template.replace("{hostname}", &hostname())
+    } else {
+        template.to_string()
+    }
+}
+
+/// Use `LOG_STREAM_NAME` if it's set. Otherwise name the log stream after
+/// the ECS task when running on ECS, or after the EC2 instance.
+async fn get_log_stream_name(aws_config: &SdkConfig) -> String {
+    if let Ok(name) = var("LOG_STREAM_NAME") {
+        return name;
+    }
+    if let Ok(metadata_uri) = var(ecs::METADATA_URI_VAR) {
+        match ecs::get_task_stream_name(&metadata_uri).await {
+            Ok(name) => return name,
+            Err(err) => warn!("get_task_stream_name failed: {}", err),
+        }
+    }
+    let attempts = get_number("METADATA_ATTEMPTS", 3).max(1);
+    let endpoint = var(ec2::METADATA_ENDPOINT_VAR).ok();
+    let mode = var(ec2::METADATA_ENDPOINT_MODE_VAR).ok();
+    let endpoint = match ec2::get_metadata_endpoint(
+        endpoint.as_deref(),
+        mode.as_deref(),
+    ) {
+        Some(endpoint) => endpoint,
+        None => {
+            warn!(
+                "invalid {} value {:?}, using IPv4",
+                ec2::METADATA_ENDPOINT_MODE_VAR,
+                mode.unwrap_or_default()
+            );
+            ec2::get_metadata_endpoint(None, None).unwrap_or_default()
+        }
+    };
+    let metadata = match ec2::MetadataClient::new(&endpoint, attempts).await {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            warn!("failed to create the metadata client: {}", err);
+            return get_default_stream_name();
+        }
+    };
+    let instance_id = match metadata.get("instance-id").await {
         Ok(id) => id,
         Err(err) => {
-            println!("get_instance_id failed: {}", err);
-            "not-ec2".to_string()
+            warn!("get_instance_id failed: {}", err);
+            return get_default_stream_name();
         }
+    };
+    // Metadata placeholders are filled in first, and any tags after them
+    let template = match var("STREAM_NAME_TEMPLATE") {
+        Ok(template) => {
+            let name = metadata.render_template(&template, &instance_id).await;
+            if !name.contains("{tag:") {
+                return name;
+            }
+            name
+        }
+        Err(_) => match var("STREAM_NAME_TAG") {
+            Ok(template) => template,
+            Err(_) => return instance_id,
+        },
+    };
+    match ec2::get_instance_name(aws_config, &instance_id, &template).await {
+        Ok(name) => name,
+        Err(err) => {
+            warn!("get_instance_name failed, using the instance ID: {}", err);
+            instance_id
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_group_name_affixes() {
+        assert_eq!(
+            get_log_group_name("journald-to-cloudwatch", "prod/", ""),
+            "prod/journald-to-cloudwatch"
+        );
+        assert_eq!(get_log_group_name("app", "", "-stage"), "app-stage");
+        let mut conf = Configuration::for_tests();
+        conf.log_group_name = get_log_group_name("app", "prod env/", "");
+        assert!(matches!(
+            conf.validate(),
+            Err(ConfigurationError::InvalidLogGroupName(_))
+        ));
+    }
+
+    #[test]
+    fn test_fill_default_stream_name() {
+        let hostname = || "web-1".to_string();
+        assert_eq!(fill_default_stream_name("not-ec2", hostname), "not-ec2");
+        assert_eq!(
+            fill_default_stream_name("onprem/{hostname}", hostname),
+            "onprem/web-1"
+        );
+    }
+
+    #[test]
+    fn test_invalid_value_message() {
+        let err = ConfigurationError::invalid_value(
+            "SOURCE",
+            "both",
+            "expected all, kernel or user",
+        );
+        assert_eq!(
+            err.to_string(),
+            "invalid SOURCE value \"both\": expected all, kernel or user"
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut conf = Configuration::for_tests();
+        assert!(conf.validate().is_ok());
+        conf.log_group_name = "/aws/my-group_1.x#y".to_string();
+        assert!(conf.validate().is_ok());
+
+        conf.log_group_name = "my group".to_string();
+        assert!(matches!(
+            conf.validate(),
+            Err(ConfigurationError::InvalidLogGroupName(_))
+        ));
+        conf.log_group_name = String::new();
+        assert!(conf.validate().is_err());
+
+        conf.log_group_name = "myGroup".to_string();
+        conf.log_stream_name = "i-123:web".to_string();
+        assert!(matches!(
+            conf.validate(),
+            Err(ConfigurationError::InvalidLogStreamName(_))
+        ));
+        conf.log_stream_name = "myStream".to_string();
+        conf.stream_per_unit = true;
+        conf.unit_stream_template = "{stream}*{unit}".to_string();
+        assert!(conf.validate().is_err());
+    }
+
+    #[test]
+    fn test_record_stream_name() {
+        let mut conf = Configuration::for_tests();
+        let mut record = BTreeMap::new();
+        record.insert("_SYSTEMD_UNIT".to_string(), "sshd.service".to_string());
+        assert_eq!(conf.record_stream_name(&record), None);
+
+        conf.stream_per_unit = true;
+        assert_eq!(
+            conf.record_stream_name(&record).as_deref(),
+            Some("myStream/sshd.service")
+        );
+        conf.unit_stream_template = "{unit}".to_string();
+        assert_eq!(
+            conf.record_stream_name(&record).as_deref(),
+            Some("sshd.service")
+        );
+        assert_eq!(conf.record_stream_name(&BTreeMap::new()), None);
+    }
+
+    #[test]
+    fn test_boot_stream_name() {
+        let mut conf = Configuration::for_tests();
+        conf.stream_per_boot = true;
+        let mut record = BTreeMap::new();
+        assert_eq!(conf.record_stream_name(&record), None);
+        record.insert(
+            "_BOOT_ID".to_string(),
+            "3f2a9c1e6b7d4e0f8a1b2c3d4e5f6a7b".to_string(),
+        );
+        assert_eq!(
+            conf.record_stream_name(&record).as_deref(),
+            Some("myStream/3f2a9c1e")
+        );
+        record.insert("_SYSTEMD_UNIT".to_string(), "sshd.service".to_string());
+        assert_eq!(
+            conf.record_stream_name(&record).as_deref(),
+            Some("myStream/3f2a9c1e")
+        );
+        conf.stream_per_unit = true;
+        assert_eq!(
+            conf.record_stream_name(&record).as_deref(),
+            Some("myStream/3f2a9c1e/sshd.service")
+        );
+    }
+
+    #[test]
+    fn test_parse_start_position() {
+        assert_eq!(StartPosition::parse("tail"), Some(StartPosition::Tail));
+        assert_eq!(StartPosition::parse("HEAD"), Some(StartPosition::Head));
+        assert_eq!(
+            StartPosition::parse(" cursor "),
+            Some(StartPosition::Cursor)
+        );
+        assert_eq!(StartPosition::parse("middle"), None);
+    }
+
+    #[test]
+    fn test_parse_journal_sources() {
+        assert_eq!(
+            parse_journal_sources("system, User,/var/lib/machines/web"),
+            Some(vec![
+                JournalSource::System,
+                JournalSource::User,
+                JournalSource::Directory(PathBuf::from(
+                    "/var/lib/machines/web"
+                )),
+            ])
+        );
+        assert_eq!(parse_journal_sources(""), Some(Vec::new()));
+        assert_eq!(parse_journal_sources("system,kernel"), None);
+        assert_eq!(parse_journal_sources("system,system"), None);
+        assert_eq!(parse_journal_sources("journal/dir"), None);
+    }
+
+    #[test]
+    fn test_parse_max_attempts() {
+        assert_eq!(parse_max_attempts("1"), Some(1));
+        assert_eq!(parse_max_attempts(" 10 "), Some(10));
+        assert_eq!(parse_max_attempts("0"), None);
+        assert_eq!(parse_max_attempts("-1"), None);
+        assert_eq!(parse_max_attempts("many"), None);
+    }
+
+    #[test]
+    fn test_parse_since() {
+        let now = 1_700_000_000_000_000;
+        assert_eq!(parse_since("-1h", now), Some(1_699_996_400_000_000));
+        assert_eq!(parse_since("-30m", now), Some(1_699_998_200_000_000));
+        assert_eq!(parse_since(" 2d ", now), Some(1_699_827_200_000_000));
+        assert_eq!(parse_since("-90s", now), Some(1_699_999_910_000_000));
+        assert_eq!(
+            parse_since("2023-11-14T22:13:20Z", now),
+            Some(1_700_000_000_000_000)
+        );
+        assert_eq!(
+            parse_since("2023-11-14T23:13:20.5+01:00", now),
+            Some(1_700_000_000_500_000)
+        );
+        assert_eq!(parse_since("yesterday", now), None);
+        assert_eq!(parse_since("-1w", now), None);
+        assert_eq!(parse_since("-h", now), None);
+        assert_eq!(parse_since("", now), None);
+        assert_eq!(parse_since("1969-01-01T00:00:00Z", now), None);
+    }
+
+    #[test]
+    fn test_parse_message_encoding() {
+        assert_eq!(MessageEncoding::parse("text"), Some(MessageEncoding::Text));
+        assert_eq!(MessageEncoding::parse("JSON"), Some(MessageEncoding::Json));
+        assert_eq!(MessageEncoding::parse("xml"), None);
+    }
+
+    #[test]
+    fn test_parse_backend() {
+        assert_eq!(Backend::parse("cloudwatch"), Some(Backend::CloudWatch));
+        assert_eq!(Backend::parse(" Stdout"), Some(Backend::Stdout));
+        assert_eq!(Backend::parse("file"), Some(Backend::File));
+        assert_eq!(Backend::parse("kinesis"), Some(Backend::Kinesis));
+        assert_eq!(Backend::parse("s3"), None);
+    }
+
+    #[test]
+    fn test_parse_source() {
+        assert_eq!(Source::parse("all"), Some(Source::All));
+        assert_eq!(Source::parse("Kernel "), Some(Source::Kernel));
+        assert_eq!(Source::parse("user"), Some(Source::User));
+        assert_eq!(Source::parse("hardware"), None);
+    }
+
+    #[test]
+    fn test_parse_missing_message() {
+        assert_eq!(MissingMessage::parse("drop"), Some(MissingMessage::Drop));
+        assert_eq!(
+            MissingMessage::parse("Record"),
+            Some(MissingMessage::Record)
+        );
+        assert_eq!(
+            MissingMessage::parse("field:MESSAGE_ID"),
+            Some(MissingMessage::Field("MESSAGE_ID".to_string()))
+        );
+        assert_eq!(MissingMessage::parse("field:"), None);
+        assert_eq!(MissingMessage::parse("empty"), None);
+    }
+
+    #[test]
+    fn test_parse_bool() {
+        assert_eq!(parse_bool("true"), Some(true));
+        assert_eq!(parse_bool("1"), Some(true));
+        assert_eq!(parse_bool("No"), Some(false));
+        assert_eq!(parse_bool("off"), Some(false));
+        assert_eq!(parse_bool("maybe"), None);
+    }
+
+    #[test]
+    fn test_parse_retention_days() {
+        assert_eq!(parse_retention_days("14"), Some(14));
+        assert_eq!(parse_retention_days("3653"), Some(3653));
+        assert_eq!(parse_retention_days("10"), None);
+        assert_eq!(parse_retention_days("forever"), None);
+    }
+
+    #[test]
+    fn test_parse_list() {
+        assert_eq!(
+            parse_list("sshd.service, nginx.service,,"),
+            vec!["sshd.service".to_string(), "nginx.service".to_string()]
+        );
+        assert!(parse_list("").is_empty());
+    }
+
+    #[test]
+    fn test_parse_patterns() {
+        // Blank lines and surrounding whitespace are ignored, but the
+        // spaces within a pattern are kept
+        let value =
+            "\\bAKIA[0-9A-Z]{16}\\b\n\n  \\b\\d(?:[ -]?\\d){12,15}\\b \n";
+        let patterns = parse_patterns(value).unwrap();
+        assert_eq!(patterns.len(), 2);
+        assert_eq!(patterns[1].as_str(), r"\b\d(?:[ -]?\d){12,15}\b");
+        assert!(patterns[1].is_match("card 4111 1111 1111 1111"));
+        assert!(parse_patterns("").unwrap().is_empty());
+        assert_eq!(parse_patterns("ok\n(unclosed").unwrap_err().0, "(unclosed");
     }
 }
@@ -0,0 +1,163 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Where a journal entry is, as far as can be told from its cursor, such
+/// as `s=739ad4...;i=4ece7;b=6c7c60...;m=6fbd5c5e;t=5a8a8d6d4f5f4;x=...`.
+/// Sequence numbers order the entries written under one sequence number
+/// ID; entries under different IDs are ordered by their realtime
+/// timestamp.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JournalPosition {
+    seqnum_id: String,
+    seqnum: u64,
+    realtime: u64,
+}
+
+impl JournalPosition {
+    pub fn parse(cursor: &str) -> Option<JournalPosition> {
+        let mut seqnum_id = None;
+        let mut seqnum = None;
+        let mut realtime = None;
+        for part in cursor.split(';') {
+            match part.split_once('=') {
+                Some(("s", value)) => seqnum_id = Some(value.to_string()),
+                Some(("i", value)) => {
+                    seqnum = u64::from_str_radix(value, 16).ok()
+                }
+                Some(("t", value)) => {
+                    realtime = u64::from_str_radix(value, 16).ok()
+                }
+                _ => {}
+            }
+        }
+        Some(JournalPosition {
+            seqnum_id: seqnum_id?,
+            seqnum: seqnum?,
+            realtime: realtime?,
+        })
+    }
+
+    /// Check whether this is the entry at `other` or one written before
+    /// it. Entries under different sequence number IDs are never the
+    /// same one, so only a strictly older timestamp counts for them.
+    pub fn is_at_or_before(&self, other: &JournalPosition) -> bool {
+        if self.seqnum_id == other.seqnum_id {
+            self.seqnum <= other.seqnum
+        } else {
+            self.realtime < other.realtime
+        }
+    }
+}
+
+/// Persists the journal cursor of the last event that was successfully
+/// uploaded, so that a restart can resume where the previous run stopped.
+#[derive(Clone, Debug)]
+pub struct CursorStore {
+    path: PathBuf,
+}
+
+impl CursorStore {
+    pub fn new(path: PathBuf) -> CursorStore {
+        CursorStore { path }
+    }
+
+    /// Read the saved cursor, if there is one.
+    pub fn load(&self) -> Option<String> {
+        match fs::read_to_string(&self.path) {
+            Ok(contents) => {
+                let cursor = contents.trim();
+                if cursor.is_empty() {
+                    None
+                } else {
+                    Some(cursor.to_string())
+                }
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Save the cursor. The file is written next to its final location
+    /// and then renamed into place so a crash never leaves a partial
+    /// cursor behind.
+    pub fn save(&self, cursor: &str) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, cursor)?;
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("jtc-cursor-{}-{}", std::process::id(), name))
+            .join("cursor")
+    }
+
+    fn cursor(seqnum_id: &str, seqnum: u64, realtime: u64) -> String {
+        format!(
+            "s={};i={:x};b=6c7c6013;m=6fbd5c5e;t={:x};x=d599bca0",
+            seqnum_id, seqnum, realtime
+        )
+    }
+
+    #[test]
+    fn test_parse_position() {
+        let position = JournalPosition::parse(&cursor("abc", 0x4ece7, 42));
+        assert_eq!(
+            position,
+            Some(JournalPosition {
+                seqnum_id: "abc".to_string(),
+                seqnum: 0x4ece7,
+                realtime: 42,
+            })
+        );
+        assert_eq!(JournalPosition::parse("s=abc;i=1"), None);
+        assert_eq!(JournalPosition::parse(""), None);
+    }
+
+    #[test]
+    fn test_is_at_or_before() {
+        let position = |s, i, t| JournalPosition::parse(&cursor(s, i, t));
+        let confirmed = position("abc", 10, 1000).unwrap();
+        assert!(position("abc", 9, 2000)
+            .unwrap()
+            .is_at_or_before(&confirmed));
+        assert!(position("abc", 10, 1000)
+            .unwrap()
+            .is_at_or_before(&confirmed));
+        assert!(!position("abc", 11, 900)
+            .unwrap()
+            .is_at_or_before(&confirmed));
+        assert!(position("def", 99, 999)
+            .unwrap()
+            .is_at_or_before(&confirmed));
+        assert!(!position("def", 1, 1000)
+            .unwrap()
+            .is_at_or_before(&confirmed));
+    }
+
+    #[test]
+    fn test_missing_cursor() {
+        let store = CursorStore::new(temp_path("missing"));
+        assert_eq!(store.load(), None);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = temp_path("roundtrip");
+        let store = CursorStore::new(path.clone());
+        store.save("s=abc;i=1").unwrap();
+        assert_eq!(store.load(), Some("s=abc;i=1".to_string()));
+        store.save("s=abc;i=2").unwrap();
+        assert_eq!(store.load(), Some("s=abc;i=2".to_string()));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
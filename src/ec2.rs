@@ -1,15 +1,421 @@
-use reqwest::ClientBuilder;
+use crate::backoff::Backoff;
+use aws_sdk_ec2::error::DescribeTagsError;
+use aws_sdk_ec2::model::Filter;
+use aws_sdk_ec2::types::SdkError;
+use aws_types::SdkConfig;
+use reqwest::{Client, ClientBuilder};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
 use std::time::Duration;
+use tracing::warn;
 
-/// Use the link-local interface to get the instance ID
+/// The instance metadata service endpoints, which can be overridden with
+/// the same environment variables the AWS SDKs use.
 ///
 /// Reference:
-/// docs.aws.amazon.com/AWSEC2/latest/UserGuide/ec2-instance-metadata.html
-pub async fn get_instance_id() -> reqwest::Result<String> {
-    let client = ClientBuilder::new()
-        .timeout(Duration::from_secs(3))
-        .build()?;
-    let url = "http://169.254.169.254/latest/meta-data/instance-id";
-    let response = client.get(url).send().await;
+/// docs.aws.amazon.com/sdkref/latest/guide/feature-imds-client.html
+const IPV4_METADATA_ENDPOINT: &str = "http://169.254.169.254";
+const IPV6_METADATA_ENDPOINT: &str = "http://[fd00:ec2::254]";
+pub const METADATA_ENDPOINT_VAR: &str = "AWS_EC2_METADATA_SERVICE_ENDPOINT";
+pub const METADATA_ENDPOINT_MODE_VAR: &str =
+    "AWS_EC2_METADATA_SERVICE_ENDPOINT_MODE";
+
+/// The instance metadata a stream name template can refer to, and the
+/// metadata path of each. The instance ID is always known by the time a
+/// template is rendered.
+const METADATA_PLACEHOLDERS: &[(&str, &str)] = &[
+    ("{az}", "placement/availability-zone"),
+    ("{instance_type}", "instance-type"),
+];
+
+/// What a placeholder is replaced with if its metadata can't be fetched.
+const UNKNOWN_METADATA: &str = "unknown";
+
+/// The delay between metadata requests. The metadata service is usually
+/// only unavailable for a moment just after boot.
+const METADATA_BACKOFF: Backoff =
+    Backoff::new(Duration::from_millis(200), Duration::from_secs(2));
+
+/// Make a metadata request up to `attempts` times, returning the last
+/// error if none of them succeed.
+async fn with_retries<T, F, Fut>(
+    name: &str,
+    attempts: u32,
+    mut request: F,
+) -> reqwest::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = reqwest::Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match request().await {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < attempts => {
+                warn!(
+                    "{} failed (attempt {}/{}), retrying: {}",
+                    name, attempt, attempts, err
+                );
+                METADATA_BACKOFF.sleep(attempt - 1).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// The metadata endpoint to use: an explicit endpoint, or the IPv4 or
+/// IPv6 address depending on the endpoint mode. Returns `None` for an
+/// unknown mode.
+pub fn get_metadata_endpoint(
+    endpoint: Option<&str>,
+    mode: Option<&str>,
+) -> Option<String> {
+    if let Some(endpoint) = endpoint {
+        return Some(endpoint.trim_end_matches('/').to_string());
+    }
+    match mode.map(|mode| mode.trim().to_lowercase()).as_deref() {
+        None | Some("ipv4") => Some(IPV4_METADATA_ENDPOINT.to_string()),
+        Some("ipv6") => Some(IPV6_METADATA_ENDPOINT.to_string()),
+        Some(_) => None,
+    }
+}
+
+/// Request an IMDSv2 session token
+///
+/// Reference:
+/// docs.aws.amazon.com/AWSEC2/latest/UserGuide/configuring-instance-metadata-service.html
+async fn get_metadata_token(
+    client: &Client,
+    endpoint: &str,
+) -> reqwest::Result<String> {
+    let url = format!("{}/latest/api/token", endpoint);
+    let response = client
+        .put(url)
+        .header("X-aws-ec2-metadata-token-ttl-seconds", "21600")
+        .send()
+        .await;
     response?.error_for_status()?.text().await
 }
+
+/// Fetches instance metadata from the metadata endpoint. IMDSv2 is tried
+/// first; if no session token can be obtained the requests fall back to
+/// IMDSv1. Every request is made up to `attempts` times.
+///
+/// Reference:
+/// docs.aws.amazon.com/AWSEC2/latest/UserGuide/ec2-instance-metadata.html
+pub struct MetadataClient {
+    client: Client,
+    endpoint: String,
+    token: Option<String>,
+    attempts: u32,
+}
+
+impl MetadataClient {
+    pub async fn new(
+        endpoint: &str,
+        attempts: u32,
+    ) -> reqwest::Result<MetadataClient> {
+        let client = ClientBuilder::new()
+            .timeout(Duration::from_secs(3))
+            .build()?;
+        let token = match with_retries("get_metadata_token", attempts, || {
+            get_metadata_token(&client, endpoint)
+        })
+        .await
+        {
+            Ok(token) => Some(token),
+            Err(err) => {
+                warn!("get_metadata_token failed, using IMDSv1: {}", err);
+                None
+            }
+        };
+        Ok(MetadataClient {
+            client,
+            endpoint: endpoint.to_string(),
+            token,
+            attempts,
+        })
+    }
+
+    /// Fetch a metadata item, such as `instance-id`.
+    pub async fn get(&self, path: &str) -> reqwest::Result<String> {
+        let url = format!("{}/latest/meta-data/{}", self.endpoint, path);
+        let name = format!("get_metadata {}", path);
+        with_retries(&name, self.attempts, || async {
+            let mut request = self.client.get(&url);
+            if let Some(token) = &self.token {
+                request = request.header("X-aws-ec2-metadata-token", token);
+            }
+            let response = request.send().await;
+            response?.error_for_status()?.text().await
+        })
+        .await
+    }
+
+    /// Fill in the `{instance_id}`, `{az}` and `{instance_type}`
+    /// placeholders of a stream name template. Only the metadata the
+    /// template refers to is fetched, and anything that can't be fetched
+    /// becomes `unknown`.
+    pub async fn render_template(
+        &self,
+        template: &str,
+        instance_id: &str,
+    ) -> String {
+        let mut values = vec![("{instance_id}", instance_id.to_string())];
+        for (placeholder, path) in METADATA_PLACEHOLDERS {
+            if !template.contains(placeholder) {
+                continue;
+            }
+            let value = self.get(path).await.unwrap_or_else(|err| {
+                warn!(
+                    "failed to fetch {}, using {}: {}",
+                    path, UNKNOWN_METADATA, err
+                );
+                UNKNOWN_METADATA.to_string()
+            });
+            values.push((placeholder, value));
+        }
+        fill_placeholders(template, &values)
+    }
+}
+
+fn fill_placeholders(template: &str, values: &[(&str, String)]) -> String {
+    values
+        .iter()
+        .fold(template.to_string(), |name, (placeholder, value)| {
+            name.replace(placeholder, value)
+        })
+}
+
+#[derive(Debug)]
+pub enum InstanceNameError {
+    DescribeTagsError(Box<SdkError<DescribeTagsError>>),
+    MissingTag(String),
+}
+
+impl fmt::Display for InstanceNameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InstanceNameError::DescribeTagsError(err) => {
+                write!(f, "failed to describe the instance tags: {}", err)
+            }
+            InstanceNameError::MissingTag(key) => {
+                write!(f, "the instance has no {:?} tag", key)
+            }
+        }
+    }
+}
+
+impl std::error::Error for InstanceNameError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            InstanceNameError::DescribeTagsError(err) => Some(err.as_ref()),
+            InstanceNameError::MissingTag(_) => None,
+        }
+    }
+}
+
+async fn get_instance_tags(
+    aws_config: &SdkConfig,
+    instance_id: &str,
+) -> Result<HashMap<String, String>, SdkError<DescribeTagsError>> {
+    let client = aws_sdk_ec2::Client::new(aws_config);
+    let output = client
+        .describe_tags()
+        .filters(
+            Filter::builder()
+                .name("resource-id")
+                .values(instance_id)
+                .build(),
+        )
+        .send()
+        .await?;
+    Ok(output
+        .tags
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|tag| Some((tag.key?, tag.value.unwrap_or_default())))
+        .collect())
+}
+
+/// Build a name from a template of instance tags. A template without any
+/// `{tag:...}` placeholders is the key of a single tag, so `Name` is the
+/// same as `{tag:Name}`.
+fn render_tag_template(
+    template: &str,
+    tags: &HashMap<String, String>,
+) -> Result<String, InstanceNameError> {
+    if !template.contains("{tag:") {
+        return tags.get(template).cloned().ok_or_else(|| {
+            InstanceNameError::MissingTag(template.to_string())
+        });
+    }
+    let mut name = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{tag:") {
+        name.push_str(&rest[..start]);
+        let after = &rest[start + "{tag:".len()..];
+        match after.find('}') {
+            Some(end) => {
+                let key = &after[..end];
+                match tags.get(key) {
+                    Some(value) => name.push_str(value),
+                    None => {
+                        return Err(InstanceNameError::MissingTag(
+                            key.to_string(),
+                        ))
+                    }
+                }
+                rest = &after[end + 1..];
+            }
+            None => {
+                name.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    name.push_str(rest);
+    Ok(name)
+}
+
+/// Name the instance from its tags, using a template as described in
+/// `render_tag_template`.
+pub async fn get_instance_name(
+    aws_config: &SdkConfig,
+    instance_id: &str,
+    template: &str,
+) -> Result<String, InstanceNameError> {
+    let tags = get_instance_tags(aws_config, instance_id)
+        .await
+        .map_err(|err| InstanceNameError::DescribeTagsError(Box::new(err)))?;
+    render_tag_template(template, &tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_tags() -> HashMap<String, String> {
+        [("Name", "web-1"), ("service", "api"), ("env", "prod")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_single_tag() {
+        let tags = create_tags();
+        assert_eq!(render_tag_template("Name", &tags).unwrap(), "web-1");
+        assert!(matches!(
+            render_tag_template("hostname", &tags),
+            Err(InstanceNameError::MissingTag(key)) if key == "hostname"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_with_retries() {
+        let client = Client::new();
+        let mut calls = 0;
+        let result: reqwest::Result<String> = with_retries("test", 2, || {
+            calls += 1;
+            let request = client.get("invalid url").send();
+            async { request.await?.text().await }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 2);
+
+        let mut calls = 0;
+        let result = with_retries("test", 3, || {
+            calls += 1;
+            async { Ok(5) }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 5);
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_get_metadata_endpoint() {
+        assert_eq!(
+            get_metadata_endpoint(None, None).unwrap(),
+            "http://169.254.169.254"
+        );
+        assert_eq!(
+            get_metadata_endpoint(None, Some("IPv6")).unwrap(),
+            "http://[fd00:ec2::254]"
+        );
+        assert_eq!(
+            get_metadata_endpoint(Some("http://localhost:1338/"), Some("IPv6"))
+                .unwrap(),
+            "http://localhost:1338"
+        );
+        assert_eq!(get_metadata_endpoint(None, Some("ipv5")), None);
+    }
+
+    /// Serves canned responses to the token and instance ID requests, one
+    /// connection each, and checks the token is sent back.
+    #[tokio::test]
+    async fn test_get_instance_id_from_endpoint() {
+        use crate::metrics::write_response;
+        use tokio::io::AsyncReadExt;
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for body in ["mytoken", "i-0123456789abcdef0"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buffer = [0; 1024];
+                let len = stream.read(&mut buffer).await.unwrap();
+                requests.push(
+                    String::from_utf8_lossy(&buffer[..len]).to_lowercase(),
+                );
+                write_response(&mut stream, "200 OK", "text/plain", body)
+                    .await
+                    .unwrap();
+            }
+            requests
+        });
+        let client = MetadataClient::new(&endpoint, 1).await.unwrap();
+        let instance_id = client.get("instance-id").await.unwrap();
+        assert_eq!(instance_id, "i-0123456789abcdef0");
+        let requests = server.await.unwrap();
+        assert!(requests[0].starts_with("put /latest/api/token "));
+        assert!(requests[1].starts_with("get /latest/meta-data/instance-id "));
+        assert!(requests[1].contains("x-aws-ec2-metadata-token: mytoken"));
+    }
+
+    #[test]
+    fn test_fill_placeholders() {
+        let values = vec![
+            ("{instance_id}", "i-abc123".to_string()),
+            ("{az}", "us-west-2a".to_string()),
+        ];
+        assert_eq!(
+            fill_placeholders("{az}/{instance_id}", &values),
+            "us-west-2a/i-abc123"
+        );
+        assert_eq!(
+            fill_placeholders("{instance_type}/{tag:Name}", &values),
+            "{instance_type}/{tag:Name}"
+        );
+    }
+
+    #[test]
+    fn test_tag_template() {
+        let tags = create_tags();
+        assert_eq!(
+            render_tag_template("{tag:service}-{tag:env}", &tags).unwrap(),
+            "api-prod"
+        );
+        assert_eq!(
+            render_tag_template("{tag:Name}/{tag:", &tags).unwrap(),
+            "web-1/{tag:"
+        );
+        assert!(render_tag_template("{tag:service}-{tag:team}", &tags).is_err());
+    }
+}
@@ -0,0 +1,108 @@
+use reqwest::ClientBuilder;
+use serde_json::Value;
+use std::fmt;
+use std::time::Duration;
+
+/// The environment variable ECS sets to the task metadata endpoint of the
+/// container.
+///
+/// Reference:
+/// docs.aws.amazon.com/AmazonECS/latest/developerguide/task-metadata-endpoint-v4.html
+pub const METADATA_URI_VAR: &str = "ECS_CONTAINER_METADATA_URI_V4";
+
+#[derive(Debug)]
+pub enum EcsMetadataError {
+    RequestError(reqwest::Error),
+    InvalidMetadata(String),
+}
+
+impl fmt::Display for EcsMetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EcsMetadataError::RequestError(err) => {
+                write!(f, "failed to fetch the task metadata: {}", err)
+            }
+            EcsMetadataError::InvalidMetadata(reason) => {
+                write!(f, "invalid task metadata: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EcsMetadataError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EcsMetadataError::RequestError(err) => Some(err),
+            EcsMetadataError::InvalidMetadata(_) => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for EcsMetadataError {
+    fn from(err: reqwest::Error) -> EcsMetadataError {
+        EcsMetadataError::RequestError(err)
+    }
+}
+
+async fn get_metadata(url: &str) -> Result<Value, EcsMetadataError> {
+    let client = ClientBuilder::new()
+        .timeout(Duration::from_secs(3))
+        .build()?;
+    let response = client.get(url).send().await?.error_for_status()?;
+    let text = response.text().await?;
+    serde_json::from_str(&text)
+        .map_err(|err| EcsMetadataError::InvalidMetadata(err.to_string()))
+}
+
+/// The task ID is the last part of the task ARN, for example
+/// `arn:aws:ecs:us-west-2:012345678910:task/cluster/9781c248`.
+fn get_task_id(task_arn: &str) -> &str {
+    task_arn.rsplit('/').next().unwrap_or(task_arn)
+}
+
+/// Name the stream `{container name}/{task ID}`, like the awslogs log
+/// driver does.
+fn get_stream_name(
+    container: &Value,
+    task: &Value,
+) -> Result<String, EcsMetadataError> {
+    let name = container["Name"].as_str().ok_or_else(|| {
+        EcsMetadataError::InvalidMetadata("no container Name".to_string())
+    })?;
+    let task_arn = task["TaskARN"].as_str().ok_or_else(|| {
+        EcsMetadataError::InvalidMetadata("no TaskARN".to_string())
+    })?;
+    Ok(format!("{}/{}", name, get_task_id(task_arn)))
+}
+
+/// Derive a log stream name from the ECS container and task metadata.
+pub async fn get_task_stream_name(
+    metadata_uri: &str,
+) -> Result<String, EcsMetadataError> {
+    let container = get_metadata(metadata_uri).await?;
+    let task = get_metadata(&format!("{}/task", metadata_uri)).await?;
+    get_stream_name(&container, &task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_get_stream_name() {
+        let container = json!({"Name": "web", "DockerId": "ea32192c8553"});
+        let task = json!({
+            "Cluster": "default",
+            "TaskARN": "arn:aws:ecs:us-west-2:111122223333:task/default/158d1c8",
+        });
+        assert_eq!(get_stream_name(&container, &task).unwrap(), "web/158d1c8");
+    }
+
+    #[test]
+    fn test_get_stream_name_missing_fields() {
+        let task = json!({"TaskARN": "arn:aws:ecs:us-west-2:1:task/abc"});
+        assert!(get_stream_name(&json!({}), &task).is_err());
+        assert!(get_stream_name(&json!({"Name": "web"}), &json!({})).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use std::time::{Duration, Instant};
+
+/// How long uploads to the primary region have to keep failing before
+/// switching to the fallback region.
+pub const FAILOVER_AFTER: Duration = Duration::from_secs(5 * 60);
+
+/// How often the primary region is checked while on the fallback.
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Tracks whether uploads go to the primary or the fallback region.
+#[derive(Debug, Default)]
+pub struct Failover {
+    /// When uploads to the primary region started failing.
+    failing_since: Option<Instant>,
+    /// When the primary region was last checked, which is only set while
+    /// on the fallback.
+    last_probe: Option<Instant>,
+}
+
+impl Failover {
+    pub fn new() -> Failover {
+        Failover {
+            failing_since: None,
+            last_probe: None,
+        }
+    }
+
+    pub fn using_fallback(&self) -> bool {
+        self.last_probe.is_some()
+    }
+
+    /// Record whether an upload to the primary region was delivered,
+    /// returning true if it has been failing for long enough to switch to
+    /// the fallback.
+    pub fn record_upload(&mut self, delivered: bool, now: Instant) -> bool {
+        if self.using_fallback() {
+            return false;
+        }
+        if delivered {
+            self.failing_since = None;
+            return false;
+        }
+        let failing_since = *self.failing_since.get_or_insert(now);
+        if now - failing_since < FAILOVER_AFTER {
+            return false;
+        }
+        self.failing_since = None;
+        self.last_probe = Some(now);
+        true
+    }
+
+    /// Whether it's time to check if the primary region has recovered.
+    pub fn should_probe(&mut self, now: Instant) -> bool {
+        match self.last_probe {
+            Some(last_probe) if now - last_probe >= PROBE_INTERVAL => {
+                self.last_probe = Some(now);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Record that the primary region is reachable again.
+    pub fn recover(&mut self) {
+        self.failing_since = None;
+        self.last_probe = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fails_over_after_sustained_failures() {
+        let mut failover = Failover::new();
+        let start = Instant::now();
+        assert!(!failover.record_upload(false, start));
+        assert!(!failover.record_upload(true, start + FAILOVER_AFTER));
+        assert!(!failover.record_upload(false, start + FAILOVER_AFTER));
+        assert!(!failover.using_fallback());
+        assert!(failover.record_upload(false, start + 2 * FAILOVER_AFTER));
+        assert!(failover.using_fallback());
+    }
+
+    #[test]
+    fn test_probes_primary_while_on_fallback() {
+        let mut failover = Failover::new();
+        let start = Instant::now();
+        assert!(!failover.should_probe(start + PROBE_INTERVAL));
+        failover.record_upload(false, start);
+        assert!(failover.record_upload(false, start + FAILOVER_AFTER));
+        let switched = start + FAILOVER_AFTER;
+        assert!(!failover.should_probe(switched + PROBE_INTERVAL / 2));
+        assert!(failover.should_probe(switched + PROBE_INTERVAL));
+        assert!(!failover.should_probe(switched + PROBE_INTERVAL));
+        failover.recover();
+        assert!(!failover.using_fallback());
+        assert!(!failover.should_probe(switched + 3 * PROBE_INTERVAL));
+    }
+}
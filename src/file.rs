@@ -0,0 +1,165 @@
+use crate::cloudwatch::{Undelivered, UploadError, Uploader};
+use crate::spool::encode_event;
+use async_trait::async_trait;
+use aws_sdk_cloudwatchlogs::model::InputLogEvent;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use tracing::{debug, warn};
+
+/// Appends events to a file as JSON lines. Once the file would grow past
+/// the maximum size it is renamed with a `.1` suffix, replacing the
+/// previous rotated file, and a new file is started.
+pub struct FileUploader {
+    path: PathBuf,
+    max_bytes: u64,
+    file: Option<File>,
+    num_bytes: u64,
+}
+
+impl FileUploader {
+    pub fn new(path: PathBuf, max_bytes: u64) -> FileUploader {
+        FileUploader {
+            path,
+            max_bytes,
+            file: None,
+            num_bytes: 0,
+        }
+    }
+
+    fn rotated_path(&self) -> PathBuf {
+        let mut path = OsString::from(&self.path);
+        path.push(".1");
+        PathBuf::from(path)
+    }
+
+    fn open(&mut self) -> io::Result<&mut File> {
+        if self.file.is_none() {
+            if let Some(parent) = self.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            self.num_bytes = file.metadata()?.len();
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        debug!("rotating {}", self.path.display());
+        self.file = None;
+        fs::rename(&self.path, self.rotated_path())?;
+        self.num_bytes = 0;
+        Ok(())
+    }
+
+    fn write_events(&mut self, events: &[InputLogEvent]) -> io::Result<()> {
+        for event in events.iter() {
+            let mut line = encode_event(event);
+            line.push('\n');
+            self.open()?;
+            // A single line larger than the maximum still gets a file of
+            // its own rather than being dropped
+            if self.num_bytes > 0
+                && self.num_bytes + line.len() as u64 > self.max_bytes
+            {
+                self.rotate()?;
+            }
+            self.open()?.write_all(line.as_bytes())?;
+            self.num_bytes += line.len() as u64;
+        }
+        self.open()?.flush()
+    }
+}
+
+#[async_trait]
+impl Uploader for FileUploader {
+    fn group_events(
+        &self,
+        events: Vec<InputLogEvent>,
+    ) -> Vec<Vec<InputLogEvent>> {
+        if events.is_empty() {
+            Vec::new()
+        } else {
+            vec![events]
+        }
+    }
+
+    async fn upload(
+        &mut self,
+        events: Vec<InputLogEvent>,
+    ) -> Result<(), Undelivered> {
+        match self.write_events(&events) {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                warn!("failed to write to {}: {}", self.path.display(), err);
+                // The file may have been rotated or replaced, so reopen it
+                // next time
+                self.file = None;
+                Err(Undelivered::new(UploadError::Other, events))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("jtc-file-{}-{}", std::process::id(), name))
+            .join("events.jsonl")
+    }
+
+    fn create_event(message: &str) -> InputLogEvent {
+        InputLogEvent::builder()
+            .message(message)
+            .timestamp(1_500_000_000_000)
+            .build()
+    }
+
+    #[tokio::test]
+    async fn test_writes_json_lines() {
+        let path = temp_path("write");
+        let mut uploader = FileUploader::new(path.clone(), 1024);
+        let events = vec![create_event("ev1"), create_event("ev2")];
+        assert!(uploader.upload(events).await.is_ok());
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "{\"message\":\"ev1\",\"timestamp\":1500000000000}\n\
+             {\"message\":\"ev2\",\"timestamp\":1500000000000}\n"
+        );
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rotates_at_max_bytes() {
+        let path = temp_path("rotate");
+        // Each line is 44 bytes, so two fit exactly
+        let mut uploader = FileUploader::new(path.clone(), 88);
+        let events = vec![
+            create_event("ev1"),
+            create_event("ev2"),
+            create_event("ev3"),
+        ];
+        assert!(uploader.upload(events).await.is_ok());
+        let rotated = fs::read_to_string(uploader.rotated_path()).unwrap();
+        assert_eq!(rotated.lines().count(), 2);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+        assert_eq!(uploader.num_bytes, 44);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_group_events_is_one_group() {
+        let uploader = FileUploader::new(temp_path("group"), 1024);
+        let groups = uploader.group_events(vec![create_event("ev1")]);
+        assert_eq!(groups.len(), 1);
+        assert!(uploader.group_events(Vec::new()).is_empty());
+    }
+}
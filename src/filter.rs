@@ -0,0 +1,343 @@
+use crate::configuration::{Configuration, Source};
+use regex::Regex;
+use systemd::{id128::Id128, journal::JournalRecord, Journal};
+
+/// The special `UNITS` value that allows kernel messages, which have no
+/// `_SYSTEMD_UNIT`.
+const KERNEL_UNIT: &str = "kernel";
+
+/// The `_TRANSPORT` values of records written by userspace programs,
+/// through the native protocol, syslog or a service's stdout. The others
+/// are `kernel`, `audit` and `driver`, which journald uses for its own
+/// messages.
+const USER_TRANSPORTS: &[&str] = &["journal", "syslog", "stdout"];
+
+/// The name this service runs under. The kernel keeps only the first 15
+/// bytes of a process name in `_COMM`.
+const OWN_NAME: &str = env!("CARGO_PKG_NAME");
+const MAX_COMM_LEN: usize = 15;
+
+/// Decides which journal records are forwarded.
+pub struct RecordFilter {
+    units: Vec<String>,
+    allow_kernel: bool,
+    source: Source,
+    include: Option<Regex>,
+    exclude: Option<Regex>,
+    /// Whether this service's own records are skipped rather than
+    /// forwarded like any others.
+    skip_own_records: bool,
+    /// The process ID and boot ID of this service. A `_PID` only
+    /// identifies it in the live local journal, and only in the current
+    /// boot, so this is unset when reading other journals.
+    own_process: Option<(String, String)>,
+    own_unit: Option<String>,
+}
+
+impl RecordFilter {
+    pub fn new(conf: &Configuration) -> RecordFilter {
+        RecordFilter {
+            units: conf
+                .units
+                .iter()
+                .filter(|unit| unit.as_str() != KERNEL_UNIT)
+                .cloned()
+                .collect(),
+            allow_kernel: conf.units.iter().any(|unit| unit == KERNEL_UNIT),
+            source: conf.source,
+            include: conf.include_pattern.clone(),
+            exclude: conf.exclude_pattern.clone(),
+            skip_own_records: !conf.forward_own_logs,
+            own_process: if conf.forward_own_logs
+                || conf.journal_directory.is_some()
+                || !conf.journal_sources.is_empty()
+            {
+                None
+            } else {
+                Id128::from_boot().ok().map(|boot_id| {
+                    (std::process::id().to_string(), boot_id.to_string())
+                })
+            },
+            own_unit: conf.own_unit.clone().filter(|_| !conf.forward_own_logs),
+        }
+    }
+
+    fn is_unit_filter_active(&self) -> bool {
+        !self.units.is_empty() || self.allow_kernel
+    }
+
+    /// Add journal matches so that the journal itself skips records that
+    /// would be rejected by `accepts`.
+    pub fn add_journal_matches(
+        &self,
+        journal: &mut Journal,
+    ) -> systemd::Result<()> {
+        if !self.is_unit_filter_active() {
+            return self.add_source_matches(journal);
+        }
+        // The unit matches are ORed with the kernel match, and ANDing the
+        // source with each of them can't be expressed with a single
+        // _TRANSPORT match, so with both filters the source is only
+        // checked by `accepts`
+        // Matches on the same field are ORed together by the journal
+        for unit in self.units.iter() {
+            journal.match_add("_SYSTEMD_UNIT", unit.as_str())?;
+        }
+        if self.allow_kernel {
+            if !self.units.is_empty() {
+                journal.match_or()?;
+            }
+            journal.match_add("_TRANSPORT", KERNEL_UNIT)?;
+        }
+        Ok(())
+    }
+
+    fn add_source_matches(&self, journal: &mut Journal) -> systemd::Result<()> {
+        let transports = match self.source {
+            Source::All => return Ok(()),
+            Source::Kernel => &[KERNEL_UNIT],
+            Source::User => USER_TRANSPORTS,
+        };
+        for transport in transports.iter() {
+            journal.match_add("_TRANSPORT", *transport)?;
+        }
+        Ok(())
+    }
+
+    /// Check whether a record should be forwarded.
+    pub fn accepts(&self, record: &JournalRecord) -> bool {
+        !self.is_own_record(record)
+            && self.accepts_source(record)
+            && self.accepts_unit(record)
+            && self.accepts_message(record)
+    }
+
+    /// Whether a record was written by this service, whose diagnostics
+    /// usually end up in the journal too. Forwarding them would log every
+    /// upload again, and with `DEBUG` each record would produce more.
+    /// Records of earlier runs are recognized by name or by the service
+    /// unit they were written by.
+    fn is_own_record(&self, record: &JournalRecord) -> bool {
+        if !self.skip_own_records {
+            return false;
+        }
+        self.own_process.as_ref().is_some_and(|(pid, boot_id)| {
+            record.get("_PID") == Some(pid)
+                && record.get("_BOOT_ID") == Some(boot_id)
+        }) || (self.own_unit.is_some()
+            && record.get("_SYSTEMD_UNIT") == self.own_unit.as_ref())
+            || record.get("SYSLOG_IDENTIFIER").map(String::as_str)
+                == Some(OWN_NAME)
+            || record.get("_COMM").map(String::as_str)
+                == Some(&OWN_NAME[..MAX_COMM_LEN])
+    }
+
+    fn accepts_source(&self, record: &JournalRecord) -> bool {
+        let transport = record.get("_TRANSPORT").map(String::as_str);
+        match self.source {
+            Source::All => true,
+            Source::Kernel => transport == Some(KERNEL_UNIT),
+            Source::User => transport
+                .is_some_and(|transport| USER_TRANSPORTS.contains(&transport)),
+        }
+    }
+
+    /// A message must match the include pattern, if there is one, and
+    /// must not match the exclude pattern.
+    fn accepts_message(&self, record: &JournalRecord) -> bool {
+        let message = record.get("MESSAGE").map(String::as_str).unwrap_or("");
+        if let Some(include) = &self.include {
+            if !include.is_match(message) {
+                return false;
+            }
+        }
+        match &self.exclude {
+            Some(exclude) => !exclude.is_match(message),
+            None => true,
+        }
+    }
+
+    fn accepts_unit(&self, record: &JournalRecord) -> bool {
+        if !self.is_unit_filter_active() {
+            return true;
+        }
+        match record.get("_SYSTEMD_UNIT") {
+            Some(unit) => self.units.contains(unit),
+            None => {
+                self.allow_kernel
+                    && record.get("_TRANSPORT").map(String::as_str)
+                        == Some(KERNEL_UNIT)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_filter(units: &[&str]) -> RecordFilter {
+        let mut conf = Configuration::for_tests();
+        conf.units = units.iter().map(|u| u.to_string()).collect();
+        RecordFilter::new(&conf)
+    }
+
+    fn create_record(fields: &[(&str, &str)]) -> JournalRecord {
+        fields
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_no_units_accepts_everything() {
+        let filter = create_filter(&[]);
+        assert!(filter.accepts(&create_record(&[("MESSAGE", "hi")])));
+        assert!(filter
+            .accepts(&create_record(&[("_SYSTEMD_UNIT", "sshd.service")])));
+    }
+
+    #[test]
+    fn test_unit_allow_list() {
+        let filter = create_filter(&["sshd.service", "nginx.service"]);
+        assert!(filter
+            .accepts(&create_record(&[("_SYSTEMD_UNIT", "nginx.service")])));
+        assert!(!filter
+            .accepts(&create_record(&[("_SYSTEMD_UNIT", "cron.service")])));
+        assert!(!filter.accepts(&create_record(&[("_TRANSPORT", "kernel")])));
+    }
+
+    #[test]
+    fn test_exclude_pattern() {
+        let mut conf = Configuration::for_tests();
+        conf.exclude_pattern = Some(Regex::new("^GET /health").unwrap());
+        let filter = RecordFilter::new(&conf);
+        assert!(
+            !filter.accepts(&create_record(&[("MESSAGE", "GET /health 200")]))
+        );
+        assert!(filter.accepts(&create_record(&[("MESSAGE", "GET / 200")])));
+        assert!(filter.accepts(&create_record(&[("_COMM", "sshd")])));
+    }
+
+    #[test]
+    fn test_include_pattern() {
+        let mut conf = Configuration::for_tests();
+        conf.include_pattern = Some(Regex::new("(?i)error").unwrap());
+        let filter = RecordFilter::new(&conf);
+        assert!(filter.accepts(&create_record(&[("MESSAGE", "an ERROR")])));
+        assert!(!filter.accepts(&create_record(&[("MESSAGE", "all good")])));
+    }
+
+    #[test]
+    fn test_exclude_takes_precedence_over_include() {
+        let mut conf = Configuration::for_tests();
+        conf.include_pattern = Some(Regex::new("error").unwrap());
+        conf.exclude_pattern = Some(Regex::new("expected").unwrap());
+        let filter = RecordFilter::new(&conf);
+        assert!(filter.accepts(&create_record(&[("MESSAGE", "disk error")])));
+        assert!(
+            !filter.accepts(&create_record(&[("MESSAGE", "expected error")]))
+        );
+        assert!(!filter.accepts(&create_record(&[("MESSAGE", "expected")])));
+    }
+
+    #[test]
+    fn test_own_records_skipped() {
+        let own_pid = std::process::id().to_string();
+        let boot_id = Id128::from_boot().unwrap().to_string();
+        let records = [
+            create_record(&[
+                ("MESSAGE", "uploading"),
+                ("_PID", &own_pid),
+                ("_BOOT_ID", &boot_id),
+            ]),
+            create_record(&[("_COMM", "journald-to-clo"), ("_PID", "1")]),
+            create_record(&[("SYSLOG_IDENTIFIER", "journald-to-cloudwatch")]),
+        ];
+        let other = create_record(&[("_COMM", "journald"), ("_PID", "1")]);
+
+        let mut conf = Configuration::for_tests();
+        let filter = RecordFilter::new(&conf);
+        assert!(records.iter().all(|record| !filter.accepts(record)));
+        assert!(filter.accepts(&other));
+
+        conf.forward_own_logs = true;
+        let filter = RecordFilter::new(&conf);
+        assert!(records.iter().all(|record| filter.accepts(record)));
+    }
+
+    #[test]
+    fn test_own_pid_only_matches_current_boot_of_local_journal() {
+        let own_pid = std::process::id().to_string();
+        let boot_id = Id128::from_boot().unwrap().to_string();
+        let own = create_record(&[("_PID", &own_pid), ("_BOOT_ID", &boot_id)]);
+        let earlier_boot =
+            create_record(&[("_PID", &own_pid), ("_BOOT_ID", "0123abcd")]);
+
+        let mut conf = Configuration::for_tests();
+        let filter = RecordFilter::new(&conf);
+        assert!(!filter.accepts(&own));
+        assert!(filter.accepts(&earlier_boot));
+
+        // Another machine's journal may have any process with this PID
+        conf.journal_directory = Some("/var/log/journal/remote".into());
+        let filter = RecordFilter::new(&conf);
+        assert!(filter.accepts(&own));
+    }
+
+    #[test]
+    fn test_own_unit_skipped() {
+        let own = create_record(&[
+            ("_SYSTEMD_UNIT", "cloudwatch-agent.service"),
+            ("_COMM", "sh"),
+        ]);
+        let other = create_record(&[("_SYSTEMD_UNIT", "sshd.service")]);
+
+        let mut conf = Configuration::for_tests();
+        let filter = RecordFilter::new(&conf);
+        assert!(filter.accepts(&own));
+
+        conf.own_unit = Some("cloudwatch-agent.service".to_string());
+        let filter = RecordFilter::new(&conf);
+        assert!(!filter.accepts(&own));
+        assert!(filter.accepts(&other));
+
+        conf.forward_own_logs = true;
+        let filter = RecordFilter::new(&conf);
+        assert!(filter.accepts(&own));
+    }
+
+    #[test]
+    fn test_source() {
+        let kernel = create_record(&[("_TRANSPORT", "kernel")]);
+        let service = create_record(&[
+            ("_TRANSPORT", "stdout"),
+            ("_SYSTEMD_UNIT", "sshd.service"),
+        ]);
+        let driver = create_record(&[("_TRANSPORT", "driver")]);
+
+        let mut conf = Configuration::for_tests();
+        conf.source = Source::Kernel;
+        let filter = RecordFilter::new(&conf);
+        assert!(filter.accepts(&kernel));
+        assert!(!filter.accepts(&service));
+        assert!(!filter.accepts(&driver));
+
+        conf.source = Source::User;
+        let filter = RecordFilter::new(&conf);
+        assert!(!filter.accepts(&kernel));
+        assert!(filter.accepts(&service));
+        assert!(!filter.accepts(&driver));
+
+        conf.units = vec!["cron.service".to_string()];
+        let filter = RecordFilter::new(&conf);
+        assert!(!filter.accepts(&service));
+    }
+
+    #[test]
+    fn test_kernel_unit() {
+        let filter = create_filter(&["sshd.service", "kernel"]);
+        assert!(filter.accepts(&create_record(&[("_TRANSPORT", "kernel")])));
+        assert!(!filter.accepts(&create_record(&[("_TRANSPORT", "stdout")])));
+    }
+}
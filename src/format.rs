@@ -0,0 +1,724 @@
+use crate::logfmt;
+use chrono::{SecondsFormat, TimeZone, Utc};
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use systemd::journal::JournalRecord;
+
+/// The message format used when `MESSAGE_FORMAT` is not set.
+pub const DEFAULT_MESSAGE_FORMAT: &str = "{comm}: {message}";
+
+/// What separates `{comm}` and `{message}` in the default format when
+/// `MESSAGE_PREFIX_SEPARATOR` is not set.
+pub const DEFAULT_PREFIX_SEPARATOR: &str = ": ";
+
+/// The default message format with another separator between the comm and
+/// the message.
+pub fn default_message_format(separator: &str) -> String {
+    format!("{{comm}}{}{{message}}", separator)
+}
+
+/// What `{comm}` is replaced with when `COMM_FALLBACK` is not set.
+pub const DEFAULT_COMM_FALLBACK: &str = "unknown";
+
+/// The level of records without a priority when `DEFAULT_LEVEL` is not
+/// set.
+pub const DEFAULT_LEVEL: &str = "INFO";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Placeholder {
+    Comm,
+    Message,
+    Pid,
+    Unit,
+    Hostname,
+    Priority,
+    Cmdline,
+    Level,
+    BootId,
+}
+
+impl Placeholder {
+    fn parse(name: &str) -> Option<Placeholder> {
+        match name {
+            "comm" => Some(Placeholder::Comm),
+            "message" => Some(Placeholder::Message),
+            "pid" => Some(Placeholder::Pid),
+            "unit" => Some(Placeholder::Unit),
+            "hostname" => Some(Placeholder::Hostname),
+            "priority" => Some(Placeholder::Priority),
+            "cmdline" => Some(Placeholder::Cmdline),
+            "level" => Some(Placeholder::Level),
+            "boot_id" => Some(Placeholder::BootId),
+            _ => None,
+        }
+    }
+
+    fn field(&self) -> &'static str {
+        match self {
+            Placeholder::Comm => "_COMM",
+            Placeholder::Message => "MESSAGE",
+            Placeholder::Pid => "_PID",
+            Placeholder::Unit => "_SYSTEMD_UNIT",
+            Placeholder::Hostname => "_HOSTNAME",
+            Placeholder::Priority => "PRIORITY",
+            Placeholder::Cmdline => "_CMDLINE",
+            Placeholder::Level => "PRIORITY",
+            Placeholder::BootId => "_BOOT_ID",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Field(Placeholder),
+    /// The container fields the record has, as `key=value ` pairs.
+    ContainerFields,
+}
+
+/// The fields Docker and Podman add to the records of containers that log
+/// to the journal, and the keys they are written under.
+const CONTAINER_FIELDS: &[(&str, &str)] = &[
+    ("container_name", "CONTAINER_NAME"),
+    ("container_id", "CONTAINER_ID"),
+    ("image_name", "IMAGE_NAME"),
+];
+
+/// A parsed message template such as `{comm}: {message}`.
+///
+/// Known placeholders are replaced with the corresponding journal field,
+/// or an empty string if the record doesn't have it. Unknown placeholders
+/// are left as they are. `{hostname}` falls back to the fallback hostname,
+/// if one is set, for records without `_HOSTNAME`. `{comm}` is the
+/// `SYSLOG_IDENTIFIER` of the record, then its `_COMM`, then the fallback
+/// comm. `{level}` is the priority as a level name, or the fallback level.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MessageFormat {
+    parts: Vec<Part>,
+    fallback_hostname: Option<String>,
+    fallback_comm: String,
+    fallback_level: String,
+}
+
+impl MessageFormat {
+    pub fn parse(template: &str) -> MessageFormat {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            literal.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            match after.find('}') {
+                Some(end) => match Placeholder::parse(&after[..end]) {
+                    Some(placeholder) => {
+                        if !literal.is_empty() {
+                            parts.push(Part::Literal(literal.clone()));
+                            literal.clear();
+                        }
+                        parts.push(Part::Field(placeholder));
+                        rest = &after[end + 1..];
+                    }
+                    None => {
+                        literal.push('{');
+                        rest = after;
+                    }
+                },
+                None => {
+                    literal.push('{');
+                    rest = after;
+                }
+            }
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        MessageFormat {
+            parts,
+            fallback_hostname: None,
+            fallback_comm: DEFAULT_COMM_FALLBACK.to_string(),
+            fallback_level: DEFAULT_LEVEL.to_string(),
+        }
+    }
+
+    pub fn with_fallback_level(mut self, level: String) -> MessageFormat {
+        self.fallback_level = level;
+        self
+    }
+
+    pub fn with_fallback_comm(mut self, comm: String) -> MessageFormat {
+        self.fallback_comm = comm;
+        self
+    }
+
+    pub fn with_fallback_hostname(mut self, hostname: String) -> MessageFormat {
+        self.fallback_hostname = Some(hostname);
+        self
+    }
+
+    /// Prefix the format with the hostname, unless it already has it.
+    pub fn with_hostname_prefix(mut self) -> MessageFormat {
+        let placeholder = Part::Field(Placeholder::Hostname);
+        if !self.parts.contains(&placeholder) {
+            self.parts.insert(0, Part::Literal(" ".to_string()));
+            self.parts.insert(0, placeholder);
+        }
+        self
+    }
+
+    /// Prefix the format with the container fields of the record. Records
+    /// that weren't written by a container get no prefix.
+    pub fn with_container_prefix(mut self) -> MessageFormat {
+        if !self.parts.contains(&Part::ContainerFields) {
+            self.parts.insert(0, Part::ContainerFields);
+        }
+        self
+    }
+
+    pub fn format(&self, record: &JournalRecord) -> String {
+        let mut message = String::new();
+        for part in self.parts.iter() {
+            match part {
+                Part::Literal(literal) => message.push_str(literal),
+                Part::ContainerFields => {
+                    for (key, field) in CONTAINER_FIELDS.iter() {
+                        if let Some(value) = record.get(*field) {
+                            message.push_str(&format!("{}={} ", key, value));
+                        }
+                    }
+                }
+                Part::Field(Placeholder::Comm) => message
+                    .push_str(get_record_comm(record, &self.fallback_comm)),
+                Part::Field(Placeholder::Hostname) => {
+                    let hostname = record
+                        .get(Placeholder::Hostname.field())
+                        .or(self.fallback_hostname.as_ref());
+                    if let Some(hostname) = hostname {
+                        message.push_str(hostname);
+                    }
+                }
+                Part::Field(Placeholder::Cmdline) => {
+                    message.push_str(&get_record_cmdline(record))
+                }
+                Part::Field(Placeholder::Level) => message
+                    .push_str(get_record_level(record, &self.fallback_level)),
+                Part::Field(placeholder) => {
+                    if let Some(value) = record.get(placeholder.field()) {
+                        message.push_str(value);
+                    }
+                }
+            }
+        }
+        message
+    }
+}
+
+impl Default for MessageFormat {
+    fn default() -> MessageFormat {
+        MessageFormat::parse(DEFAULT_MESSAGE_FORMAT)
+    }
+}
+
+/// The journal fields included in JSON encoded messages, and the keys they
+/// are written under.
+const JSON_FIELDS: &[(&str, &str)] = &[
+    ("message", "MESSAGE"),
+    ("unit", "_SYSTEMD_UNIT"),
+    ("comm", "_COMM"),
+    ("pid", "_PID"),
+    ("priority", "PRIORITY"),
+    ("hostname", "_HOSTNAME"),
+    ("cmdline", "_CMDLINE"),
+    ("repeat_count", crate::repeat::REPEAT_COUNT_FIELD),
+];
+
+/// How records are encoded as JSON objects: a set of their fields, their
+/// level, and optionally the logfmt pairs of their message. Fields the
+/// record doesn't have are left out, and pairs never replace the record's
+/// fields or level.
+///
+/// The journal library has already decoded field values lossily, so
+/// binary content shows up as replacement characters rather than making
+/// the encoding fail.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JsonFormat {
+    /// The keys of the object and the journal fields they are read from.
+    fields: Vec<(String, String)>,
+    fallback_level: String,
+    extract_logfmt: bool,
+}
+
+impl JsonFormat {
+    pub fn new() -> JsonFormat {
+        JsonFormat {
+            fields: JSON_FIELDS
+                .iter()
+                .map(|(key, field)| (key.to_string(), field.to_string()))
+                .collect(),
+            fallback_level: DEFAULT_LEVEL.to_string(),
+            extract_logfmt: false,
+        }
+    }
+
+    /// Include exactly these journal fields instead of the default ones.
+    /// The default fields keep their usual keys, so `_SYSTEMD_UNIT` is
+    /// still `unit`; any other field is written under its own name.
+    pub fn with_fields(mut self, fields: &[String]) -> JsonFormat {
+        self.fields = fields
+            .iter()
+            .map(|field| {
+                let key = JSON_FIELDS
+                    .iter()
+                    .find(|(_, known)| known == field)
+                    .map_or(field.as_str(), |(key, _)| key);
+                (key.to_string(), field.clone())
+            })
+            .collect();
+        self
+    }
+
+    pub fn with_container_fields(mut self) -> JsonFormat {
+        self.fields.extend(
+            CONTAINER_FIELDS
+                .iter()
+                .map(|(key, field)| (key.to_string(), field.to_string())),
+        );
+        self
+    }
+
+    /// Include the `_BOOT_ID` of records as `boot_id`, unless the fields
+    /// already include it.
+    pub fn with_boot_id(mut self) -> JsonFormat {
+        if !self.fields.iter().any(|(_, field)| field == "_BOOT_ID") {
+            self.fields
+                .push(("boot_id".to_string(), "_BOOT_ID".to_string()));
+        }
+        self
+    }
+
+    pub fn with_fallback_level(mut self, level: String) -> JsonFormat {
+        self.fallback_level = level;
+        self
+    }
+
+    pub fn with_logfmt(mut self) -> JsonFormat {
+        self.extract_logfmt = true;
+        self
+    }
+
+    /// Encode a record as a compact JSON object.
+    pub fn encode(&self, record: &JournalRecord) -> String {
+        let mut object = Map::new();
+        for (key, field) in self.fields.iter() {
+            if let Some(value) = record.get(field) {
+                object.insert(key.clone(), Value::String(value.clone()));
+            }
+        }
+        let level = get_record_level(record, &self.fallback_level);
+        object.insert("level".to_string(), Value::String(level.to_string()));
+        let message = record.get("MESSAGE").filter(|_| self.extract_logfmt);
+        if let Some(message) = message {
+            for (key, value) in logfmt::parse(message) {
+                object.entry(key).or_insert(Value::String(value));
+            }
+        }
+        Value::Object(object).to_string()
+    }
+}
+
+impl Default for JsonFormat {
+    fn default() -> JsonFormat {
+        JsonFormat::new()
+    }
+}
+
+/// What a truncated message ends with.
+pub const TRUNCATED_MARKER: &str = "…[truncated]";
+
+/// Keep the first `max_bytes` bytes of a message, cut back to a character
+/// boundary, followed by `TRUNCATED_MARKER`. Messages that fit are
+/// returned as they are.
+pub fn truncate_message<'a>(
+    message: &'a str,
+    max_bytes: usize,
+) -> Cow<'a, str> {
+    if message.len() <= max_bytes {
+        return Cow::Borrowed(message);
+    }
+    let mut end = max_bytes;
+    while !message.is_char_boundary(end) {
+        end -= 1;
+    }
+    Cow::Owned(format!("{}{}", &message[..end], TRUNCATED_MARKER))
+}
+
+/// Prefix a message with its time in UTC as RFC 3339 with milliseconds,
+/// as in `2017-07-14T02:40:00.123Z disk failed`. A time that can't be
+/// represented leaves the message as it is.
+pub fn prefix_timestamp(message: String, timestamp_millis: i64) -> String {
+    match Utc.timestamp_millis_opt(timestamp_millis).single() {
+        Some(time) => format!(
+            "{} {}",
+            time.to_rfc3339_opts(SecondsFormat::Millis, true),
+            message
+        ),
+        None => message,
+    }
+}
+
+/// Encode all of the record's fields as a JSON object, for records that
+/// have no message of their own. Fields starting with `__` are address
+/// fields of the journal entry rather than part of the record.
+pub fn encode_fields(record: &JournalRecord) -> String {
+    let object: Map<String, Value> = record
+        .iter()
+        .filter(|(field, _)| !field.starts_with("__"))
+        .map(|(field, value)| (field.clone(), Value::String(value.clone())))
+        .collect();
+    Value::Object(object).to_string()
+}
+
+/// The hostname of this machine, used for records that don't say which
+/// host they came from.
+pub fn get_local_hostname() -> String {
+    gethostname::gethostname().to_string_lossy().into_owned()
+}
+
+/// The command line of the process that wrote the record, or its
+/// executable if the command line wasn't recorded.
+fn get_record_cmdline(record: &JournalRecord) -> String {
+    record
+        .get("_CMDLINE")
+        .or_else(|| record.get("_EXE"))
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Map a syslog priority to a level name. Emergencies, alerts and
+/// critical conditions all count as errors, and notices as info.
+pub fn get_priority_level(priority: &str) -> Option<&'static str> {
+    match priority.trim() {
+        "0" | "1" | "2" | "3" => Some("ERROR"),
+        "4" => Some("WARN"),
+        "5" | "6" => Some("INFO"),
+        "7" => Some("DEBUG"),
+        _ => None,
+    }
+}
+
+fn get_record_level<'a>(record: &JournalRecord, fallback: &'a str) -> &'a str {
+    record
+        .get("PRIORITY")
+        .and_then(|priority| get_priority_level(priority))
+        .unwrap_or(fallback)
+}
+
+/// A name for the program that wrote the record. `SYSLOG_IDENTIFIER` is
+/// usually what the program calls itself, while `_COMM` is truncated by
+/// the kernel to 15 characters.
+fn get_record_comm<'a>(
+    record: &'a JournalRecord,
+    fallback: &'a str,
+) -> &'a str {
+    record
+        .get("SYSLOG_IDENTIFIER")
+        .or_else(|| record.get("_COMM"))
+        .map(|comm| comm.as_str())
+        .unwrap_or(fallback)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_record(fields: &[(&str, &str)]) -> JournalRecord {
+        fields
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_default_format() {
+        let record = create_record(&[("_COMM", "sshd"), ("MESSAGE", "hi")]);
+        assert_eq!(MessageFormat::default().format(&record), "sshd: hi");
+        let record = create_record(&[("MESSAGE", "hi")]);
+        assert_eq!(MessageFormat::default().format(&record), "unknown: hi");
+    }
+
+    #[test]
+    fn test_prefix_separator() {
+        assert_eq!(
+            default_message_format(DEFAULT_PREFIX_SEPARATOR),
+            DEFAULT_MESSAGE_FORMAT
+        );
+        let record = create_record(&[("_COMM", "sshd"), ("MESSAGE", "hi")]);
+        for (separator, message) in
+            [("\t", "sshd\thi"), (" | ", "sshd | hi"), ("", "sshdhi")]
+        {
+            let format =
+                MessageFormat::parse(&default_message_format(separator));
+            assert_eq!(format.format(&record), message);
+        }
+    }
+
+    #[test]
+    fn test_comm_fallbacks() {
+        let format = MessageFormat::default();
+        let record = create_record(&[
+            ("SYSLOG_IDENTIFIER", "nginx"),
+            ("_COMM", "nginx: worker"),
+            ("MESSAGE", "hi"),
+        ]);
+        assert_eq!(format.format(&record), "nginx: hi");
+        let record = create_record(&[("_COMM", "sshd"), ("MESSAGE", "hi")]);
+        assert_eq!(format.format(&record), "sshd: hi");
+        let record = create_record(&[("MESSAGE", "hi")]);
+        assert_eq!(format.format(&record), "unknown: hi");
+        let format = format.with_fallback_comm("-".to_string());
+        assert_eq!(format.format(&record), "-: hi");
+    }
+
+    #[test]
+    fn test_all_placeholders() {
+        let record = create_record(&[
+            ("_COMM", "sshd"),
+            ("MESSAGE", "hi"),
+            ("_PID", "42"),
+            ("_SYSTEMD_UNIT", "sshd.service"),
+            ("_HOSTNAME", "box"),
+            ("PRIORITY", "6"),
+        ]);
+        let format = MessageFormat::parse(
+            "[{priority}] {hostname} {unit} {comm}[{pid}]: {message}",
+        );
+        assert_eq!(format.format(&record), "[6] box sshd.service sshd[42]: hi");
+    }
+
+    #[test]
+    fn test_missing_fields_are_empty() {
+        let record = create_record(&[("MESSAGE", "hi")]);
+        let format = MessageFormat::parse("{unit}|{pid}|{message}");
+        assert_eq!(format.format(&record), "||hi");
+    }
+
+    #[test]
+    fn test_unknown_placeholders_are_literal() {
+        let record = create_record(&[("MESSAGE", "hi")]);
+        let format = MessageFormat::parse("{nope} {message} {unclosed");
+        assert_eq!(format.format(&record), "{nope} hi {unclosed");
+        let format = MessageFormat::parse("{{message}}");
+        assert_eq!(format.format(&record), "{hi}");
+    }
+
+    #[test]
+    fn test_pid_and_cmdline() {
+        let format = MessageFormat::parse("{pid}|{cmdline}|{message}");
+        let record = create_record(&[
+            ("MESSAGE", "hi"),
+            ("_PID", "42"),
+            ("_CMDLINE", "/usr/sbin/sshd -D"),
+            ("_EXE", "/usr/sbin/sshd"),
+        ]);
+        assert_eq!(format.format(&record), "42|/usr/sbin/sshd -D|hi");
+        let record = create_record(&[("MESSAGE", "hi"), ("_EXE", "/bin/sh")]);
+        assert_eq!(format.format(&record), "|/bin/sh|hi");
+        let record = create_record(&[("MESSAGE", "hi")]);
+        assert_eq!(format.format(&record), "||hi");
+    }
+
+    #[test]
+    fn test_hostname_fallback() {
+        let format = MessageFormat::parse("{hostname}: {message}")
+            .with_fallback_hostname("local".to_string());
+        let record = create_record(&[("MESSAGE", "hi")]);
+        assert_eq!(format.format(&record), "local: hi");
+        let record = create_record(&[("MESSAGE", "hi"), ("_HOSTNAME", "box")]);
+        assert_eq!(format.format(&record), "box: hi");
+    }
+
+    #[test]
+    fn test_hostname_prefix() {
+        let record = create_record(&[
+            ("_COMM", "sshd"),
+            ("MESSAGE", "hi"),
+            ("_HOSTNAME", "box"),
+        ]);
+        let format = MessageFormat::default().with_hostname_prefix();
+        assert_eq!(format.format(&record), "box sshd: hi");
+        let format = MessageFormat::parse("{message} on {hostname}")
+            .with_hostname_prefix();
+        assert_eq!(format.format(&record), "hi on box");
+    }
+
+    #[test]
+    fn test_priority_levels() {
+        let levels: Vec<_> = (0..8)
+            .map(|priority| get_priority_level(&priority.to_string()))
+            .collect();
+        assert_eq!(
+            levels,
+            vec![
+                Some("ERROR"),
+                Some("ERROR"),
+                Some("ERROR"),
+                Some("ERROR"),
+                Some("WARN"),
+                Some("INFO"),
+                Some("INFO"),
+                Some("DEBUG"),
+            ]
+        );
+        assert_eq!(get_priority_level("8"), None);
+        assert_eq!(get_priority_level("warning"), None);
+    }
+
+    #[test]
+    fn test_level_placeholder() {
+        let format = MessageFormat::parse("[{level}] {message}");
+        let record = create_record(&[("PRIORITY", "4"), ("MESSAGE", "hi")]);
+        assert_eq!(format.format(&record), "[WARN] hi");
+        let record = create_record(&[("MESSAGE", "hi")]);
+        assert_eq!(format.format(&record), "[INFO] hi");
+        let format = format.with_fallback_level("NOTICE".to_string());
+        assert_eq!(format.format(&record), "[NOTICE] hi");
+        let json_format =
+            JsonFormat::new().with_fallback_level("NOTICE".to_string());
+        assert_eq!(
+            json_format.encode(&record),
+            r#"{"level":"NOTICE","message":"hi"}"#
+        );
+    }
+
+    #[test]
+    fn test_encode_json() {
+        let record = create_record(&[
+            ("_COMM", "sshd"),
+            ("MESSAGE", "say \"hi\"\n"),
+            ("_PID", "42"),
+            ("_BOOT_ID", "ignored"),
+        ]);
+        assert_eq!(
+            JsonFormat::new().encode(&record),
+            r#"{"comm":"sshd","level":"INFO","message":"say \"hi\"\n","#
+                .to_string()
+                + r#""pid":"42"}"#
+        );
+    }
+
+    #[test]
+    fn test_encode_json_selected_fields() {
+        let record = create_record(&[
+            ("_COMM", "sshd"),
+            ("MESSAGE", "hi"),
+            ("_PID", "42"),
+            ("_SYSTEMD_UNIT", "sshd.service"),
+            ("_BOOT_ID", "b1"),
+        ]);
+        let fields: Vec<String> =
+            ["MESSAGE", "_SYSTEMD_UNIT", "_BOOT_ID", "CODE_LINE"]
+                .iter()
+                .map(|field| field.to_string())
+                .collect();
+        assert_eq!(
+            JsonFormat::new().with_fields(&fields).encode(&record),
+            r#"{"_BOOT_ID":"b1","level":"INFO","message":"hi","#.to_string()
+                + r#""unit":"sshd.service"}"#
+        );
+    }
+
+    #[test]
+    fn test_encode_json_logfmt() {
+        let record = create_record(&[
+            (
+                "MESSAGE",
+                "level=debug msg=\"cache miss\" key=user:42 pid=7",
+            ),
+            ("_PID", "42"),
+        ]);
+        assert_eq!(
+            JsonFormat::new().with_logfmt().encode(&record),
+            r#"{"key":"user:42","level":"INFO","#.to_string()
+                + r#""message":"level=debug msg=\"cache miss\" key=user:42 "#
+                + r#"pid=7","msg":"cache miss","pid":"42"}"#
+        );
+        assert!(!JsonFormat::new().encode(&record).contains(r#""msg""#));
+    }
+
+    #[test]
+    fn test_container_fields() {
+        let record = create_record(&[
+            ("_COMM", "dockerd"),
+            ("MESSAGE", "hi"),
+            ("CONTAINER_NAME", "web"),
+            ("CONTAINER_ID", "3f4e5a6b7c8d"),
+        ]);
+        let format = MessageFormat::default().with_container_prefix();
+        let json_format = JsonFormat::new().with_container_fields();
+        assert_eq!(
+            format.format(&record),
+            "container_name=web container_id=3f4e5a6b7c8d dockerd: hi"
+        );
+        assert_eq!(
+            json_format.encode(&record),
+            r#"{"comm":"dockerd","container_id":"3f4e5a6b7c8d","#.to_string()
+                + r#""container_name":"web","level":"INFO","message":"hi"}"#
+        );
+        assert!(!JsonFormat::new().encode(&record).contains("container"));
+
+        let record = create_record(&[("_COMM", "sshd"), ("MESSAGE", "hi")]);
+        assert_eq!(format.format(&record), "sshd: hi");
+        assert_eq!(
+            json_format.encode(&record),
+            r#"{"comm":"sshd","level":"INFO","message":"hi"}"#
+        );
+    }
+
+    #[test]
+    fn test_encode_json_binary_content() {
+        let mut record = JournalRecord::new();
+        record.insert(
+            "MESSAGE".to_string(),
+            String::from_utf8_lossy(&[0x66, 0xff, 0x00, 0x6f]).into(),
+        );
+        let encoded = JsonFormat::new().encode(&record);
+        let decoded: Value = serde_json::from_str(&encoded).unwrap();
+        assert_eq!(decoded["message"], "f\u{fffd}\u{0}o");
+    }
+
+    #[test]
+    fn test_prefix_timestamp() {
+        assert_eq!(
+            prefix_timestamp("disk failed".to_string(), 1_500_000_000_123),
+            "2017-07-14T02:40:00.123Z disk failed"
+        );
+        assert_eq!(
+            prefix_timestamp(String::new(), 0),
+            "1970-01-01T00:00:00.000Z "
+        );
+        assert_eq!(prefix_timestamp("hello".to_string(), i64::MAX), "hello");
+    }
+
+    #[test]
+    fn test_truncate_message_at_limit() {
+        let message = "x".repeat(10);
+        assert_eq!(truncate_message(&message, 10), Cow::Borrowed("xxxxxxxxxx"));
+        assert_eq!(
+            truncate_message(&"x".repeat(11), 10),
+            format!("{}{}", message, TRUNCATED_MARKER)
+        );
+        assert_eq!(truncate_message("", 0), "");
+    }
+
+    #[test]
+    fn test_truncate_message_respects_char_boundaries() {
+        // "é" takes two bytes, so a limit of 4 falls inside the third one
+        let message = "ééé";
+        assert_eq!(truncate_message(message, 6), "ééé");
+        assert_eq!(
+            truncate_message(message, 5),
+            format!("éé{}", TRUNCATED_MARKER)
+        );
+        assert_eq!(truncate_message(message, 1), TRUNCATED_MARKER);
+    }
+}
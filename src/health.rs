@@ -0,0 +1,140 @@
+use crate::metrics::{read_request_path, write_response, Metrics};
+use chrono::Utc;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{info, warn};
+
+/// How long uploads may keep failing after the last success before the
+/// service is reported unhealthy.
+const MAX_FAILING_SECS: i64 = 5 * 60;
+
+/// Ready once the journal is open and an upload has succeeded.
+fn is_ready(metrics: &Metrics) -> bool {
+    metrics.journal_open.load(Ordering::Relaxed)
+        && metrics.last_upload_timestamp.load(Ordering::Relaxed) > 0
+}
+
+/// Healthy while ready, unless uploads have been failing for longer than
+/// `MAX_FAILING_SECS`. A quiet host that has nothing to upload stays
+/// healthy.
+fn is_healthy(metrics: &Metrics, now: i64) -> bool {
+    let last_success = metrics.last_upload_timestamp.load(Ordering::Relaxed);
+    let last_failure = metrics
+        .last_upload_failure_timestamp
+        .load(Ordering::Relaxed);
+    is_ready(metrics)
+        && (last_failure <= last_success
+            || now - last_success <= MAX_FAILING_SECS)
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    metrics: &Metrics,
+) -> std::io::Result<()> {
+    let ok = match read_request_path(&mut stream).await.as_deref() {
+        Some("/health") => is_healthy(metrics, Utc::now().timestamp()),
+        Some("/ready") => is_ready(metrics),
+        _ => {
+            return write_response(
+                &mut stream,
+                "404 Not Found",
+                "text/plain",
+                "",
+            )
+            .await
+        }
+    };
+    if ok {
+        write_response(&mut stream, "200 OK", "text/plain", "ok\n").await
+    } else {
+        write_response(
+            &mut stream,
+            "503 Service Unavailable",
+            "text/plain",
+            &get_unavailable_body(metrics),
+        )
+        .await
+    }
+}
+
+/// The body of an unhealthy response, with the last upload error if
+/// there was one, so the reason can be seen without reading the logs.
+fn get_unavailable_body(metrics: &Metrics) -> String {
+    match metrics.last_upload_error() {
+        Some((timestamp, message)) => format!(
+            "unavailable\nlast_error: {}\nlast_error_timestamp: {}\n",
+            message.replace('\n', " "),
+            timestamp
+        ),
+        None => "unavailable\n".to_string(),
+    }
+}
+
+/// Serve `/health` and `/ready` until the process exits.
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            warn!("failed to listen for health checks on {}: {}", addr, err);
+            return;
+        }
+    };
+    info!("serving health checks on http://{}/health", addr);
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                warn!("failed to accept a health connection: {}", err);
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_connection(stream, &metrics).await {
+                warn!("failed to serve a health check: {}", err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ready_after_first_upload() {
+        let metrics = Metrics::default();
+        assert!(!is_ready(&metrics));
+        metrics.journal_open.store(true, Ordering::Relaxed);
+        assert!(!is_ready(&metrics));
+        metrics.last_upload_timestamp.store(1000, Ordering::Relaxed);
+        assert!(is_ready(&metrics));
+    }
+
+    #[test]
+    fn test_unhealthy_when_uploads_keep_failing() {
+        let metrics = Metrics::default();
+        metrics.journal_open.store(true, Ordering::Relaxed);
+        metrics.last_upload_timestamp.store(1000, Ordering::Relaxed);
+        assert!(is_healthy(&metrics, 100_000));
+        metrics
+            .last_upload_failure_timestamp
+            .store(1100, Ordering::Relaxed);
+        assert!(is_healthy(&metrics, 1000 + MAX_FAILING_SECS));
+        assert!(!is_healthy(&metrics, 1001 + MAX_FAILING_SECS));
+    }
+
+    #[test]
+    fn test_unavailable_body_has_last_error() {
+        let metrics = Metrics::default();
+        assert_eq!(get_unavailable_body(&metrics), "unavailable\n");
+        metrics.record_upload_error(&"access denied\nfor role", 1100);
+        assert_eq!(
+            get_unavailable_body(&metrics),
+            "unavailable\nlast_error: access denied for role\n\
+             last_error_timestamp: 1100\n"
+        );
+    }
+}
@@ -0,0 +1,322 @@
+use crate::backoff::Backoff;
+use crate::cloudwatch::{Undelivered, UploadError, Uploader};
+use crate::metrics::Metrics;
+use crate::spool::encode_event;
+use async_trait::async_trait;
+use aws_sdk_cloudwatchlogs::model::InputLogEvent;
+use aws_sdk_kinesis::model::{PutRecordsRequestEntry, PutRecordsResultEntry};
+use aws_sdk_kinesis::types::Blob;
+use aws_sdk_kinesis::Client;
+use aws_types::SdkConfig;
+use chrono::Utc;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, warn};
+
+/// The most records a single PutRecords request can take, and the most
+/// bytes of data and partition keys.
+///
+/// Reference:
+/// docs.aws.amazon.com/kinesis/latest/APIReference/API_PutRecords.html
+pub const MAX_RECORDS_PER_REQUEST: usize = 500;
+pub const MAX_REQUEST_BYTES: usize = 5 * 1024 * 1024;
+
+/// Partition keys are limited to 256 characters.
+const MAX_PARTITION_KEY_CHARS: usize = 256;
+
+/// Puts events to a Kinesis data stream as JSON records. Each log stream
+/// becomes a partition key, so the events of a host, or of a unit with
+/// `STREAM_PER_UNIT`, stay in order on one shard.
+pub struct KinesisUploader {
+    client: Client,
+    stream_name: String,
+    /// The partition key of events that aren't routed to a stream of
+    /// their own.
+    default_partition_key: String,
+    max_attempts: u32,
+    backoff: Backoff,
+    metrics: Arc<Metrics>,
+}
+
+impl KinesisUploader {
+    pub fn new(
+        aws_config: &SdkConfig,
+        stream_name: String,
+        default_partition_key: &str,
+        max_attempts: u32,
+        metrics: Arc<Metrics>,
+    ) -> KinesisUploader {
+        KinesisUploader {
+            client: Client::new(aws_config),
+            stream_name,
+            default_partition_key: get_partition_key(default_partition_key),
+            max_attempts,
+            backoff: Backoff::new(
+                Duration::from_millis(100),
+                Duration::from_secs(20),
+            ),
+            metrics,
+        }
+    }
+
+    /// Put one group of events, retrying the records that weren't
+    /// accepted with backoff up to the configured number of attempts.
+    /// Returns the events that could not be delivered with the error.
+    async fn put_group(
+        &self,
+        partition_key: &str,
+        mut pending: Vec<InputLogEvent>,
+    ) -> Result<(), Undelivered> {
+        let max_attempts = self.max_attempts;
+        let mut attempt = 0;
+        loop {
+            let entries = pending
+                .iter()
+                .map(|event| {
+                    PutRecordsRequestEntry::builder()
+                        .data(Blob::new(encode_event(event)))
+                        .partition_key(partition_key)
+                        .build()
+                })
+                .collect();
+            let result = self
+                .client
+                .put_records()
+                .stream_name(self.stream_name.as_str())
+                .set_records(Some(entries))
+                .send()
+                .await;
+            attempt += 1;
+            match result {
+                Ok(output) => {
+                    let results = output.records().unwrap_or_default();
+                    let error = get_record_error(&pending, results);
+                    pending = get_failed_records(pending, results);
+                    if pending.is_empty() {
+                        return Ok(());
+                    }
+                    // Records fail individually when a shard is throttled
+                    // or on internal errors, which are both worth retrying
+                    if attempt >= max_attempts {
+                        let message = format!(
+                            "{} records were not accepted",
+                            pending.len()
+                        );
+                        error!(attempt, max_attempts, "{}, giving up", message);
+                        self.metrics.record_upload_error(
+                            &message,
+                            Utc::now().timestamp(),
+                        );
+                        return Err(Undelivered::new(error, pending));
+                    }
+                    debug!(
+                        "{} records were not accepted, retrying",
+                        pending.len()
+                    );
+                }
+                Err(err) => {
+                    let error = UploadError::from_sdk_error(&err);
+                    if attempt >= max_attempts || !error.is_retryable() {
+                        error!(
+                            attempt,
+                            max_attempts,
+                            error = error.name(),
+                            "put_records failed: {}",
+                            err
+                        );
+                        self.metrics
+                            .record_upload_error(&err, Utc::now().timestamp());
+                        return Err(Undelivered::new(error, pending));
+                    }
+                    warn!(
+                        attempt,
+                        max_attempts, "put_records failed, retrying: {}", err
+                    );
+                }
+            }
+            self.backoff.sleep(attempt - 1).await;
+        }
+    }
+}
+
+fn get_partition_key(stream: &str) -> String {
+    stream.chars().take(MAX_PARTITION_KEY_CHARS).collect()
+}
+
+/// The events whose records weren't accepted. The results are in the
+/// same order as the records of the request; a missing result counts as
+/// a failure.
+fn get_failed_records(
+    events: Vec<InputLogEvent>,
+    results: &[PutRecordsResultEntry],
+) -> Vec<InputLogEvent> {
+    events
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| {
+            results
+                .get(*i)
+                .is_none_or(|result| result.error_code().is_some())
+        })
+        .map(|(_, event)| event)
+        .collect()
+}
+
+/// What the records that weren't accepted failed with, by the error code
+/// of the first of them. A missing result counts as a network failure.
+fn get_record_error(
+    events: &[InputLogEvent],
+    results: &[PutRecordsResultEntry],
+) -> UploadError {
+    let error_code = results.iter().find_map(|result| result.error_code());
+    match error_code {
+        Some(code) => UploadError::from_code(code),
+        None if results.len() < events.len() => UploadError::Network,
+        None => UploadError::Other,
+    }
+}
+
+/// Split events into groups that fit in a PutRecords request, keeping
+/// their order.
+fn group_records(
+    events: Vec<InputLogEvent>,
+    partition_key: &str,
+) -> Vec<Vec<InputLogEvent>> {
+    let mut groups: Vec<Vec<InputLogEvent>> = Vec::new();
+    let mut last_group_bytes = 0;
+    for event in events {
+        let num_bytes = encode_event(&event).len() + partition_key.len();
+        match groups.last_mut() {
+            Some(group)
+                if group.len() < MAX_RECORDS_PER_REQUEST
+                    && last_group_bytes + num_bytes <= MAX_REQUEST_BYTES =>
+            {
+                group.push(event);
+                last_group_bytes += num_bytes;
+            }
+            _ => {
+                groups.push(vec![event]);
+                last_group_bytes = num_bytes;
+            }
+        }
+    }
+    groups
+}
+
+#[async_trait]
+impl Uploader for KinesisUploader {
+    fn group_events(
+        &self,
+        events: Vec<InputLogEvent>,
+    ) -> Vec<Vec<InputLogEvent>> {
+        group_records(events, &self.default_partition_key)
+    }
+
+    async fn upload(
+        &mut self,
+        events: Vec<InputLogEvent>,
+    ) -> Result<(), Undelivered> {
+        let partition_key = self.default_partition_key.clone();
+        self.upload_to_stream(&partition_key, events).await
+    }
+
+    async fn upload_to_stream(
+        &mut self,
+        stream: &str,
+        events: Vec<InputLogEvent>,
+    ) -> Result<(), Undelivered> {
+        let partition_key = get_partition_key(stream);
+        debug!(
+            "putting {} records to {} with partition key {}",
+            events.len(),
+            self.stream_name,
+            partition_key
+        );
+        let mut undelivered = Vec::new();
+        let mut last_error = None;
+        for group in group_records(events, &partition_key) {
+            if let Err(mut failed) = self.put_group(&partition_key, group).await
+            {
+                undelivered.append(&mut failed.events);
+                last_error = Some(failed.error);
+            }
+        }
+        match last_error {
+            Some(error) => Err(Undelivered::new(error, undelivered)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_event(message: &str) -> InputLogEvent {
+        InputLogEvent::builder()
+            .message(message)
+            .timestamp(1_500_000_000_000)
+            .build()
+    }
+
+    #[test]
+    fn test_groups_limited_to_record_count() {
+        let events = (0..MAX_RECORDS_PER_REQUEST * 2 + 1)
+            .map(|i| create_event(&i.to_string()))
+            .collect();
+        let groups = group_records(events, "host");
+        let sizes: Vec<usize> =
+            groups.iter().map(|group| group.len()).collect();
+        assert_eq!(
+            sizes,
+            vec![MAX_RECORDS_PER_REQUEST, MAX_RECORDS_PER_REQUEST, 1]
+        );
+        assert_eq!(groups[1][0].message.as_deref(), Some("500"));
+    }
+
+    #[test]
+    fn test_groups_limited_to_request_bytes() {
+        // Eleven records of about 500 KiB each, so ten fit in a request
+        let message = "x".repeat(500 * 1024);
+        let events = (0..11).map(|_| create_event(&message)).collect();
+        let groups = group_records(events, "host");
+        let sizes: Vec<usize> =
+            groups.iter().map(|group| group.len()).collect();
+        assert_eq!(sizes, vec![10, 1]);
+        assert!(group_records(Vec::new(), "host").is_empty());
+    }
+
+    #[test]
+    fn test_get_failed_records() {
+        let events = vec![
+            create_event("ev1"),
+            create_event("ev2"),
+            create_event("ev3"),
+        ];
+        let results = vec![
+            PutRecordsResultEntry::builder().shard_id("shard-1").build(),
+            PutRecordsResultEntry::builder()
+                .error_code("ProvisionedThroughputExceededException")
+                .build(),
+        ];
+        let failed = get_failed_records(events, &results);
+        let messages: Vec<_> = failed
+            .iter()
+            .map(|event| event.message.as_deref())
+            .collect();
+        assert_eq!(messages, vec![Some("ev2"), Some("ev3")]);
+        let events = vec![create_event("ev1"), create_event("ev2")];
+        assert_eq!(get_record_error(&events, &results), UploadError::Throttled);
+        assert_eq!(
+            get_record_error(&events, &results[..1]),
+            UploadError::Network
+        );
+    }
+
+    #[test]
+    fn test_partition_key_is_truncated() {
+        assert_eq!(get_partition_key("web.service"), "web.service");
+        let key = get_partition_key(&"é".repeat(300));
+        assert_eq!(key.chars().count(), MAX_PARTITION_KEY_CHARS);
+    }
+}
@@ -0,0 +1,135 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// Extract the `key=value` pairs of a logfmt style message, such as
+/// `level=info msg="request done" status=200`, in order. Values may be
+/// double quoted, with backslash escapes. Words that aren't pairs, as in
+/// free text around them, are skipped.
+///
+/// Reference:
+/// brandur.org/logfmt
+pub fn parse(message: &str) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    let mut chars = message.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            return pairs;
+        }
+        let mut key = String::new();
+        while let Some(c) =
+            chars.next_if(|c| !c.is_whitespace() && *c != '=' && *c != '"')
+        {
+            key.push(c);
+        }
+        if key.is_empty() || chars.next_if_eq(&'=').is_none() {
+            skip_word(&mut chars);
+            continue;
+        }
+        let value = if chars.next_if_eq(&'"').is_some() {
+            read_quoted(&mut chars)
+        } else {
+            let mut value = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                value.push(c);
+            }
+            value
+        };
+        pairs.push((key, value));
+    }
+}
+
+/// Skip the rest of a word that isn't a pair, including any quoted string
+/// in it, since that may contain spaces.
+fn skip_word(chars: &mut Peekable<Chars>) {
+    while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+        if c == '"' {
+            read_quoted(chars);
+        }
+    }
+}
+
+/// Read a quoted value up to the closing quote, or the end of the message
+/// if there is none.
+fn read_quoted(chars: &mut Peekable<Chars>) -> String {
+    let mut value = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some('r') => value.push('\r'),
+                Some(escaped) => value.push(escaped),
+                None => value.push('\\'),
+            },
+            _ => value.push(c),
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_pairs() {
+        assert_eq!(
+            parse("level=info msg=\"request done\" status=200 path=/api"),
+            create_pairs(&[
+                ("level", "info"),
+                ("msg", "request done"),
+                ("status", "200"),
+                ("path", "/api"),
+            ])
+        );
+        assert_eq!(
+            parse("at=error code=H12 desc=\"Request timeout\" connect=1ms"),
+            create_pairs(&[
+                ("at", "error"),
+                ("code", "H12"),
+                ("desc", "Request timeout"),
+                ("connect", "1ms"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_escapes() {
+        assert_eq!(
+            parse(r#"msg="say \"hi\"\n" path="C:\\temp" empty= eq=a=b"#),
+            create_pairs(&[
+                ("msg", "say \"hi\"\n"),
+                ("path", "C:\\temp"),
+                ("empty", ""),
+                ("eq", "a=b"),
+            ])
+        );
+        assert_eq!(
+            parse("msg=\"unterminated"),
+            create_pairs(&[("msg", "unterminated")])
+        );
+    }
+
+    #[test]
+    fn test_parse_skips_free_text() {
+        assert_eq!(
+            parse("Connection closed by 10.0.0.1 port=22 [preauth]"),
+            create_pairs(&[("port", "22")])
+        );
+        assert_eq!(
+            parse("\"quoted words=1\" =orphan a=1"),
+            create_pairs(&[("a", "1")])
+        );
+        assert!(parse("").is_empty());
+        assert!(parse("no pairs here").is_empty());
+    }
+}
@@ -0,0 +1,80 @@
+use std::env::var;
+use std::io::IsTerminal;
+use tracing::warn;
+use tracing_subscriber::EnvFilter;
+
+/// How the service's own diagnostics are written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SelfLogFormat {
+    /// Human readable lines.
+    Text,
+    /// One JSON object per line, for log collectors.
+    Json,
+}
+
+impl SelfLogFormat {
+    fn parse(value: &str) -> Option<SelfLogFormat> {
+        match value.trim().to_lowercase().as_str() {
+            "text" => Some(SelfLogFormat::Text),
+            "json" => Some(SelfLogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// The filter used when neither `RUST_LOG` nor `LOG_LEVEL` is set. `DEBUG`
+/// turns on debug output for this service only, since the AWS SDK and
+/// HTTP client are very chatty at that level.
+fn get_default_filter() -> EnvFilter {
+    if var("DEBUG").is_ok() {
+        EnvFilter::new("info,journald_to_cloudwatch=debug")
+    } else {
+        EnvFilter::new("info")
+    }
+}
+
+/// Send the service's own diagnostics to stderr, filtered by `RUST_LOG`
+/// or `LOG_LEVEL`, which take a level such as `warn` or a list of
+/// directives such as `info,journald_to_cloudwatch=trace`, and formatted
+/// according to `SELF_LOG_FORMAT`.
+pub fn init() {
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_from_env("LOG_LEVEL"))
+        .unwrap_or_else(|_| get_default_filter());
+    let format_value = var("SELF_LOG_FORMAT").ok();
+    let format = format_value
+        .as_deref()
+        .and_then(SelfLogFormat::parse)
+        .unwrap_or(SelfLogFormat::Text);
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match format {
+        SelfLogFormat::Text => builder
+            // The journal would store color codes literally
+            .with_ansi(std::io::stderr().is_terminal())
+            .init(),
+        SelfLogFormat::Json => builder.json().init(),
+    }
+    if let Some(value) = format_value {
+        if SelfLogFormat::parse(&value).is_none() {
+            warn!(
+                "invalid SELF_LOG_FORMAT value {:?}, expected text or json; \
+                 using text",
+                value
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_self_log_format() {
+        assert_eq!(SelfLogFormat::parse("text"), Some(SelfLogFormat::Text));
+        assert_eq!(SelfLogFormat::parse(" JSON "), Some(SelfLogFormat::Json));
+        assert_eq!(SelfLogFormat::parse("yaml"), None);
+    }
+}
@@ -1,70 +1,351 @@
+mod backoff;
+mod backpressure;
+mod binary;
 mod cloudwatch;
 mod configuration;
+mod cursor;
 mod ec2;
+mod ecs;
+mod failover;
+mod file;
+mod filter;
+mod format;
+mod health;
+mod kinesis;
+mod logfmt;
+mod logging;
+mod metrics;
+mod notify;
+mod redact;
+mod repeat;
+mod source;
+mod spool;
+mod stdout;
 
 use aws_sdk_cloudwatchlogs::model::InputLogEvent;
+use backoff::Backoff;
+use backpressure::Backpressure;
 use chrono::Utc;
-use configuration::Configuration;
-use std::time::Duration;
+use cloudwatch::JournalEvent;
+use configuration::{
+    Backend, Configuration, ConfigurationError, JournalSource, MessageEncoding,
+    MissingMessage, StartPosition,
+};
+use cursor::CursorStore;
+use filter::RecordFilter;
+use metrics::{DropReason, Metrics};
+use source::{RecordSource, REALTIME_TIMESTAMP_FIELD};
+use std::borrow::Cow;
+use std::ffi::CString;
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{process::exit, thread};
 use systemd::{journal, Journal};
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::mpsc::{self, Sender};
+use tokio::sync::oneshot;
+use tracing::{debug, error, info, warn};
 
-fn get_record_timestamp_millis(record: &journal::JournalRecord) -> i64 {
-    if let Some(timestamp) = record.get("_SOURCE_REALTIME_TIMESTAMP") {
-        if let Ok(timestamp) = timestamp.parse::<i64>() {
-            // Convert microseconds to milliseconds
-            return timestamp / 1000;
+/// Prefer the time the message was logged, then the time the journal
+/// received it, and only use the current time if neither is known. The
+/// journal's timestamps are in microseconds, which are kept for ordering
+/// until the event is uploaded in milliseconds.
+fn get_record_timestamp_micros(record: &journal::JournalRecord) -> i64 {
+    for field in ["_SOURCE_REALTIME_TIMESTAMP", REALTIME_TIMESTAMP_FIELD] {
+        if let Some(timestamp) = record.get(field) {
+            if let Ok(timestamp) = timestamp.parse::<i64>() {
+                return timestamp;
+            }
         }
     }
     // Fall back to current time
-    Utc::now().timestamp_millis()
+    Utc::now().timestamp_nanos() / 1000
 }
 
-fn get_record_comm(record: &journal::JournalRecord) -> String {
-    if let Some(comm) = record.get("_COMM") {
-        comm.to_string()
-    } else {
-        "unknown".to_string()
+/// The message of a record without a `MESSAGE` field, if it is to be
+/// forwarded anyway.
+fn get_missing_message(
+    conf: &Configuration,
+    record: &journal::JournalRecord,
+) -> Option<String> {
+    match &conf.missing_message {
+        MissingMessage::Drop => None,
+        MissingMessage::Field(field) => record.get(field).cloned(),
+        MissingMessage::Record => Some(format::encode_fields(record)),
     }
 }
 
-fn parse_record(record: journal::JournalRecord) -> Option<InputLogEvent> {
-    if let Some(message) = record.get("MESSAGE") {
-        Some(
-            InputLogEvent::builder()
-                .message(format!(
-                    "{}: {}",
-                    get_record_comm(&record),
-                    message.to_string()
-                ))
-                .timestamp(get_record_timestamp_millis(&record))
-                .build(),
-        )
-    } else {
-        None
+fn parse_record(
+    conf: &Configuration,
+    mut record: journal::JournalRecord,
+    timestamp_micros: i64,
+) -> Option<InputLogEvent> {
+    if !record.contains_key("MESSAGE") {
+        let message = get_missing_message(conf, &record)?;
+        record.insert("MESSAGE".to_string(), message);
     }
+    let message = record.get("MESSAGE")?;
+    if let Cow::Owned(redacted) = redact::redact(&conf.redact_patterns, message)
+    {
+        record.insert("MESSAGE".to_string(), redacted);
+    }
+    if let Some(max_bytes) = conf.max_message_bytes {
+        let message = record.get("MESSAGE")?;
+        if let Cow::Owned(truncated) =
+            format::truncate_message(message, max_bytes)
+        {
+            record.insert("MESSAGE".to_string(), truncated);
+        }
+    }
+    let timestamp = timestamp_micros / 1000;
+    let message = match conf.message_encoding {
+        MessageEncoding::Text => {
+            let mut message = conf.message_format.format(&record);
+            if let Some(count) = record.get(repeat::REPEAT_COUNT_FIELD) {
+                message.push_str(&format!(" (repeated {} times)", count));
+            }
+            if conf.timestamp_prefix {
+                message = format::prefix_timestamp(message, timestamp);
+            }
+            message
+        }
+        MessageEncoding::Json => conf.json_format.encode(&record),
+    };
+    Some(
+        InputLogEvent::builder()
+            .message(message)
+            .timestamp(timestamp)
+            .build(),
+    )
 }
 
-fn run_main_loop(conf: Configuration, tx: Sender<InputLogEvent>) {
-    match journal::OpenOptions::default()
-        .local_only(false)
-        .runtime_only(false)
-        .open()
-    {
-        Ok(mut journal) => {
-            // Move to the end of the message log
-            if let Err(err) = journal.seek(journal::JournalSeek::Tail) {
-                eprintln!("failed to seek to tail: {}", err);
+/// Position the journal where the configuration says reading should
+/// start.
+fn seek_to_start(conf: &Configuration, journal: &mut Journal) {
+    match conf.start_position {
+        StartPosition::Tail => seek_to_tail(journal),
+        StartPosition::Head => {
+            warn!(
+                "reading from the head of the journal; the initial upload \
+                 may be very large"
+            );
+            if let Err(err) = journal.seek(journal::JournalSeek::Head) {
+                warn!("failed to seek to head: {}", err);
+            }
+        }
+        StartPosition::Cursor => {
+            let cursor_store = CursorStore::new(conf.cursor_path.clone());
+            seek_after_cursor(journal, cursor_store.load());
+        }
+        StartPosition::Since(usec) => {
+            let seek = journal::JournalSeek::ClockRealtime { usec };
+            if let Err(err) = journal.seek(seek) {
+                warn!("failed to seek to SINCE, using the tail: {}", err);
+                seek_to_tail(journal);
+            }
+        }
+    }
+}
+
+/// Position the journal just after the entry with the given cursor,
+/// falling back to the tail when there is no cursor or it can't be used.
+fn seek_after_cursor(journal: &mut Journal, cursor: Option<String>) {
+    if let Some(cursor) = cursor {
+        match journal.seek(journal::JournalSeek::Cursor {
+            cursor: cursor.clone(),
+        }) {
+            Ok(()) => {
+                // Step onto the saved entry so the next read returns
+                // the one after it. If the saved entry is gone, the
+                // journal lands on the closest entry, which hasn't been
+                // uploaded yet, so step back again.
+                if journal.next().is_ok()
+                    && !journal.test_cursor(cursor).unwrap_or(false)
+                {
+                    if let Err(err) = journal.previous() {
+                        warn!("failed to step back from cursor: {}", err);
+                    }
+                }
+                return;
+            }
+            Err(err) => {
+                warn!("failed to seek to saved cursor: {}", err);
             }
+        }
+    }
 
-            handle_journal_entry_loop(&conf, &mut journal, tx)
+    seek_to_tail(journal);
+}
+
+fn seek_to_tail(source: &mut impl RecordSource) {
+    // Move to the end of the message log
+    if let Err(err) = source.seek(journal::JournalSeek::Tail) {
+        warn!("failed to seek to tail: {}", err);
+    }
+}
+
+/// Open one of the `JOURNAL_SOURCES`, or without one the journal files in
+/// `JOURNAL_DIRECTORY` if it's set, otherwise the live system journal.
+fn open_journal(
+    conf: &Configuration,
+    source: Option<&JournalSource>,
+) -> systemd::Result<Journal> {
+    if let Some(source) = source {
+        return open_journal_source(source);
+    }
+    match &conf.journal_directory {
+        Some(directory) => open_directory(directory),
+        None => {
+            let mut options = journal::OpenOptions::default();
+            options.local_only(false).runtime_only(false);
+            match &conf.journal_namespace {
+                Some(namespace) => open_namespace(&options, namespace),
+                None => options.open(),
+            }
+        }
+    }
+}
+
+fn open_journal_source(source: &JournalSource) -> systemd::Result<Journal> {
+    let mut options = journal::OpenOptions::default();
+    options.local_only(false).runtime_only(false);
+    match source {
+        JournalSource::System => {
+            info!("reading the system journal");
+            options.system(true).open()
         }
+        JournalSource::User => {
+            info!("reading the journal of the current user");
+            options.current_user(true).open()
+        }
+        JournalSource::Directory(directory) => open_directory(directory),
+    }
+}
+
+fn open_directory(directory: &Path) -> systemd::Result<Journal> {
+    info!("reading the journal in {}", directory.display());
+    let directory =
+        CString::new(directory.as_os_str().as_bytes()).map_err(|err| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, err)
+        })?;
+    journal::OpenDirectoryOptions::default().open_directory(directory)
+}
+
+#[cfg(feature = "journal-namespaces")]
+fn open_namespace(
+    options: &journal::OpenOptions,
+    namespace: &str,
+) -> systemd::Result<Journal> {
+    info!("reading the journal namespace {}", namespace);
+    options.open_namespace(namespace)
+}
+
+#[cfg(not(feature = "journal-namespaces"))]
+fn open_namespace(
+    _options: &journal::OpenOptions,
+    _namespace: &str,
+) -> systemd::Result<Journal> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "JOURNAL_NAMESPACE needs the journal-namespaces feature",
+    ))
+}
+
+/// Read the journal, or one of the `JOURNAL_SOURCES`, until shutdown.
+/// `ready` is signalled once the journal is open and positioned.
+fn run_main_loop(
+    conf: Configuration,
+    source: Option<JournalSource>,
+    tx: Sender<JournalEvent>,
+    shutdown: Arc<AtomicBool>,
+    ready: oneshot::Sender<systemd::Result<()>>,
+    metrics: Arc<Metrics>,
+) {
+    let mut journal = match open_journal(&conf, source.as_ref()) {
+        Ok(journal) => journal,
         Err(err) => {
-            eprintln!("failed to open journal: {}", err);
-            exit(1);
+            let _ = ready.send(Err(err));
+            return;
+        }
+    };
+    let filter = RecordFilter::new(&conf);
+    add_journal_matches(&filter, &mut journal);
+    seek_to_start(&conf, &mut journal);
+    metrics.journal_open.store(true, Ordering::Relaxed);
+    let _ = ready.send(Ok(()));
+
+    let reopen_metrics = metrics.clone();
+    let reopen = |cursor| {
+        reopen_journal(
+            &conf,
+            source.as_ref(),
+            &filter,
+            cursor,
+            &reopen_metrics,
+            &shutdown,
+        )
+    };
+    let sender = EventSender::new(tx, conf.channel_capacity, metrics);
+    handle_journal_entry_loop(
+        &conf, &filter, journal, reopen, sender, &shutdown,
+    )
+}
+
+fn add_journal_matches(filter: &RecordFilter, journal: &mut Journal) {
+    if let Err(err) = filter.add_journal_matches(journal) {
+        warn!("failed to add journal matches: {}", err);
+    }
+}
+
+/// How many journal errors in a row make the reader reopen the journal.
+const MAX_CONSECUTIVE_ERRORS: u32 = 5;
+
+/// The delay between attempts to reopen the journal.
+const REOPEN_BACKOFF: Backoff =
+    Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+
+/// Reopen the journal and position it after the entry with the given
+/// cursor, or after the saved cursor if nothing has been read yet. The
+/// saved cursor isn't one of the `JOURNAL_SOURCES`, so they only resume
+/// from their own. This keeps trying until it succeeds, returning `None`
+/// only on shutdown.
+fn reopen_journal(
+    conf: &Configuration,
+    source: Option<&JournalSource>,
+    filter: &RecordFilter,
+    cursor: Option<String>,
+    metrics: &Metrics,
+    shutdown: &AtomicBool,
+) -> Option<Journal> {
+    let cursor = match source {
+        Some(_) => cursor,
+        None => {
+            cursor.or_else(|| CursorStore::new(conf.cursor_path.clone()).load())
+        }
+    };
+    let mut attempt = 0;
+    while !shutdown.load(Ordering::Relaxed) {
+        thread::sleep(REOPEN_BACKOFF.ceiling(attempt));
+        match open_journal(conf, source) {
+            Ok(mut journal) => {
+                add_journal_matches(filter, &mut journal);
+                seek_after_cursor(&mut journal, cursor);
+                let reopens =
+                    metrics.journal_reopens.fetch_add(1, Ordering::Relaxed) + 1;
+                info!("reopened the journal ({} times so far)", reopens);
+                return Some(journal);
+            }
+            Err(err) => {
+                warn!("failed to reopen the journal: {}", err);
+                attempt += 1;
+            }
         }
     }
+    None
 }
 
 fn short_record(record: &journal::JournalRecord) -> String {
@@ -78,47 +359,673 @@ fn short_record(record: &journal::JournalRecord) -> String {
     )
 }
 
-fn handle_journal_entry_loop(
+/// How long the reader blocks waiting for new journal entries. The wait
+/// returns as soon as the journal changes, so this only bounds how long a
+/// shutdown can go unnoticed on an idle journal.
+const JOURNAL_WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to pause after a journal error, so a persistent error doesn't
+/// turn the loop into a busy loop.
+const JOURNAL_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+/// Read records from the source and send the ones that pass the filter to
+/// the uploader until shutdown. After repeated errors the source is
+/// replaced with the one `reopen` returns for the last cursor read, and
+/// reading stops if there is none.
+fn handle_journal_entry_loop<S: RecordSource>(
     conf: &Configuration,
-    journal: &mut Journal,
-    tx: mpsc::Sender<InputLogEvent>,
+    filter: &RecordFilter,
+    mut source: S,
+    mut reopen: impl FnMut(Option<String>) -> Option<S>,
+    mut sender: EventSender,
+    shutdown: &AtomicBool,
 ) {
-    let wait_time = Some(Duration::from_secs(1));
-    loop {
-        match journal.await_next_entry(wait_time) {
+    // The cursor of the last entry read, whether or not it was sent, for
+    // resuming after a reopen
+    let mut last_cursor = None;
+    let mut consecutive_errors = 0;
+    let mut last_entry = Instant::now();
+    // One cursor can't hold the positions in several journals, so with
+    // JOURNAL_SOURCES the uploader isn't given any to save
+    let saves_cursor = conf.journal_sources.is_empty();
+    while !shutdown.load(Ordering::Relaxed) {
+        // Read everything that is already in the journal (e.g. entries
+        // written before a restart) before waiting for new ones.
+        // With ONESHOT there's no waiting, the end of the journal is the
+        // end of the run
+        let timeout = match conf.idle_timeout {
+            _ if conf.oneshot => Duration::ZERO,
+            Some(idle_timeout) => JOURNAL_WAIT_TIMEOUT
+                .min(idle_timeout.saturating_sub(last_entry.elapsed())),
+            None => JOURNAL_WAIT_TIMEOUT,
+        };
+        let result = match source.await_next_entry(timeout) {
             Ok(Some(record)) => {
-                conf.debug(format!(
+                last_entry = Instant::now();
+                sender.metrics.events_read.fetch_add(1, Ordering::Relaxed);
+                debug!(
                     "handle_entry: new record: {:?}, tx cap: {}",
                     short_record(&record),
-                    tx.capacity(),
-                ));
-                if let Some(event) = parse_record(record) {
-                    if let Err(err) = tx.blocking_send(event) {
-                        eprintln!("handle_entry: queue send failed: {}", err);
+                    sender.tx.capacity()
+                );
+                let cursor = source.cursor().ok();
+                last_cursor = cursor.clone();
+                let cursor = cursor.filter(|_| saves_cursor);
+                if !filter.accepts(&record) {
+                    sender.metrics.record_drop(DropReason::Filtered, 1);
+                    continue;
+                }
+                let stream = conf.record_stream_name(&record);
+                let timestamp = get_record_timestamp_micros(&record);
+                let (record, copies) = repeat::apply(conf.repeat_mode, record);
+                if let Some(event) = parse_record(conf, record, timestamp) {
+                    for _ in 1..copies {
+                        let event = JournalEvent::new(event.clone(), None)
+                            .with_stream(stream.clone())
+                            .with_timestamp_micros(timestamp);
+                        sender.send(event);
                     }
+                    // Only the last copy completes the journal record
+                    let event = JournalEvent::new(event, cursor)
+                        .with_stream(stream)
+                        .with_timestamp_micros(timestamp);
+                    sender.send(event);
                 } else {
-                    eprintln!("handle_entry: unable to parse the record");
+                    warn!("handle_entry: unable to parse the record");
+                    sender.metrics.record_drop(DropReason::ParseFailed, 1);
                 }
+                Ok(())
             }
-            Ok(None) => {}
+            Ok(None) if conf.oneshot => {
+                info!("reached the end of the journal");
+                break;
+            }
+            Ok(None)
+                if conf
+                    .idle_timeout
+                    .is_some_and(|timeout| last_entry.elapsed() >= timeout) =>
+            {
+                info!(
+                    "no new journal entries for {:?}, stopping",
+                    conf.idle_timeout.unwrap_or_default()
+                );
+                break;
+            }
+            Ok(None) => Ok(()),
             Err(err) => {
-                eprintln!("handle_entry: await_next_record failed: {}", err)
+                warn!("handle_entry: reading the journal failed: {}", err);
+                Err(err)
+            }
+        };
+        if result.is_ok() {
+            consecutive_errors = 0;
+            continue;
+        }
+        consecutive_errors += 1;
+        if consecutive_errors < MAX_CONSECUTIVE_ERRORS {
+            thread::sleep(JOURNAL_ERROR_DELAY);
+            continue;
+        }
+        // The handle may have gone bad, for example after journald was
+        // restarted, so start over with a new one
+        warn!(
+            "{} journal errors in a row, reopening the journal",
+            consecutive_errors
+        );
+        consecutive_errors = 0;
+        match reopen(last_cursor.clone()) {
+            Some(reopened) => source = reopened,
+            None => break,
+        }
+    }
+    debug!("journal reader stopped");
+    // Dropping the sender closes the queue, which tells the uploader to
+    // flush what is left
+}
+
+/// The sending end of the queue to the uploader.
+struct EventSender {
+    tx: Sender<JournalEvent>,
+    capacity: usize,
+    backpressure: Backpressure,
+    metrics: Arc<Metrics>,
+}
+
+impl EventSender {
+    fn new(
+        tx: Sender<JournalEvent>,
+        capacity: usize,
+        metrics: Arc<Metrics>,
+    ) -> EventSender {
+        EventSender {
+            tx,
+            capacity,
+            backpressure: Backpressure::new(),
+            metrics,
+        }
+    }
+
+    /// Send an event to the uploader, waiting for space in the queue if
+    /// it is full.
+    fn send(&mut self, event: JournalEvent) {
+        if self.tx.capacity() == 0 {
+            if self.backpressure.record_wait(Instant::now()) {
+                warn!(
+                    "handle_entry: the event queue is full, waiting for the \
+                     uploader to catch up"
+                );
             }
+            debug!(
+                "handle_entry: waited for queue space {} times",
+                self.backpressure.waits()
+            );
         }
+        let num_bytes = cloudwatch::get_event_num_bytes(&event.event) as u64;
+        self.metrics
+            .queued_bytes
+            .fetch_add(num_bytes, Ordering::Relaxed);
+        if let Err(err) = self.tx.blocking_send(event) {
+            warn!("handle_entry: queue send failed: {}", err);
+            self.metrics
+                .queued_bytes
+                .fetch_sub(num_bytes, Ordering::Relaxed);
+        }
+        let depth = self.capacity - self.tx.capacity();
+        self.metrics
+            .channel_depth
+            .store(depth as u64, Ordering::Relaxed);
+    }
+}
+
+/// Why the service stopped before it started forwarding. `main` logs it
+/// and exits with status 1, so `Restart=on-failure` and scripts see the
+/// failure instead of a service that runs but can't forward anything.
+#[derive(Debug)]
+enum StartupError {
+    Configuration(ConfigurationError),
+    OpenJournal(systemd::Error),
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StartupError::Configuration(err) => {
+                write!(f, "invalid configuration: {}", err)
+            }
+            StartupError::OpenJournal(err) => {
+                write!(f, "failed to open journal: {}", err)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StartupError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StartupError::Configuration(err) => Some(err),
+            StartupError::OpenJournal(err) => Some(err),
+        }
+    }
+}
+
+impl From<ConfigurationError> for StartupError {
+    fn from(err: ConfigurationError) -> StartupError {
+        StartupError::Configuration(err)
+    }
+}
+
+/// Check the configuration before starting.
+async fn validate(conf: &Configuration) -> Result<(), ConfigurationError> {
+    conf.validate()?;
+    if conf.backend == Backend::CloudWatch {
+        cloudwatch::check_connection(conf).await?;
+    }
+    Ok(())
+}
+
+/// Wait for `SIGTERM`, which systemd sends on stop, or `SIGINT`.
+async fn wait_for_shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(err) => {
+            warn!("failed to install the SIGTERM handler: {}", err);
+            return std::future::pending().await;
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => info!("received SIGTERM, shutting down"),
+        _ = tokio::signal::ctrl_c() => info!("received SIGINT, shutting down"),
     }
 }
 
 #[tokio::main]
 async fn main() {
-    let conf = Configuration::new().await;
+    logging::init();
+    if let Err(err) = run().await {
+        error!("{}", err);
+        exit(1);
+    }
+}
+
+/// Start the reader and the uploader and run until the journal has been
+/// forwarded in oneshot mode or the reader went idle, or until a shutdown
+/// signal. Problems that retrying won't fix are returned; failures while
+/// running are handled where they happen.
+async fn run() -> Result<(), StartupError> {
+    let conf = Configuration::new().await?;
+    conf.log_summary();
+    if conf.validate_config {
+        validate(&conf).await?;
+    }
     let conf2 = conf.clone();
-    let (tx, rx) = mpsc::channel(1024);
-    let uploader = tokio::spawn(cloudwatch::upload_thread(conf2, rx));
+    let (tx, rx) = mpsc::channel(conf.channel_capacity);
+    let metrics = Arc::new(Metrics::default());
+    if let Some(addr) = conf.metrics_addr {
+        tokio::spawn(metrics::serve(addr, metrics.clone()));
+    }
+    if let Some(addr) = conf.health_addr {
+        tokio::spawn(health::serve(addr, metrics.clone()));
+    }
+    let (uploader_ready, uploader_ready_rx) = oneshot::channel();
+    let mut uploader = tokio::spawn(cloudwatch::upload_thread(
+        conf2,
+        rx,
+        uploader_ready,
+        metrics.clone(),
+    ));
 
-    thread::spawn(move || {
-        run_main_loop(conf, tx);
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let shutdown_timeout = conf.shutdown_timeout;
+    // Runs that stop by themselves report whether everything was uploaded
+    let stops_by_itself = conf.oneshot || conf.idle_timeout.is_some();
+    let uploader_metrics = metrics.clone();
+    // Each of the JOURNAL_SOURCES has a reader of its own, all feeding the
+    // one queue, and without them there is one for the default journal
+    let sources = if conf.journal_sources.is_empty() {
+        vec![None]
+    } else {
+        conf.journal_sources.iter().cloned().map(Some).collect()
+    };
+    let mut readers_ready = Vec::new();
+    for source in sources {
+        let conf = conf.clone();
+        let tx = tx.clone();
+        let reader_shutdown = shutdown.clone();
+        let metrics = metrics.clone();
+        let (reader_ready, reader_ready_rx) = oneshot::channel();
+        thread::spawn(move || {
+            run_main_loop(
+                conf,
+                source,
+                tx,
+                reader_shutdown,
+                reader_ready,
+                metrics,
+            );
+        });
+        readers_ready.push(reader_ready_rx);
+    }
+    // The queue closes once every reader has stopped
+    drop(tx);
+    // The journals are opened right away, so a failure is known before
+    // anything else has to be waited for
+    for reader_ready_rx in readers_ready {
+        if let Ok(Err(err)) = reader_ready_rx.await {
+            return Err(StartupError::OpenJournal(err));
+        }
+    }
+
+    tokio::spawn(async move {
+        if uploader_ready_rx.await.is_ok() {
+            notify::notify_ready();
+        }
     });
-    if let Err(err) = uploader.await {
-        eprintln!("join failed: {:?}", err);
+    if let Some(interval) = notify::watchdog_interval() {
+        tokio::spawn(notify::run_watchdog(interval));
+    }
+
+    let result = tokio::select! {
+        result = &mut uploader => result,
+        _ = wait_for_shutdown_signal() => {
+            // Stop the reader, then give the uploader a bounded amount of
+            // time to drain the queue, flush and save the cursor
+            notify::notify_stopping();
+            shutdown.store(true, Ordering::Relaxed);
+            match tokio::time::timeout(shutdown_timeout, &mut uploader).await {
+                Ok(result) => result,
+                Err(_) => {
+                    error!(
+                        "pending events were not uploaded within {:?}, \
+                         exiting anyway",
+                        shutdown_timeout
+                    );
+                    exit(1);
+                }
+            }
+        }
+    };
+    if let Err(err) = result {
+        error!("join failed: {:?}", err);
+        exit(1);
+    }
+    // The uploader only returns by itself once the reader has reached the
+    // end of the journal, or gone idle, and everything left has been
+    // flushed
+    if stops_by_itself {
+        let failures = uploader_metrics.upload_failures.load(Ordering::Relaxed);
+        if failures > 0 {
+            error!("{} batches could not be uploaded", failures);
+            exit(1);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_record(fields: &[(&str, &str)]) -> journal::JournalRecord {
+        fields
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// Yields scripted records, then reports the end of the journal. The
+    /// cursor of a record is its position.
+    struct ScriptedSource {
+        records: Vec<journal::JournalRecord>,
+        position: usize,
+    }
+
+    impl ScriptedSource {
+        fn new(records: Vec<journal::JournalRecord>) -> ScriptedSource {
+            ScriptedSource {
+                records,
+                position: 0,
+            }
+        }
+    }
+
+    impl RecordSource for ScriptedSource {
+        fn await_next_entry(
+            &mut self,
+            timeout: Duration,
+        ) -> systemd::Result<Option<journal::JournalRecord>> {
+            let record = self.records.get(self.position).cloned();
+            match record {
+                Some(_) => self.position += 1,
+                // Nothing is ever appended, so waiting always times out
+                None => thread::sleep(timeout),
+            }
+            Ok(record)
+        }
+
+        fn seek(&mut self, seek: journal::JournalSeek) -> systemd::Result<()> {
+            self.position = match seek {
+                journal::JournalSeek::Head => 0,
+                journal::JournalSeek::Tail => self.records.len(),
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Unsupported,
+                        "unsupported seek",
+                    ))
+                }
+            };
+            Ok(())
+        }
+
+        fn cursor(&self) -> systemd::Result<String> {
+            match self.position.checked_sub(1) {
+                Some(position) => Ok(format!("cursor-{}", position)),
+                None => Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "no record has been read",
+                )),
+            }
+        }
+    }
+
+    /// Run the reader over the records with `ONESHOT` and return the
+    /// events it sent to the uploader.
+    fn read_events(
+        conf: &Configuration,
+        records: Vec<journal::JournalRecord>,
+    ) -> Vec<JournalEvent> {
+        let mut conf = conf.clone();
+        conf.oneshot = true;
+        let capacity = 16;
+        let (tx, mut rx) = mpsc::channel(capacity);
+        let sender = EventSender::new(tx, capacity, Arc::default());
+        let filter = RecordFilter::new(&conf);
+        handle_journal_entry_loop(
+            &conf,
+            &filter,
+            ScriptedSource::new(records),
+            |_| None,
+            sender,
+            &AtomicBool::new(false),
+        );
+        let mut events = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            events.push(event);
+        }
+        events
+    }
+
+    fn get_messages(events: &[JournalEvent]) -> Vec<&str> {
+        events
+            .iter()
+            .filter_map(|event| event.event.message.as_deref())
+            .collect()
+    }
+
+    #[test]
+    fn test_reader_filters_records() {
+        let mut conf = Configuration::for_tests();
+        conf.source = configuration::Source::User;
+        conf.exclude_pattern = regex::Regex::new("health").ok();
+        let records = vec![
+            create_record(&[
+                ("MESSAGE", "started"),
+                ("_COMM", "web"),
+                ("_TRANSPORT", "stdout"),
+            ]),
+            create_record(&[
+                ("MESSAGE", "GET /health"),
+                ("_COMM", "web"),
+                ("_TRANSPORT", "stdout"),
+            ]),
+            create_record(&[
+                ("MESSAGE", "eth0: link up"),
+                ("_TRANSPORT", "kernel"),
+            ]),
+            create_record(&[("_COMM", "web"), ("_TRANSPORT", "stdout")]),
+        ];
+        let events = read_events(&conf, records);
+        assert_eq!(get_messages(&events), ["web: started"]);
+        assert_eq!(events[0].cursor.as_deref(), Some("cursor-0"));
+    }
+
+    #[test]
+    fn test_reader_of_journal_source_saves_no_cursor() {
+        let mut conf = Configuration::for_tests();
+        conf.journal_sources = vec![JournalSource::System, JournalSource::User];
+        let records =
+            vec![create_record(&[("MESSAGE", "started"), ("_COMM", "web")])];
+        let events = read_events(&conf, records);
+        assert_eq!(get_messages(&events), ["web: started"]);
+        assert_eq!(events[0].cursor, None);
+    }
+
+    #[test]
+    fn test_reader_rolls_over_on_reboot() {
+        let mut conf = Configuration::for_tests();
+        conf.stream_per_boot = true;
+        conf.message_encoding = MessageEncoding::Json;
+        conf.json_format = format::JsonFormat::new()
+            .with_fields(&["MESSAGE".to_string()])
+            .with_boot_id();
+        let first_boot = "1b6e2f7a0c3d4e5f8a9b0c1d2e3f4a5b";
+        let second_boot = "c0ffee00aa11bb22cc33dd44ee55ff66";
+        let records = vec![
+            create_record(&[
+                ("MESSAGE", "shutting down"),
+                ("_BOOT_ID", first_boot),
+            ]),
+            create_record(&[("MESSAGE", "booted"), ("_BOOT_ID", second_boot)]),
+        ];
+        let events = read_events(&conf, records);
+        let streams: Vec<_> =
+            events.iter().map(|event| event.stream.as_deref()).collect();
+        assert_eq!(
+            streams,
+            [Some("myStream/1b6e2f7a"), Some("myStream/c0ffee00")]
+        );
+        let message = events[1].event.message.as_deref().unwrap_or_default();
+        assert!(message.contains(&format!(r#""boot_id":"{}""#, second_boot)));
+    }
+
+    #[test]
+    fn test_reader_stops_when_idle() {
+        let mut conf = Configuration::for_tests();
+        conf.idle_timeout = Some(Duration::from_millis(100));
+        let (tx, mut rx) = mpsc::channel(16);
+        let sender = EventSender::new(tx, 16, Arc::default());
+        let records = vec![
+            create_record(&[("MESSAGE", "ev1")]),
+            create_record(&[("MESSAGE", "ev2")]),
+        ];
+        let started = Instant::now();
+        // Follows the journal like a normal run, so only the idle timeout
+        // can stop it
+        handle_journal_entry_loop(
+            &conf,
+            &RecordFilter::new(&conf),
+            ScriptedSource::new(records),
+            |_| None,
+            sender,
+            &AtomicBool::new(false),
+        );
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert!(started.elapsed() < JOURNAL_WAIT_TIMEOUT);
+        let mut events = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            events.push(event);
+        }
+        assert_eq!(get_messages(&events), ["unknown: ev1", "unknown: ev2"]);
+    }
+
+    #[test]
+    fn test_reader_formats_records() {
+        let mut conf = Configuration::for_tests();
+        conf.message_format =
+            format::MessageFormat::parse("[{level}] {message}");
+        let records = vec![
+            create_record(&[
+                ("MESSAGE", "disk failed"),
+                ("PRIORITY", "3"),
+                ("_SOURCE_REALTIME_TIMESTAMP", "1500000000000000"),
+            ]),
+            create_record(&[("MESSAGE", "hello")]),
+        ];
+        let events = read_events(&conf, records.clone());
+        assert_eq!(
+            get_messages(&events),
+            ["[ERROR] disk failed", "[INFO] hello"]
+        );
+        assert_eq!(events[0].event.timestamp, Some(1500000000000));
+        assert_eq!(events[1].cursor.as_deref(), Some("cursor-1"));
+
+        conf.message_encoding = MessageEncoding::Json;
+        let events = read_events(&conf, records);
+        let message: serde_json::Value =
+            serde_json::from_str(get_messages(&events)[0]).unwrap();
+        assert_eq!(message["level"], "ERROR");
+        assert_eq!(message["message"], "disk failed");
+    }
+
+    #[test]
+    fn test_reader_prefixes_timestamps() {
+        let mut conf = Configuration::for_tests();
+        conf.timestamp_prefix = true;
+        let records = vec![create_record(&[
+            ("MESSAGE", "disk failed"),
+            ("_COMM", "smartd"),
+            ("_SOURCE_REALTIME_TIMESTAMP", "1500000000123456"),
+        ])];
+        let events = read_events(&conf, records);
+        let message = "2017-07-14T02:40:00.123Z smartd: disk failed";
+        assert_eq!(get_messages(&events), [message]);
+        assert_eq!(events[0].event.timestamp, Some(1500000000123));
+        assert_eq!(
+            cloudwatch::get_event_num_bytes(&events[0].event),
+            message.len() + cloudwatch::EVENT_OVERHEAD_BYTES
+        );
+    }
+
+    #[test]
+    fn test_record_without_message() {
+        const TIMESTAMP: i64 = 1500000001000000;
+        let mut conf = Configuration::for_tests();
+        let record = create_record(&[
+            ("MESSAGE_ID", "fc2e22bc6ee647b6b90729ab34a250b1"),
+            ("_COMM", "systemd-coredump"),
+            (REALTIME_TIMESTAMP_FIELD, "1500000001000000"),
+        ]);
+        assert!(parse_record(&conf, record.clone(), TIMESTAMP).is_none());
+
+        conf.missing_message = MissingMessage::Field("MESSAGE_ID".to_string());
+        let event = parse_record(&conf, record.clone(), TIMESTAMP).unwrap();
+        assert_eq!(
+            event.message.as_deref(),
+            Some("systemd-coredump: fc2e22bc6ee647b6b90729ab34a250b1")
+        );
+        conf.missing_message = MissingMessage::Field("CODE_FILE".to_string());
+        assert!(parse_record(&conf, record.clone(), TIMESTAMP).is_none());
+
+        conf.missing_message = MissingMessage::Record;
+        conf.message_format = format::MessageFormat::parse("{message}");
+        let event = parse_record(&conf, record, TIMESTAMP).unwrap();
+        assert_eq!(
+            event.message.as_deref(),
+            Some(
+                "{\"MESSAGE_ID\":\"fc2e22bc6ee647b6b90729ab34a250b1\",\
+                 \"_COMM\":\"systemd-coredump\"}"
+            )
+        );
+    }
+
+    #[test]
+    fn test_seek_to_tail() {
+        let mut source =
+            ScriptedSource::new(vec![create_record(&[("MESSAGE", "old")])]);
+        seek_to_tail(&mut source);
+        assert!(source.await_next_entry(Duration::ZERO).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_source_timestamp_preferred() {
+        let record = create_record(&[
+            ("_SOURCE_REALTIME_TIMESTAMP", "1500000000000000"),
+            (REALTIME_TIMESTAMP_FIELD, "1500000001000000"),
+        ]);
+        assert_eq!(get_record_timestamp_micros(&record), 1500000000000000);
+    }
+
+    #[test]
+    fn test_realtime_timestamp_fallback() {
+        let record =
+            create_record(&[(REALTIME_TIMESTAMP_FIELD, "1500000001000000")]);
+        assert_eq!(get_record_timestamp_micros(&record), 1500000001000000);
+    }
+
+    #[test]
+    fn test_current_time_fallback() {
+        let before = Utc::now().timestamp_nanos() / 1000;
+        let timestamp = get_record_timestamp_micros(&create_record(&[]));
+        assert!(timestamp >= before);
+        assert!(timestamp <= Utc::now().timestamp_nanos() / 1000);
     }
 }
@@ -0,0 +1,458 @@
+use crate::cloudwatch::UploadError;
+use std::fmt::{self, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{info, warn};
+
+/// Why an event was discarded instead of uploaded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropReason {
+    /// Skipped by the unit, source or pattern filters, or the service's
+    /// own records.
+    Filtered,
+    /// Records that no event could be made of, such as those without a
+    /// message.
+    ParseFailed,
+    /// Older than CloudWatch Logs accepts.
+    TooOld,
+    /// Dropped because `MAX_BUFFERED_BYTES` were already buffered.
+    BufferFull,
+    /// Not delivered, and there was no spool to keep them in.
+    Undelivered,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 5] = [
+        DropReason::Filtered,
+        DropReason::ParseFailed,
+        DropReason::TooOld,
+        DropReason::BufferFull,
+        DropReason::Undelivered,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DropReason::Filtered => "filtered",
+            DropReason::ParseFailed => "parse_failed",
+            DropReason::TooOld => "too_old",
+            DropReason::BufferFull => "buffer_full",
+            DropReason::Undelivered => "undelivered",
+        }
+    }
+}
+
+/// Counters shared by the journal reader and the uploader. Updating them
+/// is a single atomic operation, so they are cheap enough to keep even
+/// when nothing serves them.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub events_read: AtomicU64,
+    pub events_forwarded: AtomicU64,
+    /// Events dropped for any reason but filtering, which is what the
+    /// configuration asks for.
+    pub events_dropped: AtomicU64,
+    /// Discarded events by reason, in the order of `DropReason::ALL`.
+    events_discarded: [AtomicU64; DropReason::ALL.len()],
+    pub batches_uploaded: AtomicU64,
+    pub upload_failures: AtomicU64,
+    /// Failed batches by what they failed with, in the order of
+    /// `UploadError::ALL`.
+    upload_errors: [AtomicU64; UploadError::ALL.len()],
+    pub channel_depth: AtomicU64,
+    pub queued_bytes: AtomicU64,
+    /// Unix time in seconds, or 0 before the first successful upload.
+    pub last_upload_timestamp: AtomicI64,
+    /// Unix time in seconds, or 0 before the first failed upload.
+    pub last_upload_failure_timestamp: AtomicI64,
+    pub journal_open: AtomicBool,
+    pub journal_reopens: AtomicU64,
+    /// How far the newest uploaded event was behind the clock when it was
+    /// accepted, in milliseconds.
+    pub upload_lag_millis: AtomicI64,
+    /// Total time spent in PutLogEvents requests and how many there were,
+    /// which together make up a summary.
+    pub put_latency_micros: AtomicU64,
+    pub put_requests: AtomicU64,
+    /// The most recent upload error and its Unix time in seconds, shown
+    /// by the health check.
+    last_upload_error: Mutex<Option<(i64, String)>>,
+}
+
+/// The most characters of an upload error that are kept, since an error
+/// can include a whole response body.
+pub const MAX_ERROR_CHARS: usize = 512;
+
+impl Metrics {
+    /// Record the round trip time of one PutLogEvents request.
+    pub fn record_put_latency(&self, latency: Duration) {
+        self.put_latency_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        self.put_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count events discarded for a reason.
+    pub fn record_drop(&self, reason: DropReason, num_events: u64) {
+        self.events_discarded[reason as usize]
+            .fetch_add(num_events, Ordering::Relaxed);
+        if reason != DropReason::Filtered {
+            self.events_dropped.fetch_add(num_events, Ordering::Relaxed);
+        }
+    }
+
+    pub fn events_discarded(&self, reason: DropReason) -> u64 {
+        self.events_discarded[reason as usize].load(Ordering::Relaxed)
+    }
+
+    /// The discarded event counts as `reason=count` pairs, for the logs.
+    pub fn drop_summary(&self) -> String {
+        DropReason::ALL
+            .iter()
+            .map(|reason| {
+                format!("{}={}", reason.name(), self.events_discarded(*reason))
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Count a batch that could not be uploaded.
+    pub fn record_upload_failure(&self, error: UploadError, now: i64) {
+        self.upload_failures.fetch_add(1, Ordering::Relaxed);
+        self.upload_errors[error as usize].fetch_add(1, Ordering::Relaxed);
+        self.last_upload_failure_timestamp
+            .store(now, Ordering::Relaxed);
+    }
+
+    pub fn upload_errors(&self, error: UploadError) -> u64 {
+        self.upload_errors[error as usize].load(Ordering::Relaxed)
+    }
+
+    /// Record why an upload failed, replacing the previous error.
+    pub fn record_upload_error(&self, err: &dyn fmt::Display, now: i64) {
+        let message = err.to_string().chars().take(MAX_ERROR_CHARS).collect();
+        if let Ok(mut last_error) = self.last_upload_error.lock() {
+            *last_error = Some((now, message));
+        }
+    }
+
+    /// The most recent upload error and when it happened, if there was one.
+    pub fn last_upload_error(&self) -> Option<(i64, String)> {
+        self.last_upload_error.lock().ok()?.clone()
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    ///
+    /// Reference:
+    /// prometheus.io/docs/instrumenting/exposition_formats/
+    pub fn render(&self) -> String {
+        let metrics: &[(&str, &str, &str, i64)] = &[
+            (
+                "events_read_total",
+                "counter",
+                "Journal records read.",
+                self.events_read.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "events_forwarded_total",
+                "counter",
+                "Events accepted by CloudWatch Logs.",
+                self.events_forwarded.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "events_dropped_total",
+                "counter",
+                "Events dropped without being uploaded.",
+                self.events_dropped.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "batches_uploaded_total",
+                "counter",
+                "Batches of events uploaded.",
+                self.batches_uploaded.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "upload_failures_total",
+                "counter",
+                "Batches that could not be uploaded.",
+                self.upload_failures.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "journal_reopens_total",
+                "counter",
+                "Times the journal was reopened after repeated errors.",
+                self.journal_reopens.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "channel_depth",
+                "gauge",
+                "Events waiting in the queue to the uploader.",
+                self.channel_depth.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "queued_bytes",
+                "gauge",
+                "Bytes of events waiting in the queue to the uploader.",
+                self.queued_bytes.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "last_upload_timestamp_seconds",
+                "gauge",
+                "Unix time of the last successful upload.",
+                self.last_upload_timestamp.load(Ordering::Relaxed),
+            ),
+            (
+                "upload_lag_milliseconds",
+                "gauge",
+                "How far behind the clock the newest uploaded event was.",
+                self.upload_lag_millis.load(Ordering::Relaxed),
+            ),
+        ];
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics.iter() {
+            let name = format!("journald_to_cloudwatch_{}", name);
+            let _ = writeln!(text, "# HELP {} {}", name, help);
+            let _ = writeln!(text, "# TYPE {} {}", name, kind);
+            let _ = writeln!(text, "{} {}", name, value);
+        }
+
+        let name = "journald_to_cloudwatch_events_discarded_total";
+        let _ = writeln!(
+            text,
+            "# HELP {} Events discarded instead of uploaded, by reason.",
+            name
+        );
+        let _ = writeln!(text, "# TYPE {} counter", name);
+        for reason in DropReason::ALL.iter() {
+            let _ = writeln!(
+                text,
+                "{}{{reason=\"{}\"}} {}",
+                name,
+                reason.name(),
+                self.events_discarded(*reason)
+            );
+        }
+
+        let name = "journald_to_cloudwatch_upload_errors_total";
+        let _ = writeln!(
+            text,
+            "# HELP {} Batches that could not be uploaded, by error.",
+            name
+        );
+        let _ = writeln!(text, "# TYPE {} counter", name);
+        for error in UploadError::ALL.iter() {
+            let _ = writeln!(
+                text,
+                "{}{{error=\"{}\"}} {}",
+                name,
+                error.name(),
+                self.upload_errors(*error)
+            );
+        }
+
+        let name = "journald_to_cloudwatch_put_log_events_seconds";
+        let latency =
+            self.put_latency_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(
+            text,
+            "# HELP {} Round trip time of PutLogEvents requests.",
+            name
+        );
+        let _ = writeln!(text, "# TYPE {} summary", name);
+        let _ = writeln!(text, "{}_sum {}", name, latency);
+        let _ = writeln!(
+            text,
+            "{}_count {}",
+            name,
+            self.put_requests.load(Ordering::Relaxed)
+        );
+        text
+    }
+}
+
+/// Write a minimal HTTP/1.1 response and close the connection.
+pub async fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> std::io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Read the request line of an HTTP request and return its path. Only
+/// the start of the request is read, since nothing else is needed.
+pub async fn read_request_path(stream: &mut TcpStream) -> Option<String> {
+    let mut buffer = [0; 1024];
+    let len = stream.read(&mut buffer).await.ok()?;
+    let request = String::from_utf8_lossy(&buffer[..len]);
+    parse_request_path(&request)
+}
+
+fn parse_request_path(request: &str) -> Option<String> {
+    let mut parts = request.lines().next()?.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => Some(path.to_string()),
+        _ => None,
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    metrics: &Metrics,
+) -> std::io::Result<()> {
+    match read_request_path(&mut stream).await.as_deref() {
+        Some("/metrics") => {
+            write_response(
+                &mut stream,
+                "200 OK",
+                "text/plain; version=0.0.4",
+                &metrics.render(),
+            )
+            .await
+        }
+        _ => {
+            write_response(&mut stream, "404 Not Found", "text/plain", "").await
+        }
+    }
+}
+
+/// Serve the metrics at `/metrics` until the process exits.
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            warn!("failed to listen for metrics on {}: {}", addr, err);
+            return;
+        }
+    };
+    info!("serving metrics on http://{}/metrics", addr);
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                warn!("failed to accept a metrics connection: {}", err);
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_connection(stream, &metrics).await {
+                warn!("failed to serve metrics: {}", err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.events_read.fetch_add(3, Ordering::Relaxed);
+        metrics
+            .last_upload_timestamp
+            .store(1650000000, Ordering::Relaxed);
+        let text = metrics.render();
+        assert!(text.contains(
+            "# TYPE journald_to_cloudwatch_events_read_total counter\n\
+             journald_to_cloudwatch_events_read_total 3\n"
+        ));
+        assert!(text.contains(
+            "journald_to_cloudwatch_last_upload_timestamp_seconds 1650000000\n"
+        ));
+    }
+
+    #[test]
+    fn test_render_put_latency() {
+        let metrics = Metrics::default();
+        metrics.record_put_latency(Duration::from_millis(250));
+        metrics.record_put_latency(Duration::from_millis(500));
+        let text = metrics.render();
+        assert!(text.contains(
+            "# TYPE journald_to_cloudwatch_put_log_events_seconds summary\n\
+             journald_to_cloudwatch_put_log_events_seconds_sum 0.75\n\
+             journald_to_cloudwatch_put_log_events_seconds_count 2\n"
+        ));
+    }
+
+    #[test]
+    fn test_render_drops_by_reason() {
+        let metrics = Metrics::default();
+        metrics.record_drop(DropReason::Filtered, 3);
+        metrics.record_drop(DropReason::TooOld, 1);
+        let text = metrics.render();
+        assert!(text.contains(
+            "journald_to_cloudwatch_events_discarded_total\
+             {reason=\"filtered\"} 3\n"
+        ));
+        assert!(text.contains(
+            "journald_to_cloudwatch_events_discarded_total\
+             {reason=\"buffer_full\"} 0\n"
+        ));
+        // Filtered events were meant to be left out, so they aren't drops
+        assert_eq!(metrics.events_dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            metrics.drop_summary(),
+            "filtered=3 parse_failed=0 too_old=1 buffer_full=0 undelivered=0"
+        );
+    }
+
+    #[test]
+    fn test_render_upload_errors() {
+        let metrics = Metrics::default();
+        metrics.record_upload_failure(UploadError::Throttled, 1000);
+        metrics.record_upload_failure(UploadError::Throttled, 1001);
+        metrics.record_upload_failure(UploadError::Other, 1002);
+        let text = metrics.render();
+        assert!(text.contains(
+            "journald_to_cloudwatch_upload_errors_total\
+             {error=\"throttled\"} 2\n"
+        ));
+        assert!(text.contains(
+            "journald_to_cloudwatch_upload_errors_total\
+             {error=\"network\"} 0\n"
+        ));
+        assert_eq!(metrics.upload_failures.load(Ordering::Relaxed), 3);
+        assert_eq!(
+            metrics
+                .last_upload_failure_timestamp
+                .load(Ordering::Relaxed),
+            1002
+        );
+    }
+
+    #[test]
+    fn test_upload_error_is_bounded() {
+        let metrics = Metrics::default();
+        assert_eq!(metrics.last_upload_error(), None);
+        metrics.record_upload_error(&"throttled", 1000);
+        metrics.record_upload_error(&"é".repeat(MAX_ERROR_CHARS + 1), 1001);
+        let (timestamp, message) = metrics.last_upload_error().unwrap();
+        assert_eq!(timestamp, 1001);
+        assert_eq!(message, "é".repeat(MAX_ERROR_CHARS));
+    }
+
+    #[test]
+    fn test_parse_request_path() {
+        assert_eq!(
+            parse_request_path("GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n"),
+            Some("/metrics".to_string())
+        );
+        assert_eq!(parse_request_path("POST /metrics HTTP/1.1\r\n"), None);
+        assert_eq!(parse_request_path(""), None);
+    }
+}
@@ -0,0 +1,43 @@
+use std::time::Duration;
+use systemd::daemon;
+use tracing::warn;
+
+/// Send a state change to systemd. Outside of a systemd service there is
+/// no notification socket and this does nothing.
+fn notify(state: &str, value: &str) {
+    if let Err(err) = daemon::notify(false, [(state, value)].iter()) {
+        warn!("failed to notify systemd of {}={}: {}", state, value, err);
+    }
+}
+
+/// Tell systemd that startup has finished.
+pub fn notify_ready() {
+    notify(daemon::STATE_READY, "1");
+}
+
+/// Tell systemd that the service is shutting down.
+pub fn notify_stopping() {
+    notify(daemon::STATE_STOPPING, "1");
+}
+
+/// How often to ping the watchdog: half the interval systemd expects, or
+/// `None` if the service has no watchdog configured.
+pub fn watchdog_interval() -> Option<Duration> {
+    match daemon::watchdog_enabled(false) {
+        Ok(0) => None,
+        Ok(usec) => Some(Duration::from_micros(usec) / 2),
+        Err(err) => {
+            warn!("failed to read the watchdog interval: {}", err);
+            None
+        }
+    }
+}
+
+/// Ping the systemd watchdog at the given interval, forever.
+pub async fn run_watchdog(interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        notify(daemon::STATE_WATCHDOG, "1");
+    }
+}
@@ -0,0 +1,104 @@
+use crate::configuration::RepeatMode;
+use systemd::journal::JournalRecord;
+
+/// The most events a single repeat summary is expanded into, so a huge
+/// repeat count can't flood the queue.
+pub const MAX_REPEAT_EXPANSION: u64 = 100;
+
+/// The field set on annotated records, which JSON encoding includes as
+/// `repeat_count`.
+pub const REPEAT_COUNT_FIELD: &str = "REPEAT_COUNT";
+
+/// Parse a syslog style repeat summary such as
+/// `message repeated 3 times: [ disk full]` into the repeat count and the
+/// original message.
+fn parse_repeat_summary(message: &str) -> Option<(u64, String)> {
+    let rest = message.strip_prefix("message repeated ")?;
+    let (count, rest) = rest.split_once(" times: [")?;
+    let original = rest.strip_suffix(']')?;
+    let original = original.strip_prefix(' ').unwrap_or(original);
+    Some((count.parse().ok()?, original.to_string()))
+}
+
+/// Rewrite a repeat summary according to the mode, returning the record
+/// and how many events it should be sent as.
+pub fn apply(
+    mode: RepeatMode,
+    mut record: JournalRecord,
+) -> (JournalRecord, u64) {
+    if mode == RepeatMode::Keep {
+        return (record, 1);
+    }
+    let summary = record
+        .get("MESSAGE")
+        .and_then(|message| parse_repeat_summary(message));
+    let (count, original) = match summary {
+        Some(summary) => summary,
+        None => return (record, 1),
+    };
+    record.insert("MESSAGE".to_string(), original);
+    match mode {
+        RepeatMode::Keep => (record, 1),
+        RepeatMode::Expand => (record, count.min(MAX_REPEAT_EXPANSION)),
+        RepeatMode::Annotate => {
+            record.insert(REPEAT_COUNT_FIELD.to_string(), count.to_string());
+            (record, 1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_record(message: &str) -> JournalRecord {
+        let mut record = JournalRecord::new();
+        record.insert("MESSAGE".to_string(), message.to_string());
+        record
+    }
+
+    #[test]
+    fn test_parse_repeat_summary() {
+        assert_eq!(
+            parse_repeat_summary("message repeated 3 times: [ disk full]"),
+            Some((3, "disk full".to_string()))
+        );
+        assert_eq!(parse_repeat_summary("disk full"), None);
+        assert_eq!(
+            parse_repeat_summary("message repeated many times: [ x]"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_keep() {
+        let message = "message repeated 3 times: [ disk full]";
+        let (record, count) = apply(RepeatMode::Keep, create_record(message));
+        assert_eq!(record["MESSAGE"], message);
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_expand_is_bounded() {
+        let record = create_record("message repeated 3 times: [ disk full]");
+        let (record, count) = apply(RepeatMode::Expand, record);
+        assert_eq!(record["MESSAGE"], "disk full");
+        assert_eq!(count, 3);
+
+        let record = create_record("message repeated 99999 times: [ x]");
+        let (_, count) = apply(RepeatMode::Expand, record);
+        assert_eq!(count, MAX_REPEAT_EXPANSION);
+    }
+
+    #[test]
+    fn test_annotate() {
+        let record = create_record("message repeated 3 times: [ disk full]");
+        let (record, count) = apply(RepeatMode::Annotate, record);
+        assert_eq!(record["MESSAGE"], "disk full");
+        assert_eq!(record[REPEAT_COUNT_FIELD], "3");
+        assert_eq!(count, 1);
+
+        let (record, _) = apply(RepeatMode::Annotate, create_record("hi"));
+        assert!(!record.contains_key(REPEAT_COUNT_FIELD));
+    }
+}
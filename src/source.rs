@@ -0,0 +1,69 @@
+use crate::binary;
+use std::time::{Duration, UNIX_EPOCH};
+use systemd::journal::{JournalRecord, JournalRef, JournalSeek};
+use systemd::Journal;
+use tracing::debug;
+
+/// The journal's own timestamp of an entry, which isn't one of its
+/// fields and so has to be added to the record.
+pub const REALTIME_TIMESTAMP_FIELD: &str = "__REALTIME_TIMESTAMP";
+
+/// Where the reader gets its records from: the journal, or a scripted
+/// source in tests.
+pub trait RecordSource {
+    /// Read the next record. If there are no more, wait up to `timeout`
+    /// for the source to change and return `None`, so the caller can check
+    /// for shutdown before reading again. A zero timeout doesn't wait.
+    fn await_next_entry(
+        &mut self,
+        timeout: Duration,
+    ) -> systemd::Result<Option<JournalRecord>>;
+
+    fn seek(&mut self, seek: JournalSeek) -> systemd::Result<()>;
+
+    /// The cursor of the record last read.
+    fn cursor(&self) -> systemd::Result<String>;
+}
+
+impl RecordSource for Journal {
+    /// Records read from the journal have their raw message restored if
+    /// it isn't valid UTF-8, and the time the journal received them added.
+    fn await_next_entry(
+        &mut self,
+        timeout: Duration,
+    ) -> systemd::Result<Option<JournalRecord>> {
+        if let Some(mut record) = self.next_entry()? {
+            binary::restore_binary_message(self, &mut record);
+            add_realtime_timestamp(self, &mut record);
+            return Ok(Some(record));
+        }
+        if !timeout.is_zero() {
+            let result = self.wait(Some(timeout))?;
+            debug!("handle_entry: woke up: {:?}", result);
+        }
+        Ok(None)
+    }
+
+    fn seek(&mut self, seek: JournalSeek) -> systemd::Result<()> {
+        JournalRef::seek(self, seek)
+    }
+
+    fn cursor(&self) -> systemd::Result<String> {
+        JournalRef::cursor(self)
+    }
+}
+
+/// Add the time the journal received the current entry to the record, in
+/// microseconds like the source timestamp.
+fn add_realtime_timestamp(journal: &Journal, record: &mut JournalRecord) {
+    let timestamp = journal
+        .timestamp()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok());
+    if let Some(timestamp) = timestamp {
+        record.insert(
+            REALTIME_TIMESTAMP_FIELD.to_string(),
+            timestamp.as_micros().to_string(),
+        );
+    }
+}
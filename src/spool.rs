@@ -0,0 +1,365 @@
+use aws_sdk_cloudwatchlogs::model::InputLogEvent;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::warn;
+
+/// The extensions of spooled batch files. Batches are written gzip
+/// compressed; uncompressed ones are from earlier versions.
+const COMPRESSED_EXTENSION: &str = ".jsonl.gz";
+const UNCOMPRESSED_EXTENSION: &str = ".jsonl";
+
+/// Keeps batches that could not be uploaded on disk so they can be
+/// retried later. Each batch is stored as a gzip compressed JSON-lines
+/// file, one event per line, named so that the oldest batch sorts first.
+/// The size limit applies to the compressed files.
+#[derive(Debug)]
+pub struct SpoolStore {
+    dir: PathBuf,
+    max_bytes: u64,
+    sequence: AtomicU64,
+}
+
+/// A spooled batch, along with the log stream it was meant for if that
+/// isn't the default one.
+#[derive(Debug)]
+pub struct SpooledBatch {
+    pub stream: Option<String>,
+    pub events: Vec<InputLogEvent>,
+}
+
+/// Encode an event as a single line of JSON.
+pub fn encode_event(event: &InputLogEvent) -> String {
+    json!({
+        "timestamp": event.timestamp,
+        "message": event.message,
+    })
+    .to_string()
+}
+
+fn encode_spooled_event(stream: Option<&str>, event: &InputLogEvent) -> String {
+    match stream {
+        Some(stream) => json!({
+            "timestamp": event.timestamp,
+            "message": event.message,
+            "stream": stream,
+        })
+        .to_string(),
+        None => encode_event(event),
+    }
+}
+
+fn decode_event(line: &str) -> Option<(Option<String>, InputLogEvent)> {
+    let value: Value = serde_json::from_str(line).ok()?;
+    let stream = value["stream"].as_str().map(|s| s.to_string());
+    let event = InputLogEvent::builder()
+        .set_timestamp(value["timestamp"].as_i64())
+        .set_message(value["message"].as_str().map(|m| m.to_string()))
+        .build();
+    Some((stream, event))
+}
+
+impl SpoolStore {
+    pub fn new(dir: PathBuf, max_bytes: u64) -> SpoolStore {
+        SpoolStore {
+            dir,
+            max_bytes,
+            sequence: AtomicU64::new(0),
+        }
+    }
+
+    /// The spooled batch files, oldest first.
+    pub fn files(&self) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if name.ends_with(COMPRESSED_EXTENSION)
+                || name.ends_with(UNCOMPRESSED_EXTENSION)
+            {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.files().map(|files| files.is_empty()).unwrap_or(true)
+    }
+
+    /// Write a batch to the spool, then discard the oldest batches if the
+    /// spool has grown past its maximum size.
+    pub fn enqueue(
+        &self,
+        stream: Option<&str>,
+        events: Vec<InputLogEvent>,
+    ) -> io::Result<()> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.dir)?;
+        let name = format!(
+            "{:020}-{:06}{}",
+            chrono::Utc::now().timestamp_millis(),
+            self.sequence.fetch_add(1, Ordering::Relaxed) % 1_000_000,
+            COMPRESSED_EXTENSION
+        );
+        write_batch(&self.dir.join(name), stream, &events)?;
+        self.enforce_max_bytes()
+    }
+
+    fn enforce_max_bytes(&self) -> io::Result<()> {
+        let files = self.files()?;
+        let mut sizes = Vec::with_capacity(files.len());
+        for path in files.iter() {
+            sizes.push(fs::metadata(path)?.len());
+        }
+        let mut total: u64 = sizes.iter().sum();
+        for (path, size) in files.iter().zip(sizes) {
+            if total <= self.max_bytes {
+                break;
+            }
+            warn!(
+                "spool is over {} bytes, discarding {}",
+                self.max_bytes,
+                path.display()
+            );
+            fs::remove_file(path)?;
+            total -= size;
+        }
+        Ok(())
+    }
+
+    /// Read the oldest spooled batch, along with its file. The file stays
+    /// in the spool until it is passed to `remove` once the batch has been
+    /// delivered, so a crash before then can't lose it. Files that can't
+    /// be read are discarded.
+    pub fn oldest(&self) -> Option<(PathBuf, SpooledBatch)> {
+        for path in self.files().ok()? {
+            match read_batch(&path) {
+                Ok(batch) => return Some((path, batch)),
+                Err(err) => {
+                    warn!("failed to read {}: {}", path.display(), err);
+                    self.remove(&path);
+                }
+            }
+        }
+        None
+    }
+
+    /// Remove a batch file returned by `oldest`.
+    pub fn remove(&self, path: &Path) {
+        if let Err(err) = fs::remove_file(path) {
+            warn!("failed to remove {}: {}", path.display(), err);
+        }
+    }
+
+    /// Replace a batch file returned by `oldest` with the events of it
+    /// that are still to be delivered, keeping its place in the spool.
+    pub fn replace(
+        &self,
+        path: &Path,
+        stream: Option<&str>,
+        events: &[InputLogEvent],
+    ) -> io::Result<()> {
+        if events.is_empty() {
+            self.remove(path);
+            return Ok(());
+        }
+        // Older versions wrote uncompressed files, which are replaced by
+        // a compressed one under the same name
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let stem = name
+            .strip_suffix(COMPRESSED_EXTENSION)
+            .or_else(|| name.strip_suffix(UNCOMPRESSED_EXTENSION))
+            .unwrap_or(&name);
+        let new_path =
+            path.with_file_name(format!("{}{}", stem, COMPRESSED_EXTENSION));
+        write_batch(&new_path, stream, events)?;
+        if new_path != path {
+            self.remove(path);
+        }
+        Ok(())
+    }
+}
+
+/// Write a batch under a name the spool ignores, then rename it into
+/// place, so a partially written batch is never read.
+fn write_batch(
+    path: &Path,
+    stream: Option<&str>,
+    events: &[InputLogEvent],
+) -> io::Result<()> {
+    let mut contents = String::new();
+    for event in events.iter() {
+        contents.push_str(&encode_spooled_event(stream, event));
+        contents.push('\n');
+    }
+    let tmp_path = path.with_extension("tmp");
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(contents.as_bytes())?;
+    fs::write(&tmp_path, encoder.finish()?)?;
+    fs::rename(&tmp_path, path)
+}
+
+fn read_batch(path: &Path) -> io::Result<SpooledBatch> {
+    let mut contents = String::new();
+    if path.to_string_lossy().ends_with(COMPRESSED_EXTENSION) {
+        GzDecoder::new(fs::File::open(path)?).read_to_string(&mut contents)?;
+    } else {
+        contents = fs::read_to_string(path)?;
+    }
+    let mut batch = SpooledBatch {
+        stream: None,
+        events: Vec::new(),
+    };
+    // Every event of a batch is for the same stream
+    for (stream, event) in contents.lines().filter_map(decode_event) {
+        batch.stream = stream;
+        batch.events.push(event);
+    }
+    Ok(batch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "jtc-spool-{}-{}",
+            std::process::id(),
+            name
+        ))
+    }
+
+    fn create_event(message: &str, timestamp: i64) -> InputLogEvent {
+        InputLogEvent::builder()
+            .message(message)
+            .timestamp(timestamp)
+            .build()
+    }
+
+    /// Read and remove every batch, oldest first.
+    fn drain(spool: &SpoolStore) -> Vec<SpooledBatch> {
+        let mut batches = Vec::new();
+        while let Some((path, batch)) = spool.oldest() {
+            spool.remove(&path);
+            batches.push(batch);
+        }
+        batches
+    }
+
+    #[test]
+    fn test_enqueue_and_drain() {
+        let dir = temp_dir("drain");
+        let spool = SpoolStore::new(dir.clone(), 1024 * 1024);
+        assert!(spool.is_empty());
+        spool
+            .enqueue(None, vec![create_event("ev1", 1), create_event("ev2", 2)])
+            .unwrap();
+        spool
+            .enqueue(Some("host/sshd.service"), vec![create_event("ev3\n", 3)])
+            .unwrap();
+        assert!(!spool.is_empty());
+
+        let batches = drain(&spool);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].stream, None);
+        assert_eq!(batches[0].events.len(), 2);
+        assert_eq!(batches[0].events[1].message.as_deref(), Some("ev2"));
+        assert_eq!(batches[1].stream.as_deref(), Some("host/sshd.service"));
+        assert_eq!(batches[1].events[0].message.as_deref(), Some("ev3\n"));
+        assert_eq!(batches[1].events[0].timestamp, Some(3));
+        assert!(spool.is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_batches_are_compressed() {
+        let dir = temp_dir("compressed");
+        let spool = SpoolStore::new(dir.clone(), 1024 * 1024);
+        let events = (0..100)
+            .map(|i| create_event("connection reset by peer", i))
+            .collect();
+        spool.enqueue(None, events).unwrap();
+        let files = spool.files().unwrap();
+        assert_eq!(files.len(), 1);
+        assert!(files[0].to_string_lossy().ends_with(".jsonl.gz"));
+        // About 6 KiB of JSON lines
+        assert!(fs::metadata(&files[0]).unwrap().len() < 1024);
+
+        // Uncompressed batches left by earlier versions are drained too,
+        // in order
+        fs::write(
+            dir.join("99999999999999999999-000000.jsonl"),
+            "{\"timestamp\":100,\"message\":\"old\"}\n",
+        )
+        .unwrap();
+        let batches = drain(&spool);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].events.len(), 100);
+        assert_eq!(batches[1].events[0].message.as_deref(), Some("old"));
+        assert!(spool.is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_oldest_batches_discarded_over_max_size() {
+        let dir = temp_dir("max");
+        // Room for two and a half of the compressed batches
+        let probe = SpoolStore::new(dir.clone(), u64::MAX);
+        let message = format!("{}{}", 0, "x".repeat(60));
+        probe
+            .enqueue(None, vec![create_event(&message, 0)])
+            .unwrap();
+        let batch_bytes =
+            fs::metadata(&probe.files().unwrap()[0]).unwrap().len();
+        drain(&probe);
+
+        let spool = SpoolStore::new(dir.clone(), batch_bytes * 5 / 2);
+        for i in 0..5 {
+            let message = format!("{}{}", i, "x".repeat(60));
+            spool
+                .enqueue(None, vec![create_event(&message, i)])
+                .unwrap();
+        }
+        let batches = drain(&spool);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].events[0].timestamp, Some(3));
+        assert_eq!(batches[1].events[0].timestamp, Some(4));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_batches_stay_until_removed() {
+        let dir = temp_dir("oldest");
+        let spool = SpoolStore::new(dir.clone(), 1024 * 1024);
+        spool
+            .enqueue(None, vec![create_event("ev1", 1), create_event("ev2", 2)])
+            .unwrap();
+        spool.enqueue(None, vec![create_event("ev3", 3)]).unwrap();
+        let (path, batch) = spool.oldest().unwrap();
+        assert_eq!(batch.events.len(), 2);
+        // Reading a batch doesn't take it out of the spool
+        assert_eq!(spool.oldest().unwrap().0, path);
+
+        spool.replace(&path, None, &batch.events[1..]).unwrap();
+        let (replaced, batch) = spool.oldest().unwrap();
+        assert_eq!(replaced, path);
+        assert_eq!(batch.events[0].message.as_deref(), Some("ev2"));
+        assert_eq!(spool.files().unwrap().len(), 2);
+
+        spool.remove(&path);
+        let (_, batch) = spool.oldest().unwrap();
+        assert_eq!(batch.events[0].message.as_deref(), Some("ev3"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+use crate::cloudwatch::{do_group_events, Undelivered, Uploader};
+use async_trait::async_trait;
+use aws_sdk_cloudwatchlogs::model::InputLogEvent;
+use chrono::{TimeZone, Utc};
+
+/// Prints events instead of uploading them, grouped into the same batches
+/// they would be uploaded in. Used for dry runs.
+#[derive(Default)]
+pub struct StdoutUploader {
+    num_batches: usize,
+}
+
+impl StdoutUploader {
+    pub fn new() -> StdoutUploader {
+        StdoutUploader { num_batches: 0 }
+    }
+}
+
+fn format_timestamp(timestamp: Option<i64>) -> String {
+    match timestamp.and_then(|ts| Utc.timestamp_millis_opt(ts).single()) {
+        Some(time) => time.to_rfc3339(),
+        None => "-".to_string(),
+    }
+}
+
+#[async_trait]
+impl Uploader for StdoutUploader {
+    fn group_events(
+        &self,
+        events: Vec<InputLogEvent>,
+    ) -> Vec<Vec<InputLogEvent>> {
+        do_group_events(events)
+    }
+
+    async fn upload(
+        &mut self,
+        events: Vec<InputLogEvent>,
+    ) -> Result<(), Undelivered> {
+        let groups = self.group_events(events);
+        let num_groups = groups.len();
+        for (i, group) in groups.iter().enumerate() {
+            self.num_batches += 1;
+            println!(
+                "--- batch {} (group {}/{}, {} events, {} to {})",
+                self.num_batches,
+                i + 1,
+                num_groups,
+                group.len(),
+                format_timestamp(group.first().and_then(|e| e.timestamp)),
+                format_timestamp(group.last().and_then(|e| e.timestamp)),
+            );
+            for event in group.iter() {
+                println!(
+                    "{} {}",
+                    format_timestamp(event.timestamp),
+                    event.message.as_deref().unwrap_or("")
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(
+            format_timestamp(Some(1_500_000_000_123)),
+            "2017-07-14T02:40:00.123+00:00"
+        );
+        assert_eq!(format_timestamp(None), "-");
+    }
+
+    #[tokio::test]
+    async fn test_upload_counts_batches() {
+        let mut uploader = StdoutUploader::new();
+        let day = 24 * 60 * 60 * 1000;
+        let events = vec![
+            InputLogEvent::builder().message("ev1").timestamp(0).build(),
+            InputLogEvent::builder()
+                .message("ev2")
+                .timestamp(day)
+                .build(),
+        ];
+        assert!(uploader.upload(events).await.is_ok());
+        assert_eq!(uploader.num_batches, 2);
+    }
+}